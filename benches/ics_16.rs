@@ -1,24 +1,37 @@
-use std::path::PathBuf;
-
 use criterion::black_box;
 use criterion::criterion_group;
 use criterion::criterion_main;
 use criterion::Criterion;
-use voronoi_swim::command_line_args::CommandLineArgs;
-use voronoi_swim::run::run;
+use voronoi_swim::generate::generate_grid;
+use voronoi_swim::generate::GridKind;
+use voronoi_swim::generate::GridSpec;
+use voronoi_swim::param_file::ParamFile;
+use voronoi_swim::run::simulate_grids;
+
+const PARAMS: &str = "
+num_directions: 84
+send_time_offset: 1.0e-6
+send_time_per_byte: 1.0e-9
+recv_time_offset: 1.0e-6
+recv_time_per_byte: 1.0e-9
+solve_time_offset: 1.0e-7
+solve_time_per_task: 1.0e-6
+";
 
 pub fn bench_16(c: &mut Criterion) {
-    c.bench_function("ics_16_1", |b| b.iter(|| run_ics_16(black_box(1))));
-    c.bench_function("ics_16_1024", |b| b.iter(|| run_ics_16(black_box(1024))));
+    c.bench_function("cartesian_16_1", |b| b.iter(|| run_16(black_box(1))));
+    c.bench_function("cartesian_16_64", |b| b.iter(|| run_16(black_box(64))));
 }
 
-fn run_ics_16(num_cores: usize) {
-    let args = CommandLineArgs {
-        grid_files: vec![PathBuf::from("testFiles/ics_16.dat")],
-        domain_decomposition: Some(num_cores),
-        quiet: true,
-    };
-    run(&args).unwrap();
+fn run_16(num_processors: usize) {
+    let param_file = ParamFile::from_yaml(PARAMS).unwrap();
+    let grid = generate_grid(&GridSpec {
+        kind: GridKind::JitteredCartesian,
+        size: [16, 16, 16],
+        num_processors,
+        ..GridSpec::default()
+    });
+    simulate_grids(&param_file, &[grid]).unwrap();
 }
 
 criterion_group! {
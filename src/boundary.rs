@@ -0,0 +1,211 @@
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::direction::get_mirrored_directions;
+use crate::direction::Direction;
+use crate::grid::Grid;
+use crate::vector_3d::Vector3D;
+
+/// A plane of the box enclosing the domain.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum BoundaryPlane {
+    XMin,
+    XMax,
+    YMin,
+    YMax,
+    ZMin,
+    ZMax,
+}
+
+/// The box enclosing the domain, which wraps around along its periodic
+/// axes.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PeriodicBox {
+    /// Edge lengths of the box
+    pub size: [f64; 3],
+    /// Whether the box wraps around along each axis
+    #[serde(default = "default_periodic")]
+    pub periodic: [bool; 3],
+    /// Which dependencies use the values of the previous iteration to break
+    /// the cycles that the periodic boundaries create
+    #[serde(default)]
+    pub lagging: LaggingStrategy,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum LaggingStrategy {
+    /// Lag every dependency across a periodic boundary.
+    #[default]
+    Boundary,
+    /// Lag only the dependencies across a periodic boundary which point
+    /// backwards in a topological order of the other dependencies. This
+    /// never lags more dependencies than `Boundary`.
+    FeedbackEdges,
+}
+
+/// A dependency between two tasks of different directions, given as
+/// (direction, cell) of the upwind and of the downwind task.
+pub type CrossDependency = ((usize, usize), (usize, usize));
+
+impl PeriodicBox {
+    /// The shortest difference between two positions in the periodic box.
+    pub fn wrap(&self, difference: &Vector3D) -> Vector3D {
+        let mut components = [*difference.x, *difference.y, *difference.z];
+        for (axis, component) in components.iter_mut().enumerate() {
+            if self.periodic[axis] {
+                *component -= self.size[axis] * (*component / self.size[axis]).round();
+            }
+        }
+        Vector3D::new(components[0], components[1], components[2])
+    }
+
+    /// Whether the shortest connection between the two positions crosses a
+    /// periodic boundary.
+    pub fn crosses_boundary(&self, position_0: &Vector3D, position_1: &Vector3D) -> bool {
+        let difference = position_1.sub(position_0);
+        let components = [*difference.x, *difference.y, *difference.z];
+        (0..3).any(|axis| self.periodic[axis] && components[axis].abs() > 0.5 * self.size[axis])
+    }
+}
+
+fn default_periodic() -> [bool; 3] {
+    [true; 3]
+}
+
+impl BoundaryPlane {
+    pub fn axis(&self) -> usize {
+        match self {
+            BoundaryPlane::XMin | BoundaryPlane::XMax => 0,
+            BoundaryPlane::YMin | BoundaryPlane::YMax => 1,
+            BoundaryPlane::ZMin | BoundaryPlane::ZMax => 2,
+        }
+    }
+
+    pub fn get_outward_normal(&self) -> Vector3D {
+        match self {
+            BoundaryPlane::XMin => Vector3D::new(-1.0, 0.0, 0.0),
+            BoundaryPlane::XMax => Vector3D::new(1.0, 0.0, 0.0),
+            BoundaryPlane::YMin => Vector3D::new(0.0, -1.0, 0.0),
+            BoundaryPlane::YMax => Vector3D::new(0.0, 1.0, 0.0),
+            BoundaryPlane::ZMin => Vector3D::new(0.0, 0.0, -1.0),
+            BoundaryPlane::ZMax => Vector3D::new(0.0, 0.0, 1.0),
+        }
+    }
+}
+
+/// Radiation that leaves a cell through a reflective plane re-enters it in
+/// the mirrored direction. The task of every direction entering the domain
+/// through the plane therefore depends on the task of its mirrored
+/// direction in each cell at the plane.
+pub fn get_reflective_dependencies(
+    grid: &Grid,
+    directions: &[Direction],
+    planes: &[BoundaryPlane],
+) -> Vec<CrossDependency> {
+    let mut dependencies = vec![];
+    for plane in planes.iter() {
+        let normal = plane.get_outward_normal();
+        let mirrored = get_mirrored_directions(directions, &normal);
+        let cells = grid.get_boundary_cells(&normal);
+        for direction in directions.iter() {
+            let mirrored_direction = &directions[mirrored[direction.index]];
+            if *direction.vector.dot(&normal) >= 0.0
+                || *mirrored_direction.vector.dot(&normal) <= 0.0
+            {
+                continue;
+            }
+            for cell in cells.iter() {
+                dependencies.push(((mirrored_direction.index, *cell), (direction.index, *cell)));
+            }
+        }
+    }
+    dependencies
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+    use crate::direction::get_directions;
+    use crate::generate::generate_grid;
+    use crate::generate::GridKind;
+    use crate::generate::GridSpec;
+    use crate::param_file::get_test_param_file;
+    use crate::run::simulate_grid_with_trace;
+    use crate::validate::validate;
+    use crate::voronoi::VoronoiSpec;
+
+    #[test]
+    fn reflective_boundaries() {
+        let grid = generate_grid(&GridSpec {
+            size: [4, 4, 4],
+            num_processors: 4,
+            ..GridSpec::default()
+        });
+        let directions = get_directions(84);
+        let dependencies = get_reflective_dependencies(&grid, &directions, &[BoundaryPlane::XMin]);
+        // Grazing directions may lack a mirrored direction leaving the domain
+        let reflected: HashSet<usize> = dependencies
+            .iter()
+            .map(|(_, (direction, _))| *direction)
+            .collect();
+        assert_eq!(dependencies.len(), 16 * reflected.len());
+        assert!(directions
+            .iter()
+            .filter(|direction| *direction.vector.x > 0.1)
+            .all(|direction| reflected.contains(&direction.index)));
+        for ((upwind_direction, upwind_cell), (direction, cell)) in dependencies {
+            assert_eq!(upwind_cell, cell);
+            let upwind = &directions[upwind_direction].vector;
+            let vector = &directions[direction].vector;
+            assert!(*upwind.x < 0.0);
+            // The closest direction to the exact mirror image
+            let mirror_image = Vector3D::new(-*vector.x, *vector.y, *vector.z);
+            assert!(*upwind.dot(&mirror_image) > 0.95);
+        }
+        let get_param_file = |boundaries: &str| {
+            let mut param_file =
+                get_test_param_file(&format!("reflective_boundaries: [{}]", boundaries));
+            param_file.num_directions = 84;
+            param_file
+        };
+        let (vacuum, _) = simulate_grid_with_trace(&get_param_file(""), &grid).unwrap();
+        let param_file = get_param_file("x_min, y_max");
+        let (reflective, trace) = simulate_grid_with_trace(&param_file, &grid).unwrap();
+        validate(&grid, &param_file, &trace).unwrap();
+        assert!(reflective.time > vacuum.time);
+        // Opposite reflective planes couple the mirrored directions in a cycle
+        assert!(simulate_grid_with_trace(&get_param_file("x_min, x_max"), &grid).is_err());
+    }
+
+    #[test]
+    fn periodic_box() {
+        let spec = GridSpec {
+            kind: GridKind::Voronoi,
+            size: [6, 6, 6],
+            num_processors: 8,
+            voronoi: VoronoiSpec {
+                periodic: true,
+                ..Default::default()
+            },
+            ..GridSpec::default()
+        };
+        let mut num_lagged = vec![];
+        for lagging in ["boundary", "feedback_edges"] {
+            let param_file = get_test_param_file(&format!(
+                "periodic_box:\n  size: [6.0, 6.0, 6.0]\n  lagging: {}",
+                lagging
+            ));
+            let grid =
+                generate_grid(&spec).with_periodic_box(param_file.periodic_box.clone().unwrap());
+            let (run_data, trace) = simulate_grid_with_trace(&param_file, &grid).unwrap();
+            validate(&grid, &param_file, &trace).unwrap();
+            num_lagged.push(run_data.num_lagged_dependencies);
+        }
+        assert!(num_lagged[1] > 0);
+        assert!(num_lagged[1] < num_lagged[0]);
+    }
+}
@@ -0,0 +1,415 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+
+use anyhow::anyhow;
+use anyhow::bail;
+use anyhow::Context;
+use anyhow::Result;
+
+use crate::coarsen::coarsen;
+use crate::coarsen::CoarseningStrategy;
+use crate::grid::Grid;
+use crate::param_file::CommunicationMode;
+use crate::param_file::ParamFile;
+use crate::run::read_grid;
+use crate::run::simulate_repetitions;
+
+/// Coefficients that are fitted unless others are requested.
+pub const DEFAULT_PARAMETERS: &[&str] = &[
+    "send_time_offset",
+    "send_time_per_byte",
+    "recv_time_offset",
+    "recv_time_per_byte",
+    "solve_time_offset",
+    "solve_time_per_task",
+];
+
+/// Whether the parameter changes the simulated time under the settings of
+/// the param file. Expressions replace the coefficients they stand for,
+/// and the posting overhead only exists in the asynchronous mode.
+pub fn affects_model(param_file: &ParamFile, name: &str) -> bool {
+    match name {
+        "send_time_offset" | "send_time_per_byte" => param_file.send_time.is_none(),
+        "recv_time_offset" | "recv_time_per_byte" => param_file.recv_time.is_none(),
+        "solve_time_per_task" => param_file.solve_time.is_none(),
+        "post_time_offset" => param_file.communication_mode == CommunicationMode::Asynchronous,
+        _ => true,
+    }
+}
+
+/// The default parameters which affect the model of the param file.
+pub fn get_default_parameters(param_file: &ParamFile) -> Vec<String> {
+    DEFAULT_PARAMETERS
+        .iter()
+        .filter(|name| affects_model(param_file, name))
+        .map(|name| name.to_string())
+        .collect()
+}
+
+/// Starting value of parameters which are zero in the param file.
+const DEFAULT_START_VALUE: f64 = 1.0e-8;
+/// Size of the initial simplex in log space.
+const INITIAL_STEP: f64 = 0.5;
+const TOLERANCE: f64 = 1.0e-10;
+
+/// Columns of the measurement file, in order.
+const MEASUREMENT_COLUMNS: [&str; 4] = ["grid", "num_processors", "batch_size", "measured_time"];
+
+/// A measured wall time of a sweep.
+pub struct Measurement {
+    pub grid_file: PathBuf,
+    pub num_processors: usize,
+    pub batch_size: usize,
+    pub measured_time: f64,
+}
+
+/// A measurement whose grid has been read and coarsened.
+pub struct DataPoint {
+    /// Index into the list of grids
+    pub grid: usize,
+    pub batch_size: usize,
+    pub measured_time: f64,
+}
+
+pub struct CalibrationSettings {
+    pub parameters: Vec<String>,
+    pub max_iterations: usize,
+    /// Number of simulations with different noise seeds averaged for each
+    /// data point
+    pub repetitions: usize,
+}
+
+pub struct Calibration {
+    pub param_file: ParamFile,
+    pub simulated_times: Vec<f64>,
+    /// Sum of the squared relative errors
+    pub error: f64,
+    pub num_iterations: usize,
+}
+
+/// Reads a CSV file with the columns grid, num_processors, batch_size and
+/// measured_time. Grid files are relative to the CSV file.
+pub fn read_measurements(path: &Path) -> Result<Vec<Measurement>> {
+    let contents =
+        fs::read_to_string(path).context(format!("While reading measurements at {:?}", path))?;
+    parse_measurements(&contents, path)
+}
+
+/// Parses the lines of a measurement file. A header line naming the columns
+/// is skipped if it comes before the first measurement.
+fn parse_measurements(contents: &str, path: &Path) -> Result<Vec<Measurement>> {
+    let folder = path.parent().unwrap_or_else(|| Path::new(""));
+    let mut measurements = vec![];
+    for (line_num, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let entries: Vec<&str> = line.split(',').map(|entry| entry.trim()).collect();
+        if measurements.is_empty() && entries == MEASUREMENT_COLUMNS {
+            continue;
+        }
+        let context = || format!("In line {} of {:?}: {}", line_num + 1, path, line);
+        if entries.len() != 4 {
+            bail!("{}: Expected 4 columns, found {}", context(), entries.len());
+        }
+        measurements.push(Measurement {
+            grid_file: folder.join(entries[0]),
+            num_processors: entries[1].parse().with_context(context)?,
+            batch_size: entries[2].parse().with_context(context)?,
+            measured_time: entries[3].parse().with_context(context)?,
+        });
+    }
+    if measurements.is_empty() {
+        bail!("No measurements in {:?}", path);
+    }
+    Ok(measurements)
+}
+
+/// Reads the grids of the measurements, places them in the periodic box of
+/// the param file and coarsens them to the measured numbers of processors.
+/// Each grid is only read and coarsened once.
+pub fn load_data_points(
+    measurements: &[Measurement],
+    param_file: &ParamFile,
+    strategy: CoarseningStrategy,
+) -> Result<(Vec<Grid>, Vec<DataPoint>)> {
+    let mut grids = vec![];
+    let mut grid_indices: HashMap<(PathBuf, usize), usize> = HashMap::new();
+    let mut data_points = vec![];
+    for measurement in measurements.iter() {
+        let key = (measurement.grid_file.clone(), measurement.num_processors);
+        let grid = match grid_indices.get(&key) {
+            Some(index) => *index,
+            None => {
+                let grid = read_grid(&measurement.grid_file, None)?;
+                let grid = match &param_file.periodic_box {
+                    Some(periodic_box) => grid.with_periodic_box(periodic_box.clone()),
+                    None => grid,
+                };
+                let grid = match grid.num_processors() == measurement.num_processors {
+                    true => grid,
+                    false => coarsen(&grid, measurement.num_processors, strategy)
+                        .context(format!("While coarsening {:?}", measurement.grid_file))?,
+                };
+                grids.push(grid);
+                grid_indices.insert(key, grids.len() - 1);
+                grids.len() - 1
+            }
+        };
+        data_points.push(DataPoint {
+            grid,
+            batch_size: measurement.batch_size,
+            measured_time: measurement.measured_time,
+        });
+    }
+    Ok((grids, data_points))
+}
+
+/// Fits the parameters by minimizing the squared relative error between the
+/// simulated and measured times with the Nelder-Mead method. The parameters
+/// are optimized in log space, which keeps them positive.
+pub fn calibrate(
+    param_file: &ParamFile,
+    grids: &[Grid],
+    data_points: &[DataPoint],
+    settings: &CalibrationSettings,
+) -> Result<Calibration> {
+    let mut start_param_file = param_file.clone();
+    for name in settings.parameters.iter() {
+        get_parameter(&mut start_param_file, name)?;
+        if !affects_model(param_file, name) {
+            bail!(
+                "The parameter {} has no effect with the expressions and communication mode of the param file",
+                name
+            );
+        }
+    }
+    if settings.parameters.is_empty() {
+        bail!("No parameters to fit");
+    }
+    for name in settings.parameters.iter() {
+        let value = get_parameter(&mut start_param_file, name)?;
+        if *value <= 0.0 {
+            *value = DEFAULT_START_VALUE;
+        }
+    }
+    let start: Vec<f64> = settings
+        .parameters
+        .iter()
+        .map(|name| Ok(get_parameter(&mut start_param_file, name)?.ln()))
+        .collect::<Result<_>>()?;
+    let with_parameters = |point: &[f64]| -> Result<ParamFile> {
+        let mut param_file = start_param_file.clone();
+        for (name, value) in settings.parameters.iter().zip(point) {
+            *get_parameter(&mut param_file, name)? = value.exp();
+        }
+        Ok(param_file)
+    };
+    let get_error = |point: &[f64]| -> Result<f64> {
+        let times = simulate_data_points(&with_parameters(point)?, grids, data_points, settings)?;
+        Ok(get_squared_relative_error(&times, data_points))
+    };
+    let (optimum, num_iterations) = minimize(get_error, &start, settings.max_iterations)?;
+    let param_file = with_parameters(&optimum)?;
+    let simulated_times = simulate_data_points(&param_file, grids, data_points, settings)?;
+    Ok(Calibration {
+        error: get_squared_relative_error(&simulated_times, data_points),
+        param_file,
+        simulated_times,
+        num_iterations,
+    })
+}
+
+fn simulate_data_points(
+    param_file: &ParamFile,
+    grids: &[Grid],
+    data_points: &[DataPoint],
+    settings: &CalibrationSettings,
+) -> Result<Vec<f64>> {
+    data_points
+        .iter()
+        .map(|data_point| {
+            let mut param_file = param_file.clone();
+            param_file.batch_size = data_point.batch_size;
+            let grids = std::slice::from_ref(&grids[data_point.grid]);
+            let runs = simulate_repetitions(&param_file, grids, settings.repetitions)?;
+            Ok(runs[0].iter().map(|run_data| run_data.time).sum::<f64>() / runs[0].len() as f64)
+        })
+        .collect()
+}
+
+fn get_squared_relative_error(times: &[f64], data_points: &[DataPoint]) -> f64 {
+    times
+        .iter()
+        .zip(data_points)
+        .map(|(time, data_point)| (time / data_point.measured_time - 1.0).powi(2))
+        .sum()
+}
+
+fn get_parameter<'a>(param_file: &'a mut ParamFile, name: &str) -> Result<&'a mut f64> {
+    match name {
+        "send_time_offset" => Ok(&mut param_file.send_time_offset),
+        "send_time_per_byte" => Ok(&mut param_file.send_time_per_byte),
+        "recv_time_offset" => Ok(&mut param_file.recv_time_offset),
+        "recv_time_per_byte" => Ok(&mut param_file.recv_time_per_byte),
+        "solve_time_offset" => Ok(&mut param_file.solve_time_offset),
+        "solve_time_per_task" => Ok(&mut param_file.solve_time_per_task),
+        "post_time_offset" => Ok(&mut param_file.post_time_offset),
+        _ => Err(anyhow!(
+            "Unknown parameter {:?}. Available parameters: {}, post_time_offset",
+            name,
+            DEFAULT_PARAMETERS.join(", ")
+        )),
+    }
+}
+
+/// Nelder-Mead minimization starting from a simplex around `start`. Returns
+/// the best point and the number of iterations.
+fn minimize(
+    function: impl Fn(&[f64]) -> Result<f64>,
+    start: &[f64],
+    max_iterations: usize,
+) -> Result<(Vec<f64>, usize)> {
+    let dimension = start.len();
+    let mut simplex: Vec<(Vec<f64>, f64)> = vec![(start.to_vec(), function(start)?)];
+    for i in 0..dimension {
+        let mut point = start.to_vec();
+        point[i] += INITIAL_STEP;
+        let value = function(&point)?;
+        simplex.push((point, value));
+    }
+    let combine = |a: &[f64], b: &[f64], factor: f64| -> Vec<f64> {
+        a.iter().zip(b).map(|(a, b)| a + factor * (b - a)).collect()
+    };
+    for iteration in 0..max_iterations {
+        simplex.sort_by(|(_, a), (_, b)| a.total_cmp(b));
+        let (best, worst) = (simplex[0].1, simplex[dimension].1);
+        if worst - best <= TOLERANCE * (1.0 + best.abs()) {
+            return Ok((simplex.swap_remove(0).0, iteration));
+        }
+        let centroid: Vec<f64> = (0..dimension)
+            .map(|i| {
+                simplex[..dimension]
+                    .iter()
+                    .map(|(point, _)| point[i])
+                    .sum::<f64>()
+            })
+            .map(|sum| sum / dimension as f64)
+            .collect();
+        let worst_point = simplex[dimension].0.clone();
+        let reflected = combine(&centroid, &worst_point, -1.0);
+        let reflected_value = function(&reflected)?;
+        if reflected_value < best {
+            let expanded = combine(&centroid, &worst_point, -2.0);
+            let expanded_value = function(&expanded)?;
+            simplex[dimension] = match expanded_value < reflected_value {
+                true => (expanded, expanded_value),
+                false => (reflected, reflected_value),
+            };
+        } else if reflected_value < simplex[dimension - 1].1 {
+            simplex[dimension] = (reflected, reflected_value);
+        } else {
+            let contracted = match reflected_value < worst {
+                true => combine(&centroid, &reflected, 0.5),
+                false => combine(&centroid, &worst_point, 0.5),
+            };
+            let contracted_value = function(&contracted)?;
+            if contracted_value < worst.min(reflected_value) {
+                simplex[dimension] = (contracted, contracted_value);
+            } else {
+                let best_point = simplex[0].0.clone();
+                for (point, value) in simplex.iter_mut().skip(1) {
+                    *point = combine(&best_point, point, 0.5);
+                    *value = function(point)?;
+                }
+            }
+        }
+    }
+    simplex.sort_by(|(_, a), (_, b)| a.total_cmp(b));
+    Ok((simplex.swap_remove(0).0, max_iterations))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::expression::Expression;
+    use crate::generate::generate_grid;
+    use crate::generate::GridSpec;
+    use crate::run::simulate_grids;
+
+    #[test]
+    fn calibration_recovers_parameters() {
+        let grids: Vec<Grid> = [1, 4]
+            .iter()
+            .map(|num_processors| {
+                generate_grid(&GridSpec {
+                    size: [4, 4, 4],
+                    num_processors: *num_processors,
+                    ..GridSpec::default()
+                })
+            })
+            .collect();
+        let true_param_file = ParamFile::from_yaml(
+            "num_directions: 8
+send_time_offset: 2.0e-6
+send_time_per_byte: 0.0
+recv_time_offset: 0.0
+recv_time_per_byte: 0.0
+solve_time_offset: 0.0
+solve_time_per_task: 3.0e-7",
+        )
+        .unwrap();
+        let mut data_points = vec![];
+        for (grid, batch_size) in [(0, usize::MAX), (1, 1), (1, 4), (1, usize::MAX)] {
+            let mut param_file = true_param_file.clone();
+            param_file.batch_size = batch_size;
+            let run_data = simulate_grids(&param_file, std::slice::from_ref(&grids[grid]))
+                .unwrap()
+                .remove(0);
+            data_points.push(DataPoint {
+                grid,
+                batch_size,
+                measured_time: run_data.time,
+            });
+        }
+        let mut param_file = true_param_file.clone();
+        param_file.send_time_offset = 1.0e-5;
+        param_file.solve_time_per_task = 1.0e-6;
+        let settings = CalibrationSettings {
+            parameters: vec!["send_time_offset".into(), "solve_time_per_task".into()],
+            max_iterations: 80,
+            repetitions: 1,
+        };
+        let calibration = calibrate(&param_file, &grids, &data_points, &settings).unwrap();
+        assert!(calibration.error < 1.0e-3);
+        let relative_error = |a: f64, b: f64| (a / b - 1.0).abs();
+        assert!(relative_error(calibration.param_file.solve_time_per_task, 3.0e-7) < 0.01);
+        assert!(relative_error(calibration.param_file.send_time_offset, 2.0e-6) < 0.01);
+        // Expressions and the blocking mode leave these coefficients unused
+        param_file.solve_time = Some(Expression::parse("3.0e-7").unwrap());
+        assert!(!get_default_parameters(&param_file).contains(&"solve_time_per_task".into()));
+        let settings = CalibrationSettings {
+            parameters: vec!["post_time_offset".into()],
+            ..settings
+        };
+        assert!(calibrate(&param_file, &grids, &data_points, &settings).is_err());
+    }
+
+    #[test]
+    fn only_the_header_line_is_skipped() {
+        let contents = "grid, num_processors, batch_size, measured_time
+grid_1.dat, 4, 8, 1.5
+# comment
+grids/a.dat, 2, 1, 2.0
+";
+        let measurements = parse_measurements(contents, Path::new("runs/times.csv")).unwrap();
+        assert_eq!(measurements.len(), 2);
+        assert_eq!(measurements[0].grid_file, Path::new("runs/grid_1.dat"));
+        assert_eq!(measurements[0].batch_size, 8);
+        assert_eq!(measurements[1].grid_file, Path::new("runs/grids/a.dat"));
+        assert!(parse_measurements("grid_1.dat, 4, 8\n", Path::new("times.csv")).is_err());
+    }
+}
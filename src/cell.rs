@@ -1,15 +1,20 @@
+use std::collections::BTreeMap;
 use std::str::FromStr;
 
+use ordered_float::OrderedFloat;
+
 use regex::Regex;
 
 use crate::vector_3d::Vector3D;
 
-#[derive(Hash, PartialEq, Clone, Debug)]
+#[derive(Hash, PartialEq, Eq, Clone, Debug)]
 pub struct Cell {
     pub center: Vector3D,
     pub global_index: usize,
     pub local_index: usize,
     pub processor_num: usize,
+    /// Named per-cell quantities such as the optical depth
+    pub weights: BTreeMap<String, OrderedFloat<f64>>,
 }
 
 impl Cell {
@@ -21,20 +26,12 @@ impl Cell {
     }
 }
 
-impl Eq for Cell {
-    fn assert_receiver_is_total_eq(&self) {}
-}
-
-#[derive(Hash, PartialEq, Clone, Debug)]
+#[derive(Hash, PartialEq, Eq, Clone, Debug)]
 pub struct CellId {
     index: usize,
     processor_num: usize,
 }
 
-impl Eq for CellId {
-    fn assert_receiver_is_total_eq(&self) {}
-}
-
 impl FromStr for CellId {
     type Err = anyhow::Error;
 
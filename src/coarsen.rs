@@ -0,0 +1,243 @@
+use std::collections::HashMap;
+
+use anyhow::bail;
+use anyhow::Result;
+use clap::ArgEnum;
+
+use crate::grid::Grid;
+use crate::processors::Processors;
+use crate::vector_3d::Vector3D;
+
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq)]
+pub enum CoarseningStrategy {
+    /// Merge blocks of consecutive processor numbers.
+    Id,
+    /// Recursively bisect the domain centers, balancing the number of cells.
+    Centers,
+    /// Repeatedly merge pairs of processors that share the most faces.
+    Graph,
+}
+
+/// Merges the processor domains of the grid into fewer processors.
+pub fn coarsen(grid: &Grid, num_processors: usize, strategy: CoarseningStrategy) -> Result<Grid> {
+    let old_num_processors = grid.num_processors();
+    if num_processors == 0 || num_processors > old_num_processors {
+        bail!(
+            "Cannot coarsen a grid with {} processors to {} processors",
+            old_num_processors,
+            num_processors
+        );
+    }
+    let new_processors = match strategy {
+        CoarseningStrategy::Id => (0..old_num_processors)
+            .map(|processor_num| processor_num * num_processors / old_num_processors)
+            .collect(),
+        CoarseningStrategy::Centers => merge_by_centers(grid, num_processors),
+        CoarseningStrategy::Graph => merge_by_graph(grid, num_processors),
+    };
+    grid.reassign_processors(|cell| new_processors[cell.processor_num])
+        .with_num_processors(num_processors)
+}
+
+/// The processor counts of a strong scaling series: all powers of two below
+/// the given number of processors, in ascending order.
+pub fn get_strong_scaling_counts(num_processors: usize) -> Vec<usize> {
+    let mut counts = vec![];
+    let mut count = 1;
+    while count < num_processors {
+        counts.push(count);
+        count *= 2;
+    }
+    counts
+}
+
+fn merge_by_centers(grid: &Grid, num_processors: usize) -> Vec<usize> {
+    let num_cells = grid.get_num_cells_per_processor();
+    let centers = Processors::get_centers(grid.iter(), num_cells.len());
+    let domains: Vec<(usize, Vector3D, usize)> = centers
+        .into_iter()
+        .zip(num_cells)
+        .enumerate()
+        .map(|(processor_num, (center, num_cells))| (processor_num, center, num_cells))
+        .collect();
+    let mut new_processors = vec![0; domains.len()];
+    let mut next_processor = 0;
+    bisect(
+        domains,
+        num_processors,
+        &mut new_processors,
+        &mut next_processor,
+    );
+    new_processors
+}
+
+/// Splits the domains along the axis of largest extent of their centers such
+/// that the number of cells on each side is proportional to the number of
+/// processors it is split into.
+fn bisect(
+    mut domains: Vec<(usize, Vector3D, usize)>,
+    num_processors: usize,
+    new_processors: &mut [usize],
+    next_processor: &mut usize,
+) {
+    if num_processors == 1 {
+        for (processor_num, _, _) in domains.iter() {
+            new_processors[*processor_num] = *next_processor;
+        }
+        *next_processor += 1;
+        return;
+    }
+    let coordinate = |center: &Vector3D, axis: usize| match axis {
+        0 => *center.x,
+        1 => *center.y,
+        _ => *center.z,
+    };
+    let axis = (0..3)
+        .max_by(|a, b| {
+            let extent = |axis| {
+                let values = domains
+                    .iter()
+                    .map(|(_, center, _)| coordinate(center, axis));
+                values.clone().fold(f64::NEG_INFINITY, f64::max)
+                    - values.fold(f64::INFINITY, f64::min)
+            };
+            extent(*a).partial_cmp(&extent(*b)).unwrap()
+        })
+        .unwrap();
+    domains.sort_by(|(_, a, _), (_, b, _)| {
+        coordinate(a, axis)
+            .partial_cmp(&coordinate(b, axis))
+            .unwrap()
+    });
+    let num_left = num_processors / 2;
+    let total: usize = domains.iter().map(|(_, _, num_cells)| num_cells).sum();
+    let target = total as f64 * num_left as f64 / num_processors as f64;
+    let mut split = 0;
+    let mut num_cells_left = 0;
+    while split < domains.len() && (num_cells_left as f64) < target {
+        num_cells_left += domains[split].2;
+        split += 1;
+    }
+    // Both sides need at least one domain per processor
+    let split = split
+        .max(num_left)
+        .min(domains.len() - (num_processors - num_left));
+    let right = domains.split_off(split);
+    bisect(domains, num_left, new_processors, next_processor);
+    bisect(
+        right,
+        num_processors - num_left,
+        new_processors,
+        next_processor,
+    );
+}
+
+fn find_root(parents: &mut [usize], mut group: usize) -> usize {
+    while parents[group] != group {
+        parents[group] = parents[parents[group]];
+        group = parents[group];
+    }
+    group
+}
+
+/// Heavy edge matching on the processor adjacency graph: in every round, pairs
+/// of groups with the largest shared boundary relative to their combined size
+/// are merged, until the desired number of groups is reached.
+fn merge_by_graph(grid: &Grid, num_processors: usize) -> Vec<usize> {
+    let num_cells = grid.get_num_cells_per_processor();
+    let old_num_processors = num_cells.len();
+    let mut parents: Vec<usize> = (0..old_num_processors).collect();
+    let mut sizes = num_cells;
+    let mut num_groups = old_num_processors;
+    while num_groups > num_processors {
+        let mut weights: HashMap<(usize, usize), usize> = HashMap::new();
+        for (cell_0, cell_1, _) in grid.iter_neighbours() {
+            let group_0 = find_root(&mut parents, cell_0.processor_num);
+            let group_1 = find_root(&mut parents, cell_1.processor_num);
+            if group_0 < group_1 {
+                *weights.entry((group_0, group_1)).or_insert(0) += 1;
+            }
+        }
+        let mut candidates: Vec<((usize, usize), f64)> = weights
+            .into_iter()
+            .map(|((g0, g1), weight)| ((g0, g1), weight as f64 / (sizes[g0] + sizes[g1]) as f64))
+            .collect();
+        candidates.sort_by(|(pair_0, score_0), (pair_1, score_1)| {
+            score_1
+                .partial_cmp(score_0)
+                .unwrap()
+                .then(pair_0.cmp(pair_1))
+        });
+        let mut matched = vec![false; old_num_processors];
+        let mut num_merges = 0;
+        for ((g0, g1), _) in candidates {
+            if num_groups - num_merges == num_processors {
+                break;
+            }
+            if !matched[g0] && !matched[g1] {
+                matched[g0] = true;
+                matched[g1] = true;
+                parents[g1] = g0;
+                sizes[g0] += sizes[g1];
+                num_merges += 1;
+            }
+        }
+        if num_merges == 0 {
+            // Disconnected domains: merge the two smallest groups.
+            let mut roots: Vec<usize> = (0..old_num_processors)
+                .filter(|group| parents[*group] == *group)
+                .collect();
+            roots.sort_by_key(|group| (sizes[*group], *group));
+            parents[roots[1]] = roots[0];
+            sizes[roots[0]] += sizes[roots[1]];
+            num_merges = 1;
+        }
+        num_groups -= num_merges;
+    }
+    let mut new_numbers: HashMap<usize, usize> = HashMap::new();
+    (0..old_num_processors)
+        .map(|processor_num| {
+            let root = find_root(&mut parents, processor_num);
+            let next = new_numbers.len();
+            *new_numbers.entry(root).or_insert(next)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generate::generate_grid;
+    use crate::generate::GridSpec;
+
+    #[test]
+    fn coarsening_renumbers_cells() {
+        let grid = generate_grid(&GridSpec {
+            size: [8, 8, 8],
+            num_processors: 64,
+            ..GridSpec::default()
+        });
+        for strategy in [
+            CoarseningStrategy::Id,
+            CoarseningStrategy::Centers,
+            CoarseningStrategy::Graph,
+        ] {
+            let coarse = coarsen(&grid, 8, strategy).unwrap();
+            let num_cells = coarse.get_num_cells_per_processor();
+            assert_eq!(num_cells.len(), 8);
+            if strategy != CoarseningStrategy::Graph {
+                assert_eq!(num_cells, vec![64; 8]);
+            }
+            let mut next_local_index = [0; 8];
+            for (index, cell) in coarse.iter().enumerate() {
+                assert_eq!(cell.global_index, index);
+                assert_eq!(cell.local_index, next_local_index[cell.processor_num]);
+                next_local_index[cell.processor_num] += 1;
+            }
+            assert_eq!(
+                coarse.iter_neighbours().count(),
+                grid.iter_neighbours().count()
+            );
+        }
+    }
+}
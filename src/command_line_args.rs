@@ -2,10 +2,177 @@ use std::path::PathBuf;
 
 use clap::Clap;
 
+use crate::coarsen::CoarseningStrategy;
+use crate::generate::GridKind;
+use crate::voronoi::PointDistribution;
+
 #[derive(Clap)]
 #[clap(version = "0.1.0")]
 pub struct CommandLineArgs {
+    #[clap(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Clap)]
+pub enum Command {
+    /// Simulate a sweep on each of the given grids.
+    Simulate(SimulateArgs),
+    /// Generate a synthetic grid.
+    Generate(GenerateArgs),
+    /// Find a rank placement on the nodes of the topology that minimizes hop-bytes.
+    OptimizePlacement(OptimizePlacementArgs),
+    /// Fit the coefficients of the param file to measured runtimes.
+    Calibrate(CalibrateArgs),
+    /// Time an upwind transport kernel on the grid and write the solve time coefficients.
+    MeasureSolve(MeasureSolveArgs),
+    /// Simulate a sweep and write the order in which each processor solves its tasks.
+    ExportSchedule(ExportScheduleArgs),
+    /// Replay a recorded schedule through the cost model and compare it to measured times.
+    Replay(ReplayArgs),
+}
+
+#[derive(Clap)]
+pub struct SimulateArgs {
     pub param_file: PathBuf,
+    /// Write each grid as a vtu file into this folder.
+    #[clap(long)]
+    pub vtu_output: Option<PathBuf>,
+    /// Total number of processors, including ones without cells. Overrides the param file.
+    #[clap(long)]
+    pub num_processors: Option<usize>,
+    /// Additionally simulate each grid coarsened to these numbers of processors, e.g. 512,256
+    #[clap(long, use_delimiter = true)]
+    pub coarsen: Vec<usize>,
+    /// Additionally simulate each grid coarsened to all powers of two below its number of processors
+    #[clap(long)]
+    pub strong_scaling: bool,
+    #[clap(long, arg_enum, default_value = "graph")]
+    pub coarsening_strategy: CoarseningStrategy,
+    /// Repeat the simulation with different noise seeds and report the mean, standard
+    /// deviation and 95% confidence interval of the makespan and efficiency
+    #[clap(long, default_value = "1")]
+    pub repetitions: usize,
     #[clap(required = true)]
     pub grid_files: Vec<PathBuf>,
 }
+
+#[derive(Clap)]
+pub struct GenerateArgs {
+    #[clap(arg_enum)]
+    pub kind: GridKind,
+    /// Number of cells along each axis, e.g. 16,16,16. For Voronoi grids, this is
+    /// the size of the box, which contains one point per unit volume.
+    #[clap(long, use_delimiter = true, required = true)]
+    pub size: Vec<usize>,
+    #[clap(long, default_value = "1")]
+    pub num_processors: usize,
+    /// Number of processor blocks along each axis, e.g. 4,2,1. Overrides num-processors.
+    #[clap(long, use_delimiter = true)]
+    pub blocks: Option<Vec<usize>>,
+    /// Maximum displacement of jittered cell centers in units of the cell spacing
+    #[clap(long, default_value = "0.3")]
+    pub jitter: f64,
+    #[clap(long, arg_enum, default_value = "random")]
+    pub distribution: PointDistribution,
+    #[clap(long, default_value = "0")]
+    pub lloyd_iterations: usize,
+    /// Connect the cells across opposite faces of the box (Cartesian and Voronoi grids).
+    /// Simulating the grid requires a matching periodic_box in the param file.
+    #[clap(long)]
+    pub periodic: bool,
+    #[clap(long, default_value = "8")]
+    pub num_clusters: usize,
+    /// Width of the point clusters in units of the mean point spacing
+    #[clap(long, default_value = "2.0")]
+    pub cluster_width: f64,
+    #[clap(long, default_value = "0")]
+    pub seed: u64,
+    /// Output file (.dat)
+    #[clap(short, long)]
+    pub output: PathBuf,
+}
+
+#[derive(Clap)]
+pub struct OptimizePlacementArgs {
+    pub param_file: PathBuf,
+    pub grid_file: PathBuf,
+    /// Total number of processors, including ones without cells. Overrides the param file.
+    #[clap(long)]
+    pub num_processors: Option<usize>,
+    /// Output rankfile for Open MPI
+    #[clap(long)]
+    pub rankfile: PathBuf,
+    /// Host names in the rankfile are this prefix followed by the node index
+    #[clap(long, default_value = "node")]
+    pub host_prefix: String,
+    /// Also write the placement in the format of the `file` placement of the param file
+    #[clap(long)]
+    pub placement_file: Option<PathBuf>,
+}
+
+#[derive(Clap)]
+pub struct CalibrateArgs {
+    /// Param file with the starting values of the fitted parameters
+    pub param_file: PathBuf,
+    /// CSV file with the columns grid, num_processors, batch_size, measured_time.
+    /// Grids are coarsened to the measured number of processors.
+    pub measurements: PathBuf,
+    /// Parameters to fit, e.g. send_time_offset,solve_time_per_task. Defaults to all
+    /// send, receive and solve time coefficients that are not replaced by expressions.
+    #[clap(long, use_delimiter = true)]
+    pub parameters: Vec<String>,
+    #[clap(long, default_value = "200")]
+    pub max_iterations: usize,
+    /// Average each simulated time over this many noise seeds
+    #[clap(long, default_value = "1")]
+    pub repetitions: usize,
+    #[clap(long, arg_enum, default_value = "graph")]
+    pub coarsening_strategy: CoarseningStrategy,
+    /// Write the calibrated param file
+    #[clap(short, long)]
+    pub output: Option<PathBuf>,
+}
+
+#[derive(Clap)]
+pub struct MeasureSolveArgs {
+    pub grid_file: PathBuf,
+    #[clap(long, default_value = "84")]
+    pub num_directions: usize,
+    /// Faces whose area projected onto a direction is below this value are ignored
+    #[clap(long, default_value = "0")]
+    pub face_tolerance: f64,
+    /// Time each batch size this many times and keep the fastest run
+    #[clap(long, default_value = "5")]
+    pub repetitions: usize,
+    /// Write the param file fragment to this file instead of stdout
+    #[clap(short, long)]
+    pub output: Option<PathBuf>,
+}
+
+#[derive(Clap)]
+pub struct ExportScheduleArgs {
+    pub param_file: PathBuf,
+    pub grid_file: PathBuf,
+    /// Total number of processors, including ones without cells. Overrides the param file.
+    #[clap(long)]
+    pub num_processors: Option<usize>,
+    /// Also write the simulated start time of each task
+    #[clap(long)]
+    pub with_times: bool,
+    #[clap(short, long)]
+    pub output: PathBuf,
+}
+
+#[derive(Clap)]
+pub struct ReplayArgs {
+    pub param_file: PathBuf,
+    pub grid_file: PathBuf,
+    /// Schedule in the format written by export-schedule
+    pub schedule_file: PathBuf,
+    /// Total number of processors, including ones without cells. Overrides the param file.
+    #[clap(long)]
+    pub num_processors: Option<usize>,
+    /// Write the discrepancy between measured and modeled start time of every task as CSV
+    #[clap(long)]
+    pub discrepancy_output: Option<PathBuf>,
+}
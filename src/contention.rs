@@ -0,0 +1,282 @@
+use std::collections::HashMap;
+
+use generational_arena::Index;
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::topology::Link;
+use crate::topology::Topology;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ContentionParams {
+    /// Inverse bandwidth of every network link. Zero means unlimited.
+    pub link_time_per_byte: f64,
+    /// Inverse bandwidth at which each processor can inject messages into the
+    /// network. Zero means unlimited.
+    #[serde(default)]
+    pub injection_time_per_byte: f64,
+}
+
+#[derive(Hash, PartialEq, Eq, Clone, Debug)]
+enum Resource {
+    Link(Link),
+    Injection(usize),
+}
+
+struct Flow {
+    sender: usize,
+    receiver: usize,
+    tasks: Vec<Index>,
+    remaining_bytes: f64,
+    max_rate: f64,
+    resources: Vec<Resource>,
+    rate: f64,
+}
+
+pub struct Message {
+    pub sender: usize,
+    pub receiver: usize,
+    pub tasks: Vec<Index>,
+    pub arrival: f64,
+}
+
+/// Inter-node messages in flight. Their bandwidth is shared between all
+/// messages using the same link or network interface according to max-min
+/// fairness, and recomputed whenever a message starts or ends.
+pub struct FlowNetwork {
+    topology: Topology,
+    params: ContentionParams,
+    flows: Vec<Flow>,
+    /// Messages which start transmitting later than the current time
+    scheduled: Vec<(f64, Flow)>,
+    time: f64,
+}
+
+impl FlowNetwork {
+    pub fn new(topology: &Topology, params: &ContentionParams) -> Self {
+        FlowNetwork {
+            topology: topology.clone(),
+            params: params.clone(),
+            flows: vec![],
+            scheduled: vec![],
+            time: 0.0,
+        }
+    }
+
+    pub fn start_flow(
+        &mut self,
+        time: f64,
+        sender: usize,
+        receiver: usize,
+        num_bytes: f64,
+        tasks: Vec<Index>,
+    ) {
+        let mut resources: Vec<Resource> = self
+            .topology
+            .get_route(sender, receiver)
+            .into_iter()
+            .map(Resource::Link)
+            .collect();
+        resources.push(Resource::Injection(sender));
+        let flow = Flow {
+            sender,
+            receiver,
+            tasks,
+            remaining_bytes: num_bytes,
+            max_rate: get_rate(self.topology.get_time_per_byte(sender, receiver)),
+            resources,
+            rate: 0.0,
+        };
+        if time > self.time {
+            self.scheduled.push((time, flow));
+        } else {
+            self.flows.push(flow);
+            self.update_rates();
+        }
+    }
+
+    /// The time at which the next message finishes transmitting or a
+    /// scheduled message starts, assuming no other message starts before then.
+    pub fn get_next_event(&self) -> Option<f64> {
+        self.flows
+            .iter()
+            .map(|flow| self.get_completion(flow))
+            .chain(self.get_next_start().map(|(_, time)| time))
+            .min_by(|t0, t1| t0.partial_cmp(t1).unwrap())
+    }
+
+    fn get_next_start(&self) -> Option<(usize, f64)> {
+        self.scheduled
+            .iter()
+            .enumerate()
+            .map(|(index, (time, _))| (index, *time))
+            .min_by(|(_, t0), (_, t1)| t0.partial_cmp(t1).unwrap())
+    }
+
+    /// Advances to the next event and returns the messages that finish then,
+    /// with their arrival time at the receiver.
+    pub fn complete_next(&mut self) -> Vec<Message> {
+        let time = match self.get_next_event() {
+            Some(time) => time,
+            None => return vec![],
+        };
+        if let Some((index, start)) = self.get_next_start() {
+            if start <= time {
+                self.advance(start);
+                let (_, flow) = self.scheduled.remove(index);
+                self.flows.push(flow);
+                self.update_rates();
+                return vec![];
+            }
+        }
+        let is_finished: Vec<bool> = self
+            .flows
+            .iter()
+            .map(|flow| self.get_completion(flow) <= time)
+            .collect();
+        self.advance(time);
+        let mut finished = vec![];
+        let flows = std::mem::take(&mut self.flows);
+        for (flow, is_finished) in flows.into_iter().zip(is_finished) {
+            if is_finished {
+                finished.push(flow);
+            } else {
+                self.flows.push(flow);
+            }
+        }
+        self.update_rates();
+        finished
+            .into_iter()
+            .map(|flow| Message {
+                sender: flow.sender,
+                arrival: time + self.topology.get_latency(flow.sender, flow.receiver),
+                receiver: flow.receiver,
+                tasks: flow.tasks,
+            })
+            .collect()
+    }
+
+    fn get_completion(&self, flow: &Flow) -> f64 {
+        if flow.remaining_bytes <= 0.0 {
+            self.time
+        } else {
+            self.time + flow.remaining_bytes / flow.rate
+        }
+    }
+
+    fn advance(&mut self, time: f64) {
+        if time > self.time {
+            for flow in self.flows.iter_mut() {
+                flow.remaining_bytes =
+                    (flow.remaining_bytes - flow.rate * (time - self.time)).max(0.0);
+            }
+            self.time = time;
+        }
+    }
+
+    fn get_capacity(&self, resource: &Resource) -> f64 {
+        match resource {
+            Resource::Link(_) => get_rate(self.params.link_time_per_byte),
+            Resource::Injection(_) => get_rate(self.params.injection_time_per_byte),
+        }
+    }
+
+    /// Progressive filling: raise the rates of all unsaturated flows equally
+    /// until a resource or the flow itself is saturated.
+    fn update_rates(&mut self) {
+        let mut capacities: HashMap<Resource, f64> = HashMap::new();
+        for flow in self.flows.iter() {
+            for resource in flow.resources.iter() {
+                let capacity = self.get_capacity(resource);
+                capacities.entry(resource.clone()).or_insert(capacity);
+            }
+        }
+        let mut active: Vec<usize> = (0..self.flows.len()).collect();
+        for flow in self.flows.iter_mut() {
+            flow.rate = 0.0;
+        }
+        while !active.is_empty() {
+            let mut num_users: HashMap<&Resource, usize> = HashMap::new();
+            for index in active.iter() {
+                for resource in self.flows[*index].resources.iter() {
+                    *num_users.entry(resource).or_insert(0) += 1;
+                }
+            }
+            let resource_increment = num_users
+                .iter()
+                .map(|(resource, num)| capacities[*resource] / *num as f64)
+                .fold(f64::INFINITY, f64::min);
+            let flow_increment = active
+                .iter()
+                .map(|index| self.flows[*index].max_rate - self.flows[*index].rate)
+                .fold(f64::INFINITY, f64::min);
+            let increment = resource_increment.min(flow_increment);
+            let num_users: HashMap<Resource, usize> = num_users
+                .into_iter()
+                .map(|(resource, num)| (resource.clone(), num))
+                .collect();
+            for (resource, num) in num_users.iter() {
+                *capacities.get_mut(resource).unwrap() -= increment * *num as f64;
+            }
+            for index in active.iter() {
+                self.flows[*index].rate += increment;
+            }
+            active.retain(|index| {
+                let flow = &self.flows[*index];
+                let saturated = flow.rate >= flow.max_rate * (1.0 - 1e-12)
+                    || flow.resources.iter().any(|resource| {
+                        capacities[resource] <= self.get_capacity(resource) * 1e-12
+                    });
+                !saturated
+            });
+        }
+    }
+}
+
+fn get_rate(time_per_byte: f64) -> f64 {
+    if time_per_byte > 0.0 {
+        1.0 / time_per_byte
+    } else {
+        f64::INFINITY
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::topology::Network;
+    use crate::topology::Placement;
+    use crate::topology::TopologyParams;
+
+    #[test]
+    fn shared_link_halves_bandwidth() {
+        let contention = ContentionParams {
+            link_time_per_byte: 1.0,
+            injection_time_per_byte: 0.0,
+        };
+        let params = TopologyParams {
+            ranks_per_node: 1,
+            network: Network::Torus {
+                dimensions: [4, 1, 1],
+            },
+            placement: Placement::Block,
+            intra_node_latency: 0.0,
+            intra_node_time_per_byte: 0.0,
+            inter_node_latency: 1.0,
+            latency_per_hop: 0.0,
+            inter_node_time_per_byte: 0.0,
+            contention: Some(contention.clone()),
+        };
+        let topology = Topology::new(&params, 4).unwrap();
+        let mut network = FlowNetwork::new(&topology, &contention);
+        // Both messages use the link from node 0 to node 1.
+        network.start_flow(0.0, 0, 1, 10.0, vec![]);
+        network.start_flow(0.0, 0, 2, 20.0, vec![]);
+        let first = network.complete_next();
+        assert_eq!(first.len(), 1);
+        assert!((first[0].arrival - 21.0).abs() < 1e-9);
+        let second = network.complete_next();
+        assert!((second[0].arrival - 31.0).abs() < 1e-9);
+        assert!(network.get_next_event().is_none());
+    }
+}
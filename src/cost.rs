@@ -0,0 +1,248 @@
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::rc::Rc;
+
+use anyhow::bail;
+use anyhow::Result;
+use ordered_float::OrderedFloat;
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::expression::Expression;
+use crate::grid::Grid;
+use crate::task::Task;
+
+/// The attributes of a task which its solve time can depend on.
+pub struct TaskAttributes<'a> {
+    pub global_index: usize,
+    pub processor_num: usize,
+    /// Number of faces of the cell
+    pub num_neighbours: usize,
+    /// Number of faces through which radiation enters the cell in this direction
+    pub num_upwind_faces: usize,
+    pub direction_index: usize,
+    pub direction: [f64; 3],
+    /// Per-cell weight columns of the grid file
+    pub weights: &'a BTreeMap<String, OrderedFloat<f64>>,
+}
+
+impl<'a> TaskAttributes<'a> {
+    pub(crate) fn new(task: &'a Task) -> Self {
+        let vector = &task.direction.vector;
+        TaskAttributes {
+            global_index: task.cell.global_index,
+            processor_num: task.processor_num,
+            num_neighbours: task.num_neighbours,
+            num_upwind_faces: task.num_upwind_faces,
+            direction_index: task.direction.index,
+            direction: [*vector.x, *vector.y, *vector.z],
+            weights: &task.cell.weights,
+        }
+    }
+
+    pub fn get_weight(&self, column: &str) -> Option<f64> {
+        self.weights.get(column).map(|weight| **weight)
+    }
+
+    /// The value of a variable of a `solve_time` expression.
+    fn get_variable(&self, name: &str) -> Option<f64> {
+        match name {
+            "num_faces" => Some(self.num_neighbours as f64),
+            "num_upwind_faces" => Some(self.num_upwind_faces as f64),
+            "direction" => Some(self.direction_index as f64),
+            "processor" => Some(self.processor_num as f64),
+            "cell" => Some(self.global_index as f64),
+            _ => self.get_weight(name.strip_prefix("weight_")?),
+        }
+    }
+}
+
+/// Variables which the `solve_time` expression can use, besides
+/// `weight_<column>` for the weight columns of the grid file.
+pub(crate) const SOLVE_TIME_VARIABLES: &[&str] = &[
+    "num_faces",
+    "num_upwind_faces",
+    "direction",
+    "processor",
+    "cell",
+];
+
+/// Computes the time it takes to solve a task, excluding the
+/// `solve_time_offset` of a batch.
+pub trait CostModel: Debug {
+    fn get_solve_time(&self, task: &TaskAttributes) -> Result<f64>;
+
+    /// Checks that the model can be evaluated for every cell of the grid.
+    fn validate(&self, _grid: &Grid) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// The built-in cost models. All of them start from `solve_time_per_task`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum CostModelParams {
+    /// Every task takes the same time.
+    #[default]
+    Constant,
+    /// Additional time for every face of the cell.
+    Faces { time_per_face: f64 },
+    /// Additional time for every upwind face of the cell in the direction.
+    UpwindFaces { time_per_upwind_face: f64 },
+    /// Additional time proportional to a weight column of the grid file.
+    Weighted {
+        column: String,
+        time_per_weight: f64,
+    },
+    /// The time is scaled by a factor for each direction. The factors repeat
+    /// if there are more directions than factors.
+    DirectionFactors { factors: Vec<f64> },
+}
+
+#[derive(Debug)]
+struct BuiltinCostModel {
+    params: CostModelParams,
+    solve_time_per_task: f64,
+}
+
+impl CostModelParams {
+    pub fn build(&self, solve_time_per_task: f64) -> Rc<dyn CostModel> {
+        Rc::new(BuiltinCostModel {
+            params: self.clone(),
+            solve_time_per_task,
+        })
+    }
+}
+
+impl CostModel for BuiltinCostModel {
+    fn get_solve_time(&self, task: &TaskAttributes) -> Result<f64> {
+        let base = self.solve_time_per_task;
+        Ok(match &self.params {
+            CostModelParams::Constant => base,
+            CostModelParams::Faces { time_per_face } => {
+                base + time_per_face * task.num_neighbours as f64
+            }
+            CostModelParams::UpwindFaces {
+                time_per_upwind_face,
+            } => base + time_per_upwind_face * task.num_upwind_faces as f64,
+            CostModelParams::Weighted {
+                column,
+                time_per_weight,
+            } => base + time_per_weight * task.get_weight(column).unwrap_or(0.0),
+            CostModelParams::DirectionFactors { factors } => {
+                base * factors[task.direction_index % factors.len()]
+            }
+        })
+    }
+
+    fn validate(&self, grid: &Grid) -> Result<()> {
+        match &self.params {
+            CostModelParams::Weighted { column, .. } => {
+                if let Some(cell) = grid.iter().find(|cell| !cell.weights.contains_key(column)) {
+                    bail!(
+                        "Cell {} of processor {} has no weight column {:?}",
+                        cell.local_index,
+                        cell.processor_num,
+                        column
+                    );
+                }
+            }
+            CostModelParams::DirectionFactors { factors } if factors.is_empty() => {
+                bail!("The direction cost model needs at least one factor")
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+/// The solve time given by the `solve_time` expression of the param file.
+#[derive(Debug)]
+pub(crate) struct ExpressionCostModel {
+    expression: Expression,
+}
+
+impl ExpressionCostModel {
+    pub(crate) fn new(expression: Expression) -> Self {
+        ExpressionCostModel { expression }
+    }
+}
+
+impl CostModel for ExpressionCostModel {
+    fn get_solve_time(&self, task: &TaskAttributes) -> Result<f64> {
+        self.expression
+            .evaluate_time(|name| task.get_variable(name))
+    }
+
+    fn validate(&self, grid: &Grid) -> Result<()> {
+        for column in self
+            .expression
+            .variables()
+            .into_iter()
+            .filter_map(|name| name.strip_prefix("weight_"))
+        {
+            if let Some(cell) = grid.iter().find(|cell| !cell.weights.contains_key(column)) {
+                bail!(
+                    "Cell {} of processor {} has no weight column {:?} used in solve_time",
+                    cell.local_index,
+                    cell.processor_num,
+                    column
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generate::generate_grid;
+    use crate::generate::GridSpec;
+    use crate::param_file::ParamFile;
+    use crate::run::simulate_grids;
+    use crate::run::simulate_grids_with_cost_model;
+
+    #[derive(Debug)]
+    struct UpwindCost;
+
+    impl CostModel for UpwindCost {
+        fn get_solve_time(&self, task: &TaskAttributes) -> Result<f64> {
+            Ok(1.0e-6 + 1.0e-6 * task.num_upwind_faces as f64)
+        }
+    }
+
+    #[test]
+    fn custom_cost_model_matches_builtin() {
+        let grid = generate_grid(&GridSpec {
+            size: [4, 4, 4],
+            num_processors: 4,
+            ..GridSpec::default()
+        });
+        let param_file = ParamFile::from_yaml(
+            "num_directions: 8
+send_time_offset: 1.0e-6
+send_time_per_byte: 0.0
+recv_time_offset: 1.0e-6
+recv_time_per_byte: 0.0
+solve_time_offset: 0.0
+solve_time_per_task: 1.0e-6
+cost_model:
+  upwind_faces:
+    time_per_upwind_face: 1.0e-6",
+        )
+        .unwrap();
+        let grids = std::slice::from_ref(&grid);
+        let builtin = simulate_grids(&param_file, grids).unwrap().remove(0);
+        let cost_model: Rc<dyn CostModel> = Rc::new(UpwindCost);
+        let custom = simulate_grids_with_cost_model(&param_file, grids, &cost_model)
+            .unwrap()
+            .remove(0);
+        let constant = CostModelParams::Constant.build(1.0e-6);
+        let constant = simulate_grids_with_cost_model(&param_file, grids, &constant)
+            .unwrap()
+            .remove(0);
+        assert_eq!(builtin.time, custom.time);
+        assert!(builtin.time > constant.time);
+    }
+}
@@ -0,0 +1,430 @@
+use std::collections::HashMap;
+
+use crate::vector_3d::Vector3D;
+
+/// Points are snapped to an integer lattice with this many steps along
+/// each axis of the bounding box. This keeps every intermediate value of
+/// the orientation and insphere determinants (and the super tetrahedron)
+/// within i128, so both predicates can be evaluated exactly.
+const QUANTIZATION_STEPS: i64 = 1 << 18;
+
+type QuantizedPoint = [i64; 3];
+
+#[derive(Clone, Debug)]
+struct Tetra {
+    vertices: [usize; 4],
+    /// The neighbour across the face opposite to the vertex with the same index.
+    neighbours: [Option<usize>; 4],
+    alive: bool,
+}
+
+/// A Delaunay tetrahedralization constructed by incremental Bowyer-Watson
+/// insertion. All tetrahedra are positively oriented.
+pub struct Triangulation {
+    points: Vec<QuantizedPoint>,
+    num_input_points: usize,
+    tetras: Vec<Tetra>,
+    free_slots: Vec<usize>,
+    last_created: usize,
+    min: Vector3D,
+    scale: f64,
+    /// For input points that coincide with an earlier point after snapping,
+    /// the index of that earlier point.
+    pub duplicates: HashMap<usize, usize>,
+}
+
+impl Triangulation {
+    /// All points need to lie within the box spanned by `min` and `max`.
+    pub fn new(points: &[Vector3D], min: &Vector3D, max: &Vector3D) -> Self {
+        let extent = max.sub(min);
+        let largest_extent = *extent.x.max(extent.y.max(extent.z));
+        let scale = QUANTIZATION_STEPS as f64 / largest_extent;
+        let mut quantized: Vec<QuantizedPoint> = points
+            .iter()
+            .map(|point| {
+                let relative = point.sub(min);
+                [
+                    (*relative.x * scale).round() as i64,
+                    (*relative.y * scale).round() as i64,
+                    (*relative.z * scale).round() as i64,
+                ]
+            })
+            .collect();
+        let num_input_points = quantized.len();
+        // Super tetrahedron: contains the cube [0, Q]^3 since x + y + z <= 3Q < 6Q - 2Q
+        let q = QUANTIZATION_STEPS;
+        quantized.push([-q, -q, -q]);
+        quantized.push([6 * q, -q, -q]);
+        quantized.push([-q, 6 * q, -q]);
+        quantized.push([-q, -q, 6 * q]);
+        let s = num_input_points;
+        let mut super_vertices = [s, s + 1, s + 2, s + 3];
+        if orient3d(&quantized, super_vertices) < 0 {
+            super_vertices.swap(0, 1);
+        }
+        let mut triangulation = Triangulation {
+            points: quantized,
+            num_input_points,
+            tetras: vec![Tetra {
+                vertices: super_vertices,
+                neighbours: [None; 4],
+                alive: true,
+            }],
+            free_slots: vec![],
+            last_created: 0,
+            min: min.clone(),
+            scale,
+            duplicates: HashMap::new(),
+        };
+        for point in get_insertion_order(&triangulation.points[..num_input_points]) {
+            triangulation.insert(point);
+        }
+        triangulation
+    }
+
+    pub fn is_super_vertex(&self, vertex: usize) -> bool {
+        vertex >= self.num_input_points
+    }
+
+    /// The position of a vertex after snapping to the lattice.
+    pub fn get_position(&self, vertex: usize) -> Vector3D {
+        let [x, y, z] = self.points[vertex];
+        Vector3D::new(
+            x as f64 / self.scale + *self.min.x,
+            y as f64 / self.scale + *self.min.y,
+            z as f64 / self.scale + *self.min.z,
+        )
+    }
+
+    pub fn iter_tetras(&self) -> impl Iterator<Item = [usize; 4]> + '_ {
+        self.tetras
+            .iter()
+            .filter(|tetra| tetra.alive)
+            .map(|tetra| tetra.vertices)
+    }
+
+    pub fn get_circumcenter(&self, tetra: [usize; 4]) -> Vector3D {
+        let a = self.get_position(tetra[0]);
+        let d1 = self.get_position(tetra[1]).sub(&a);
+        let d2 = self.get_position(tetra[2]).sub(&a);
+        let d3 = self.get_position(tetra[3]).sub(&a);
+        let denominator = 2.0 * *d1.dot(&d2.cross(&d3));
+        let numerator = d2
+            .cross(&d3)
+            .scale(*d1.dot(&d1))
+            .add(&d3.cross(&d1).scale(*d2.dot(&d2)))
+            .add(&d1.cross(&d2).scale(*d3.dot(&d3)));
+        a.add(&numerator.scale(1.0 / denominator))
+    }
+
+    fn insert(&mut self, point: usize) {
+        let containing = self.locate(point);
+        if let Some(vertex) = self.tetras[containing]
+            .vertices
+            .iter()
+            .find(|vertex| self.points[**vertex] == self.points[point])
+        {
+            self.duplicates.insert(point, *vertex);
+            return;
+        }
+        let mut cavity = self.find_cavity(containing, point);
+        let boundary = loop {
+            let boundary = self.get_cavity_boundary(&cavity);
+            // In degenerate configurations, the point can be coplanar with
+            // (or behind) a face of the cavity. Growing the cavity across
+            // that face keeps all new tetrahedra positively oriented.
+            let invalid = boundary
+                .iter()
+                .find(|(tetra, face)| self.orient_replaced(*tetra, *face, point) <= 0);
+            match invalid {
+                Some((tetra, face)) => {
+                    let outer = self.tetras[*tetra].neighbours[*face]
+                        .expect("Point outside of super tetrahedron");
+                    cavity.push(outer);
+                }
+                None => break boundary,
+            }
+        };
+        self.fill_cavity(&cavity, &boundary, point);
+    }
+
+    /// Visibility walk from the most recently created tetrahedron.
+    fn locate(&self, point: usize) -> usize {
+        let mut current = self.last_created;
+        let mut start_face = 0;
+        'walk: loop {
+            for i in 0..4 {
+                let face = (start_face + i) % 4;
+                if self.orient_replaced(current, face, point) < 0 {
+                    current = self.tetras[current].neighbours[face]
+                        .expect("Point outside of super tetrahedron");
+                    start_face = (start_face + 1) % 4;
+                    continue 'walk;
+                }
+            }
+            return current;
+        }
+    }
+
+    fn find_cavity(&self, containing: usize, point: usize) -> Vec<usize> {
+        let mut cavity = vec![containing];
+        let mut stack = vec![containing];
+        while let Some(tetra) = stack.pop() {
+            for neighbour in self.tetras[tetra].neighbours.iter().flatten() {
+                if !cavity.contains(neighbour) && self.in_circumsphere(*neighbour, point) {
+                    cavity.push(*neighbour);
+                    stack.push(*neighbour);
+                }
+            }
+        }
+        cavity
+    }
+
+    /// Faces of the cavity given as (tetra, index of the opposite vertex).
+    fn get_cavity_boundary(&self, cavity: &[usize]) -> Vec<(usize, usize)> {
+        let mut boundary = vec![];
+        for tetra in cavity.iter() {
+            for (face, neighbour) in self.tetras[*tetra].neighbours.iter().enumerate() {
+                let is_boundary = match neighbour {
+                    Some(neighbour) => !cavity.contains(neighbour),
+                    None => true,
+                };
+                if is_boundary {
+                    boundary.push((*tetra, face));
+                }
+            }
+        }
+        boundary
+    }
+
+    fn fill_cavity(&mut self, cavity: &[usize], boundary: &[(usize, usize)], point: usize) {
+        // Each new tetrahedron replaces the adjacency of the outer neighbour across
+        // its boundary face. Look up the outer face index before any slots are reused.
+        let new_tetras: Vec<(Option<(usize, usize)>, Tetra)> = boundary
+            .iter()
+            .map(|(tetra, face)| {
+                let mut vertices = self.tetras[*tetra].vertices;
+                vertices[*face] = point;
+                let mut neighbours = [None; 4];
+                let outer = self.tetras[*tetra].neighbours[*face];
+                neighbours[*face] = outer;
+                let outer_face = outer.map(|outer| {
+                    let outer_face = self.tetras[outer]
+                        .neighbours
+                        .iter()
+                        .position(|neighbour| *neighbour == Some(*tetra))
+                        .unwrap();
+                    (outer, outer_face)
+                });
+                (
+                    outer_face,
+                    Tetra {
+                        vertices,
+                        neighbours,
+                        alive: true,
+                    },
+                )
+            })
+            .collect();
+        for tetra in cavity.iter() {
+            self.tetras[*tetra].alive = false;
+            self.free_slots.push(*tetra);
+        }
+        let mut indices = vec![];
+        for (outer_face, tetra) in new_tetras.into_iter() {
+            let index = self.add_tetra(tetra);
+            if let Some((outer, face)) = outer_face {
+                self.tetras[outer].neighbours[face] = Some(index);
+            }
+            indices.push(index);
+        }
+        // New tetrahedra are adjacent across faces containing the new point.
+        // Each such face is identified by the other two vertices on it.
+        let mut open_faces: HashMap<(usize, usize), (usize, usize)> = HashMap::new();
+        for index in indices.iter() {
+            let vertices = self.tetras[*index].vertices;
+            for face in 0..4 {
+                if vertices[face] == point {
+                    continue;
+                }
+                let mut edge: Vec<usize> = (0..4)
+                    .filter(|i| *i != face && vertices[*i] != point)
+                    .map(|i| vertices[i])
+                    .collect();
+                edge.sort_unstable();
+                let key = (edge[0], edge[1]);
+                match open_faces.remove(&key) {
+                    Some((other, other_face)) => {
+                        self.tetras[*index].neighbours[face] = Some(other);
+                        self.tetras[other].neighbours[other_face] = Some(*index);
+                    }
+                    None => {
+                        open_faces.insert(key, (*index, face));
+                    }
+                }
+            }
+        }
+        self.last_created = *indices.last().unwrap();
+    }
+
+    fn add_tetra(&mut self, tetra: Tetra) -> usize {
+        match self.free_slots.pop() {
+            Some(slot) => {
+                self.tetras[slot] = tetra;
+                slot
+            }
+            None => {
+                self.tetras.push(tetra);
+                self.tetras.len() - 1
+            }
+        }
+    }
+
+    /// The orientation of the tetrahedron obtained by replacing one vertex with the point.
+    fn orient_replaced(&self, tetra: usize, face: usize, point: usize) -> i32 {
+        let mut vertices = self.tetras[tetra].vertices;
+        vertices[face] = point;
+        orient3d(&self.points, vertices)
+    }
+
+    fn in_circumsphere(&self, tetra: usize, point: usize) -> bool {
+        insphere(&self.points, self.tetras[tetra].vertices, point) > 0
+    }
+}
+
+/// Sorts the points along a Morton curve so that consecutive insertions are
+/// close to each other and the walk in `locate` stays short.
+fn get_insertion_order(points: &[QuantizedPoint]) -> Vec<usize> {
+    let interleave = |point: &QuantizedPoint| {
+        let mut code: u64 = 0;
+        for bit in 0..21 {
+            for (axis, coordinate) in point.iter().enumerate() {
+                code |= (((*coordinate as u64) >> bit) & 1) << (3 * bit + axis);
+            }
+        }
+        code
+    };
+    let mut order: Vec<usize> = (0..points.len()).collect();
+    order.sort_by_key(|index| interleave(&points[*index]));
+    order
+}
+
+fn difference(a: &QuantizedPoint, b: &QuantizedPoint) -> [i128; 3] {
+    [
+        (a[0] - b[0]) as i128,
+        (a[1] - b[1]) as i128,
+        (a[2] - b[2]) as i128,
+    ]
+}
+
+fn det3(a: &[i128; 3], b: &[i128; 3], c: &[i128; 3]) -> i128 {
+    a[0] * (b[1] * c[2] - b[2] * c[1]) - a[1] * (b[0] * c[2] - b[2] * c[0])
+        + a[2] * (b[0] * c[1] - b[1] * c[0])
+}
+
+/// Exact sign of the orientation of the tetrahedron.
+fn orient3d(points: &[QuantizedPoint], vertices: [usize; 4]) -> i32 {
+    let a = &points[vertices[0]];
+    let det = det3(
+        &difference(&points[vertices[1]], a),
+        &difference(&points[vertices[2]], a),
+        &difference(&points[vertices[3]], a),
+    );
+    det.signum() as i32
+}
+
+/// Exact sign of the insphere test: positive if the point lies strictly inside
+/// the circumsphere of the positively oriented tetrahedron.
+fn insphere(points: &[QuantizedPoint], vertices: [usize; 4], point: usize) -> i32 {
+    let e = &points[point];
+    let rows: Vec<[i128; 3]> = vertices
+        .iter()
+        .map(|vertex| difference(&points[*vertex], e))
+        .collect();
+    let lift = |row: &[i128; 3]| row[0] * row[0] + row[1] * row[1] + row[2] * row[2];
+    let det = -lift(&rows[0]) * det3(&rows[1], &rows[2], &rows[3])
+        + lift(&rows[1]) * det3(&rows[0], &rows[2], &rows[3])
+        - lift(&rows[2]) * det3(&rows[0], &rows[1], &rows[3])
+        + lift(&rows[3]) * det3(&rows[0], &rows[1], &rows[2]);
+    // For positively oriented tetrahedra, the determinant is negative for interior points
+    -det.signum() as i32
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::Rng;
+    use rand::SeedableRng;
+
+    use super::*;
+
+    fn get_volume_times_six(points: &[QuantizedPoint], vertices: [usize; 4]) -> i128 {
+        let a = &points[vertices[0]];
+        det3(
+            &difference(&points[vertices[1]], a),
+            &difference(&points[vertices[2]], a),
+            &difference(&points[vertices[3]], a),
+        )
+    }
+
+    #[test]
+    fn predicates() {
+        let points = vec![
+            [0, 0, 0],
+            [4, 0, 0],
+            [0, 4, 0],
+            [0, 0, 4],
+            [1, 1, 1],
+            [9, 9, 9],
+        ];
+        assert_eq!(orient3d(&points, [0, 1, 2, 3]), 1);
+        assert_eq!(orient3d(&points, [1, 0, 2, 3]), -1);
+        assert_eq!(insphere(&points, [0, 1, 2, 3], 4), 1);
+        assert_eq!(insphere(&points, [0, 1, 2, 3], 5), -1);
+        // (4, 4, 0) lies on the circumsphere
+        let points = vec![[0, 0, 0], [4, 0, 0], [0, 4, 0], [0, 0, 4], [4, 4, 0]];
+        assert_eq!(insphere(&points, [0, 1, 2, 3], 4), 0);
+    }
+
+    #[test]
+    fn empty_circumspheres() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let points: Vec<Vector3D> = (0..200)
+            .map(|_| Vector3D::new(rng.gen(), rng.gen(), rng.gen()))
+            .collect();
+        let min = Vector3D::new(0.0, 0.0, 0.0);
+        let max = Vector3D::new(1.0, 1.0, 1.0);
+        let triangulation = Triangulation::new(&points, &min, &max);
+        let mut volume = 0;
+        for tetra in triangulation.iter_tetras() {
+            assert_eq!(orient3d(&triangulation.points, tetra), 1);
+            for point in 0..points.len() {
+                assert!(insphere(&triangulation.points, tetra, point) <= 0);
+            }
+            volume += get_volume_times_six(&triangulation.points, tetra);
+        }
+        // The tetrahedra tile the super tetrahedron without gaps or overlaps
+        let super_tetra = [200, 201, 202, 203];
+        assert_eq!(
+            volume,
+            get_volume_times_six(&triangulation.points, super_tetra).abs()
+        );
+    }
+
+    #[test]
+    fn cospherical_lattice() {
+        let points: Vec<Vector3D> = (0..125)
+            .map(|i| Vector3D::new((i % 5) as f64, (i / 5 % 5) as f64, (i / 25) as f64))
+            .collect();
+        let min = Vector3D::new(0.0, 0.0, 0.0);
+        let max = Vector3D::new(4.0, 4.0, 4.0);
+        let triangulation = Triangulation::new(&points, &min, &max);
+        let volume: f64 = triangulation
+            .iter_tetras()
+            .filter(|tetra| tetra.iter().all(|v| !triangulation.is_super_vertex(*v)))
+            .map(|tetra| get_volume_times_six(&triangulation.points, tetra) as f64)
+            .sum();
+        let scale = QUANTIZATION_STEPS as f64 / 4.0;
+        assert!((volume / 6.0 / scale.powi(3) - 64.0).abs() < 1e-6);
+    }
+}
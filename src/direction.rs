@@ -51,6 +51,24 @@ pub fn get_equally_distributed_directions_on_sphere(num_directions: usize) -> Ve
         .collect()
 }
 
+/// For each direction, the index of the direction closest to its mirror
+/// image at a plane with the given unit normal.
+pub fn get_mirrored_directions(directions: &[Direction], normal: &Vector3D) -> Vec<usize> {
+    directions
+        .iter()
+        .map(|direction| {
+            let mirrored = direction
+                .vector
+                .sub(&normal.scale(2.0 * *direction.vector.dot(normal)));
+            directions
+                .iter()
+                .max_by_key(|candidate| candidate.vector.dot(&mirrored))
+                .unwrap()
+                .index
+        })
+        .collect()
+}
+
 pub fn get_directions_from_constant(constant: &[[f64; 3]]) -> Vec<Direction> {
     constant
         .iter()
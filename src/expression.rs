@@ -0,0 +1,589 @@
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fmt;
+
+use anyhow::anyhow;
+use anyhow::bail;
+use anyhow::Result;
+use serde::Deserialize;
+use serde::Serialize;
+
+/// An arithmetic expression such as `1.2e-6 + 3e-8 * num_faces`. Supports
+/// `+ - * / ^`, parentheses, variables and the functions `min`, `max`,
+/// `abs`, `sqrt`, `exp` and `ln`.
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+#[serde(try_from = "RawExpression", into = "RawExpression")]
+pub struct Expression {
+    source: String,
+    root: Node,
+}
+
+/// Param files may contain plain numbers as well as expressions.
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum RawExpression {
+    Number(f64),
+    Text(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Node {
+    Number(f64),
+    Variable(String),
+    Negate(Box<Node>),
+    Binary(BinaryOperator, Box<Node>, Box<Node>),
+    Call(Function, Vec<Node>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum BinaryOperator {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Power,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Function {
+    Min,
+    Max,
+    Abs,
+    Sqrt,
+    Exp,
+    Ln,
+}
+
+impl Function {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "min" => Some(Function::Min),
+            "max" => Some(Function::Max),
+            "abs" => Some(Function::Abs),
+            "sqrt" => Some(Function::Sqrt),
+            "exp" => Some(Function::Exp),
+            "ln" => Some(Function::Ln),
+            _ => None,
+        }
+    }
+
+    fn num_arguments(&self) -> usize {
+        match self {
+            Function::Min | Function::Max => 2,
+            _ => 1,
+        }
+    }
+
+    fn apply(&self, arguments: &[f64]) -> f64 {
+        match self {
+            Function::Min => arguments[0].min(arguments[1]),
+            Function::Max => arguments[0].max(arguments[1]),
+            Function::Abs => arguments[0].abs(),
+            Function::Sqrt => arguments[0].sqrt(),
+            Function::Exp => arguments[0].exp(),
+            Function::Ln => arguments[0].ln(),
+        }
+    }
+}
+
+impl Expression {
+    pub fn parse(source: &str) -> Result<Self> {
+        let tokens = tokenize(source)?;
+        let mut parser = Parser {
+            source,
+            tokens,
+            position: 0,
+        };
+        let root = parser.parse_sum()?;
+        if let Some((token, offset)) = parser.peek() {
+            bail!(
+                "Unexpected {} at position {} in expression `{}`",
+                token,
+                offset,
+                source
+            );
+        }
+        Ok(Expression {
+            source: source.to_string(),
+            root,
+        })
+    }
+
+    pub fn constant(value: f64) -> Self {
+        Expression {
+            source: value.to_string(),
+            root: Node::Number(value),
+        }
+    }
+
+    /// Replaces the variables that name one of the constants by its value.
+    pub fn substitute(&mut self, constants: &BTreeMap<String, f64>) {
+        self.root.substitute(constants);
+    }
+
+    /// Checks that all variables are accepted by the predicate.
+    pub fn check_variables(
+        &self,
+        name: &str,
+        is_known: impl Fn(&str) -> bool,
+        known: &[&str],
+    ) -> Result<()> {
+        match self
+            .variables()
+            .into_iter()
+            .find(|variable| !is_known(variable))
+        {
+            Some(variable) => Err(anyhow!(
+                "Unknown variable `{}` in {} `{}`. Available variables: {}",
+                variable,
+                name,
+                self.source,
+                known.join(", ")
+            )),
+            None => Ok(()),
+        }
+    }
+
+    pub fn variables(&self) -> Vec<&str> {
+        let mut variables = vec![];
+        self.root.collect_variables(&mut variables);
+        variables
+    }
+
+    /// Evaluates the expression. Variables which the lookup does not know
+    /// evaluate to zero, which cannot happen after a successful check.
+    pub fn evaluate(&self, lookup: impl Fn(&str) -> Option<f64>) -> f64 {
+        self.root.evaluate(&lookup)
+    }
+
+    /// Evaluates an expression for a duration. Negative results count as
+    /// zero, since time cannot run backwards. Non-finite results are an
+    /// error.
+    pub fn evaluate_time(&self, lookup: impl Fn(&str) -> Option<f64>) -> Result<f64> {
+        let time = self.evaluate(&lookup);
+        if !time.is_finite() {
+            let mut variables = self.variables();
+            variables.sort_unstable();
+            variables.dedup();
+            let values: Vec<String> = variables
+                .into_iter()
+                .map(|name| format!("{} = {}", name, lookup(name).unwrap_or(0.0)))
+                .collect();
+            bail!(
+                "The expression {:?} evaluates to {} with {}",
+                self.source,
+                time,
+                values.join(", ")
+            );
+        }
+        Ok(time.max(0.0))
+    }
+
+    /// Checks that an expression for a duration which does not depend on
+    /// any variable gives a valid time.
+    pub fn check_constant_time(&self, name: &str) -> Result<()> {
+        if !self.variables().is_empty() {
+            return Ok(());
+        }
+        let time = self.evaluate(|_| None);
+        if !time.is_finite() || time < 0.0 {
+            bail!(
+                "The {} expression {:?} evaluates to {}, which is not a valid time",
+                name,
+                self.source,
+                time
+            );
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Expression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.source)
+    }
+}
+
+impl TryFrom<RawExpression> for Expression {
+    type Error = anyhow::Error;
+
+    fn try_from(raw: RawExpression) -> Result<Self> {
+        match raw {
+            RawExpression::Number(value) => Ok(Expression::constant(value)),
+            RawExpression::Text(source) => Expression::parse(&source),
+        }
+    }
+}
+
+impl From<Expression> for RawExpression {
+    fn from(expression: Expression) -> Self {
+        RawExpression::Text(expression.source)
+    }
+}
+
+impl Node {
+    fn substitute(&mut self, constants: &BTreeMap<String, f64>) {
+        match self {
+            Node::Number(_) => {}
+            Node::Variable(name) => {
+                if let Some(value) = constants.get(name) {
+                    *self = Node::Number(*value);
+                }
+            }
+            Node::Negate(node) => node.substitute(constants),
+            Node::Binary(_, lhs, rhs) => {
+                lhs.substitute(constants);
+                rhs.substitute(constants);
+            }
+            Node::Call(_, arguments) => {
+                for argument in arguments.iter_mut() {
+                    argument.substitute(constants);
+                }
+            }
+        }
+    }
+
+    fn collect_variables<'a>(&'a self, variables: &mut Vec<&'a str>) {
+        match self {
+            Node::Number(_) => {}
+            Node::Variable(name) => variables.push(name),
+            Node::Negate(node) => node.collect_variables(variables),
+            Node::Binary(_, lhs, rhs) => {
+                lhs.collect_variables(variables);
+                rhs.collect_variables(variables);
+            }
+            Node::Call(_, arguments) => {
+                for argument in arguments.iter() {
+                    argument.collect_variables(variables);
+                }
+            }
+        }
+    }
+
+    fn evaluate(&self, lookup: &impl Fn(&str) -> Option<f64>) -> f64 {
+        match self {
+            Node::Number(value) => *value,
+            Node::Variable(name) => lookup(name).unwrap_or(0.0),
+            Node::Negate(node) => -node.evaluate(lookup),
+            Node::Binary(operator, lhs, rhs) => {
+                let lhs = lhs.evaluate(lookup);
+                let rhs = rhs.evaluate(lookup);
+                match operator {
+                    BinaryOperator::Add => lhs + rhs,
+                    BinaryOperator::Subtract => lhs - rhs,
+                    BinaryOperator::Multiply => lhs * rhs,
+                    BinaryOperator::Divide => lhs / rhs,
+                    BinaryOperator::Power => lhs.powf(rhs),
+                }
+            }
+            Node::Call(function, arguments) => {
+                let arguments: Vec<f64> = arguments
+                    .iter()
+                    .map(|argument| argument.evaluate(lookup))
+                    .collect();
+                function.apply(&arguments)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(f64),
+    Identifier(String),
+    Symbol(char),
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Number(value) => write!(f, "number {}", value),
+            Token::Identifier(name) => write!(f, "`{}`", name),
+            Token::Symbol(symbol) => write!(f, "`{}`", symbol),
+        }
+    }
+}
+
+/// Splits the source into tokens along with their character offsets.
+fn tokenize(source: &str) -> Result<Vec<(Token, usize)>> {
+    let chars: Vec<char> = source.chars().collect();
+    let mut tokens = vec![];
+    let mut offset = 0;
+    while offset < chars.len() {
+        let c = chars[offset];
+        let start = offset;
+        if c.is_whitespace() {
+            offset += 1;
+        } else if c.is_ascii_digit() || c == '.' {
+            while offset < chars.len() && (chars[offset].is_ascii_digit() || chars[offset] == '.') {
+                offset += 1;
+            }
+            // Exponent, as in 1.5e-6
+            if offset < chars.len() && (chars[offset] == 'e' || chars[offset] == 'E') {
+                let mut end = offset + 1;
+                if end < chars.len() && (chars[end] == '+' || chars[end] == '-') {
+                    end += 1;
+                }
+                if end < chars.len() && chars[end].is_ascii_digit() {
+                    offset = end;
+                    while offset < chars.len() && chars[offset].is_ascii_digit() {
+                        offset += 1;
+                    }
+                }
+            }
+            let text: String = chars[start..offset].iter().collect();
+            let value = text.parse::<f64>().map_err(|_| {
+                anyhow!(
+                    "Invalid number `{}` at position {} in expression `{}`",
+                    text,
+                    start,
+                    source
+                )
+            })?;
+            tokens.push((Token::Number(value), start));
+        } else if c.is_alphabetic() || c == '_' {
+            while offset < chars.len() && (chars[offset].is_alphanumeric() || chars[offset] == '_')
+            {
+                offset += 1;
+            }
+            let name: String = chars[start..offset].iter().collect();
+            tokens.push((Token::Identifier(name), start));
+        } else if "+-*/^(),".contains(c) {
+            tokens.push((Token::Symbol(c), start));
+            offset += 1;
+        } else {
+            bail!(
+                "Unexpected character `{}` at position {} in expression `{}`",
+                c,
+                start,
+                source
+            );
+        }
+    }
+    Ok(tokens)
+}
+
+/// Recursive descent parser. `^` binds tighter than unary minus, which binds
+/// tighter than `*` and `/`, which bind tighter than `+` and `-`.
+struct Parser<'a> {
+    source: &'a str,
+    tokens: Vec<(Token, usize)>,
+    position: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&(Token, usize)> {
+        self.tokens.get(self.position)
+    }
+
+    fn next_is(&self, symbol: char) -> bool {
+        matches!(self.peek(), Some((Token::Symbol(s), _)) if *s == symbol)
+    }
+
+    fn expect(&mut self, symbol: char) -> Result<()> {
+        if self.next_is(symbol) {
+            self.position += 1;
+            Ok(())
+        } else {
+            Err(self.error(&format!("`{}`", symbol)))
+        }
+    }
+
+    fn error(&self, expected: &str) -> anyhow::Error {
+        match self.peek() {
+            Some((token, offset)) => anyhow!(
+                "Expected {} but found {} at position {} in expression `{}`",
+                expected,
+                token,
+                offset,
+                self.source
+            ),
+            None => anyhow!(
+                "Expected {} at the end of expression `{}`",
+                expected,
+                self.source
+            ),
+        }
+    }
+
+    fn parse_sum(&mut self) -> Result<Node> {
+        let mut node = self.parse_product()?;
+        loop {
+            let operator = if self.next_is('+') {
+                BinaryOperator::Add
+            } else if self.next_is('-') {
+                BinaryOperator::Subtract
+            } else {
+                return Ok(node);
+            };
+            self.position += 1;
+            let rhs = self.parse_product()?;
+            node = Node::Binary(operator, Box::new(node), Box::new(rhs));
+        }
+    }
+
+    fn parse_product(&mut self) -> Result<Node> {
+        let mut node = self.parse_unary()?;
+        loop {
+            let operator = if self.next_is('*') {
+                BinaryOperator::Multiply
+            } else if self.next_is('/') {
+                BinaryOperator::Divide
+            } else {
+                return Ok(node);
+            };
+            self.position += 1;
+            let rhs = self.parse_unary()?;
+            node = Node::Binary(operator, Box::new(node), Box::new(rhs));
+        }
+    }
+
+    fn parse_unary(&mut self) -> Result<Node> {
+        if self.next_is('-') {
+            self.position += 1;
+            return Ok(Node::Negate(Box::new(self.parse_unary()?)));
+        }
+        if self.next_is('+') {
+            self.position += 1;
+            return self.parse_unary();
+        }
+        self.parse_power()
+    }
+
+    fn parse_power(&mut self) -> Result<Node> {
+        let base = self.parse_atom()?;
+        if self.next_is('^') {
+            self.position += 1;
+            let exponent = self.parse_unary()?;
+            return Ok(Node::Binary(
+                BinaryOperator::Power,
+                Box::new(base),
+                Box::new(exponent),
+            ));
+        }
+        Ok(base)
+    }
+
+    fn parse_atom(&mut self) -> Result<Node> {
+        let (token, offset) = match self.peek() {
+            Some(entry) => entry.clone(),
+            None => return Err(self.error("a number, variable or `(`")),
+        };
+        match token {
+            Token::Number(value) => {
+                self.position += 1;
+                Ok(Node::Number(value))
+            }
+            Token::Identifier(name) => {
+                self.position += 1;
+                if !self.next_is('(') {
+                    return Ok(Node::Variable(name));
+                }
+                let function = Function::from_name(&name).ok_or_else(|| {
+                    anyhow!(
+                        "Unknown function `{}` at position {} in expression `{}`",
+                        name,
+                        offset,
+                        self.source
+                    )
+                })?;
+                self.expect('(')?;
+                let mut arguments = vec![self.parse_sum()?];
+                while self.next_is(',') {
+                    self.position += 1;
+                    arguments.push(self.parse_sum()?);
+                }
+                self.expect(')')?;
+                if arguments.len() != function.num_arguments() {
+                    bail!(
+                        "Function `{}` at position {} takes {} argument(s) but got {} in expression `{}`",
+                        name,
+                        offset,
+                        function.num_arguments(),
+                        arguments.len(),
+                        self.source
+                    );
+                }
+                Ok(Node::Call(function, arguments))
+            }
+            Token::Symbol('(') => {
+                self.position += 1;
+                let node = self.parse_sum()?;
+                self.expect(')')?;
+                Ok(node)
+            }
+            Token::Symbol(_) => Err(self.error("a number, variable or `(`")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn evaluate(source: &str) -> f64 {
+        Expression::parse(source)
+            .unwrap()
+            .evaluate(|name| match name {
+                "x" => Some(2.0),
+                "num_faces" => Some(6.0),
+                _ => None,
+            })
+    }
+
+    #[test]
+    fn arithmetic() {
+        assert_eq!(evaluate("1 + 2 * 3"), 7.0);
+        assert_eq!(evaluate("(1 + 2) * 3"), 9.0);
+        assert_eq!(evaluate("-x^2"), -4.0);
+        assert_eq!(evaluate("2^3^2"), 512.0);
+        assert_eq!(evaluate("8 / 2 / 2 - 1"), 1.0);
+        assert_eq!(evaluate("max(x, 3) + min(1, -1) + abs(-1)"), 3.0);
+        assert!((evaluate("1.2e-6 + 3e-8 * num_faces") - 1.38e-6).abs() < 1e-18);
+    }
+
+    #[test]
+    fn errors() {
+        let message = |source| Expression::parse(source).err().unwrap().to_string();
+        assert_eq!(
+            message("1 + #"),
+            "Unexpected character `#` at position 4 in expression `1 + #`"
+        );
+        assert_eq!(
+            message("(1 + 2"),
+            "Expected `)` at the end of expression `(1 + 2`"
+        );
+        assert_eq!(
+            message("1 2"),
+            "Unexpected number 2 at position 2 in expression `1 2`"
+        );
+        assert!(message("foo(1)").starts_with("Unknown function `foo`"));
+        assert!(message("min(1)").contains("takes 2 argument(s) but got 1"));
+        let mut expression = Expression::parse("offset + bytes * y").unwrap();
+        let constants = vec![("offset".to_string(), 1.0)].into_iter().collect();
+        expression.substitute(&constants);
+        let error = expression
+            .check_variables("send_time", |name| name == "bytes", &["bytes"])
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Unknown variable `y` in send_time `offset + bytes * y`. Available variables: bytes"
+        );
+        let negative = Expression::parse("-1e-6").unwrap();
+        assert!(negative.check_constant_time("solve_time").is_err());
+        let expression = Expression::parse("x - 5").unwrap();
+        assert!(expression.check_constant_time("solve_time").is_ok());
+        assert_eq!(expression.evaluate_time(|_| Some(2.0)).unwrap(), 0.0);
+        let expression = Expression::parse("bytes / num_tasks").unwrap();
+        let error = expression
+            .evaluate_time(|name| match name {
+                "bytes" => Some(8.0),
+                _ => Some(0.0),
+            })
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "The expression \"bytes / num_tasks\" evaluates to inf with bytes = 8, num_tasks = 0"
+        );
+    }
+}
@@ -1,6 +1,37 @@
 use crate::vector_3d::Vector3D;
 
-#[derive(Hash, PartialEq, Eq, Clone)]
+/// The face of an edge from cell A to cell B. The normal points from B
+/// towards A, so B lies downwind of A for any direction that has a
+/// negative scalar product with the normal.
+#[derive(PartialEq, Clone, Debug)]
 pub struct Face {
     pub normal: Vector3D,
+    /// Only known if the grid file contains face geometry.
+    pub area: Option<f64>,
+}
+
+impl Face {
+    /// Constructs the face of an edge from cell A to cell B from the
+    /// outward normal of cell A.
+    pub fn from_outward_normal(outward_normal: &Vector3D, area: f64) -> Face {
+        Face {
+            normal: outward_normal.scale(-1.0 / outward_normal.norm()),
+            area: Some(area),
+        }
+    }
+
+    /// The flux of a unit beam in the given direction through this face,
+    /// measured along the normal. Faces without a known area count as unit area.
+    pub fn get_projected_area(&self, direction: &Vector3D) -> f64 {
+        *self.normal.dot(direction) * self.area.unwrap_or(1.0)
+    }
+
+    /// Whether radiation in the direction crosses the face from cell A to
+    /// cell B. Faces whose projected area is below `tolerance` are ignored,
+    /// so a large face needs a smaller angle to the direction than a tiny
+    /// one to be dropped.
+    pub fn is_downwind(&self, direction: &Vector3D, tolerance: f64) -> bool {
+        let projected_area = self.get_projected_area(direction);
+        projected_area < 0.0 && projected_area.abs() >= tolerance
+    }
 }
@@ -0,0 +1,453 @@
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+
+use anyhow::bail;
+use anyhow::Result;
+use clap::ArgEnum;
+use rand::rngs::StdRng;
+use rand::Rng;
+use rand::SeedableRng;
+
+use crate::cell::Cell;
+use crate::face::Face;
+use crate::grid::Grid;
+use crate::vector_3d::Vector3D;
+use crate::voronoi::generate_tessellation;
+use crate::voronoi::VoronoiSpec;
+
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq)]
+pub enum GridKind {
+    Cartesian,
+    JitteredCartesian,
+    HexagonalClosePacking,
+    Cartesian2d,
+    JitteredCartesian2d,
+    Hexagonal2d,
+    Voronoi,
+}
+
+impl GridKind {
+    pub fn num_dimensions(&self) -> usize {
+        match self {
+            GridKind::Cartesian
+            | GridKind::JitteredCartesian
+            | GridKind::HexagonalClosePacking
+            | GridKind::Voronoi => 3,
+            GridKind::Cartesian2d | GridKind::JitteredCartesian2d | GridKind::Hexagonal2d => 2,
+        }
+    }
+}
+
+/// Splits the bounding box of the grid into equally sized blocks,
+/// one per processor.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Decomposition {
+    pub num_processors: [usize; 3],
+}
+
+impl Decomposition {
+    pub fn blocks(num_processors: [usize; 3]) -> Self {
+        Decomposition { num_processors }
+    }
+
+    /// Distributes the prime factors of the number of processors over the axes
+    /// such that the blocks are as close to cubes as possible.
+    pub fn automatic(num_processors: usize, extent: [f64; 3]) -> Self {
+        let mut blocks = [1, 1, 1];
+        for factor in get_prime_factors(num_processors).into_iter().rev() {
+            let axis = (0..3)
+                .max_by(|a, b| {
+                    let length_a = extent[*a] / blocks[*a] as f64;
+                    let length_b = extent[*b] / blocks[*b] as f64;
+                    length_a.partial_cmp(&length_b).unwrap().then(b.cmp(a))
+                })
+                .unwrap();
+            blocks[axis] *= factor;
+        }
+        Decomposition::blocks(blocks)
+    }
+
+    pub fn total(&self) -> usize {
+        self.num_processors.iter().product()
+    }
+
+    fn get_processor_num(&self, center: &Vector3D, min: &[f64; 3], max: &[f64; 3]) -> usize {
+        let position = [*center.x, *center.y, *center.z];
+        let mut processor_num = 0;
+        for axis in (0..3).rev() {
+            let num = self.num_processors[axis];
+            let length = max[axis] - min[axis];
+            let block = if length > 0.0 {
+                ((position[axis] - min[axis]) / length * num as f64) as usize
+            } else {
+                0
+            };
+            processor_num = processor_num * num + block.min(num - 1);
+        }
+        processor_num
+    }
+}
+
+fn get_prime_factors(mut num: usize) -> Vec<usize> {
+    let mut factors = vec![];
+    let mut factor = 2;
+    while num > 1 {
+        while num.is_multiple_of(factor) {
+            factors.push(factor);
+            num /= factor;
+        }
+        factor += 1;
+    }
+    factors
+}
+
+#[derive(Clone, Debug)]
+pub struct GridSpec {
+    pub kind: GridKind,
+    /// Number of cells along each axis. The third entry is ignored for 2D grids.
+    pub size: [usize; 3],
+    pub num_processors: usize,
+    /// Explicit number of blocks along each axis. Overrides `num_processors`.
+    pub decomposition: Option<Decomposition>,
+    /// Maximum displacement of the jittered cell centers relative to the cell spacing.
+    pub jitter: f64,
+    /// Connect the cells of Cartesian grids across opposite faces of the box.
+    pub periodic: bool,
+    pub voronoi: VoronoiSpec,
+    pub seed: u64,
+}
+
+impl Default for GridSpec {
+    fn default() -> Self {
+        GridSpec {
+            kind: GridKind::Cartesian,
+            size: [16, 16, 16],
+            num_processors: 1,
+            decomposition: None,
+            jitter: 0.3,
+            periodic: false,
+            voronoi: VoronoiSpec::default(),
+            seed: 0,
+        }
+    }
+}
+
+impl GridSpec {
+    /// Checks that the grid gets at least one processor block along each
+    /// axis.
+    pub fn validate(&self) -> Result<()> {
+        match &self.decomposition {
+            Some(decomposition) if decomposition.num_processors.contains(&0) => bail!(
+                "Every axis needs at least one processor block, got {:?}",
+                decomposition.num_processors
+            ),
+            None if self.num_processors == 0 => {
+                bail!("The number of processors needs to be positive")
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+pub fn generate_grid(spec: &GridSpec) -> Grid {
+    let size = match spec.kind.num_dimensions() {
+        2 => [spec.size[0], spec.size[1], 1],
+        _ => spec.size,
+    };
+    let (centers, edges) = match spec.kind {
+        GridKind::Cartesian | GridKind::Cartesian2d => {
+            with_both_orders(cartesian(size, spec.periodic))
+        }
+        GridKind::JitteredCartesian | GridKind::JitteredCartesian2d => {
+            let (mut centers, pairs) = cartesian(size, spec.periodic);
+            jitter(
+                &mut centers,
+                spec.jitter,
+                spec.seed,
+                spec.kind.num_dimensions(),
+            );
+            with_both_orders((centers, pairs))
+        }
+        GridKind::HexagonalClosePacking => with_both_orders(hexagonal_close_packing(size)),
+        GridKind::Hexagonal2d => with_both_orders(hexagonal_2d(size)),
+        GridKind::Voronoi => {
+            let box_size = Vector3D::new(size[0] as f64, size[1] as f64, size[2] as f64);
+            let tessellation = generate_tessellation(&spec.voronoi, &box_size, spec.seed);
+            let edges = tessellation
+                .faces
+                .into_iter()
+                .map(|(i0, i1, face)| (i0, i1, Some(face)))
+                .collect();
+            (tessellation.points, edges)
+        }
+    };
+    let decomposition = spec.decomposition.clone().unwrap_or_else(|| {
+        let (min, max) = get_bounding_box(&centers);
+        let extent = [max[0] - min[0], max[1] - min[1], max[2] - min[2]];
+        Decomposition::automatic(spec.num_processors, extent)
+    });
+    build_grid_with_faces(centers, edges, &decomposition)
+}
+
+type Edges = Vec<(usize, usize, Option<Face>)>;
+
+fn with_both_orders(
+    (centers, pairs): (Vec<Vector3D>, Vec<(usize, usize)>),
+) -> (Vec<Vector3D>, Edges) {
+    let edges = pairs
+        .into_iter()
+        .flat_map(|(i0, i1)| vec![(i0, i1, None), (i1, i0, None)])
+        .collect();
+    (centers, edges)
+}
+
+/// Builds a grid from cell centers and neighbour pairs, assigning cells
+/// to processors according to the decomposition.
+pub fn build_grid(
+    centers: Vec<Vector3D>,
+    pairs: &[(usize, usize)],
+    decomposition: &Decomposition,
+) -> Grid {
+    let (centers, edges) = with_both_orders((centers, pairs.to_vec()));
+    build_grid_with_faces(centers, edges, decomposition)
+}
+
+/// Like `build_grid`, but for directed edges with optional face geometry.
+pub fn build_grid_with_faces(
+    centers: Vec<Vector3D>,
+    edges: Edges,
+    decomposition: &Decomposition,
+) -> Grid {
+    let (min, max) = get_bounding_box(&centers);
+    let mut num_cells_per_processor = vec![0; decomposition.total()];
+    let mut cells: Vec<Cell> = centers
+        .into_iter()
+        .map(|center| {
+            let processor_num = decomposition.get_processor_num(&center, &min, &max);
+            let local_index = num_cells_per_processor[processor_num];
+            num_cells_per_processor[processor_num] += 1;
+            Cell {
+                center,
+                global_index: 0,
+                local_index,
+                processor_num,
+                weights: BTreeMap::new(),
+            }
+        })
+        .collect();
+    let mut order: Vec<usize> = (0..cells.len()).collect();
+    order.sort_by_key(|index| (cells[*index].processor_num, cells[*index].local_index));
+    let mut new_index = vec![0; cells.len()];
+    for (global_index, old_index) in order.iter().enumerate() {
+        new_index[*old_index] = global_index;
+        cells[*old_index].global_index = global_index;
+    }
+    cells.sort_by_key(|cell| cell.global_index);
+    let edges = edges
+        .into_iter()
+        .map(|(i0, i1, face)| (new_index[i0], new_index[i1], face))
+        .collect();
+    Grid::from_cells_and_faces(cells, edges)
+        .with_num_processors(decomposition.total())
+        .expect("Decomposition assigned a cell to a nonexistent processor")
+}
+
+fn get_bounding_box(centers: &[Vector3D]) -> ([f64; 3], [f64; 3]) {
+    let mut min = [f64::INFINITY; 3];
+    let mut max = [f64::NEG_INFINITY; 3];
+    for center in centers.iter() {
+        for (axis, value) in [*center.x, *center.y, *center.z].iter().enumerate() {
+            min[axis] = min[axis].min(*value);
+            max[axis] = max[axis].max(*value);
+        }
+    }
+    (min, max)
+}
+
+/// Cells on a regular lattice with unit spacing, each connected to its
+/// (up to) six direct neighbours. Every pair is returned once. In periodic
+/// grids, the last cell along each axis with more than two cells is also
+/// connected to the first one.
+fn cartesian(size: [usize; 3], periodic: bool) -> (Vec<Vector3D>, Vec<(usize, usize)>) {
+    let [nx, ny, nz] = size;
+    let index = |x: usize, y: usize, z: usize| x + nx * (y + ny * z);
+    let next = |i: usize, n: usize| {
+        if i + 1 < n {
+            Some(i + 1)
+        } else if periodic && n > 2 {
+            Some(0)
+        } else {
+            None
+        }
+    };
+    let mut centers = vec![];
+    let mut pairs = vec![];
+    for z in 0..nz {
+        for y in 0..ny {
+            for x in 0..nx {
+                centers.push(Vector3D::new(x as f64, y as f64, z as f64));
+                if let Some(x1) = next(x, nx) {
+                    pairs.push((index(x, y, z), index(x1, y, z)));
+                }
+                if let Some(y1) = next(y, ny) {
+                    pairs.push((index(x, y, z), index(x, y1, z)));
+                }
+                if let Some(z1) = next(z, nz) {
+                    pairs.push((index(x, y, z), index(x, y, z1)));
+                }
+            }
+        }
+    }
+    (centers, pairs)
+}
+
+fn jitter(centers: &mut [Vector3D], amount: f64, seed: u64, num_dimensions: usize) {
+    let mut rng = StdRng::seed_from_u64(seed);
+    for center in centers.iter_mut() {
+        let mut offset = || {
+            if amount > 0.0 {
+                rng.gen_range(-amount..amount)
+            } else {
+                0.0
+            }
+        };
+        let (dx, dy) = (offset(), offset());
+        let dz = if num_dimensions == 3 { offset() } else { 0.0 };
+        *center = center.add(&Vector3D::new(dx, dy, dz));
+    }
+}
+
+/// Spheres of unit diameter in hexagonal close packing (ABAB stacking).
+/// Each interior cell has twelve neighbours.
+fn hexagonal_close_packing(size: [usize; 3]) -> (Vec<Vector3D>, Vec<(usize, usize)>) {
+    let [nx, ny, nz] = size;
+    let mut centers = vec![];
+    for k in 0..nz {
+        for j in 0..ny {
+            for i in 0..nx {
+                let x = i as f64 + 0.5 * ((j + k) % 2) as f64;
+                let y = 3.0f64.sqrt() * (j as f64 + (k % 2) as f64 / 3.0) / 2.0;
+                let z = 6.0f64.sqrt() / 3.0 * k as f64;
+                centers.push(Vector3D::new(x, y, z));
+            }
+        }
+    }
+    let pairs = get_pairs_at_unit_distance(&centers);
+    (centers, pairs)
+}
+
+/// Cells on a triangular lattice with unit spacing, each connected to its
+/// six nearest neighbours.
+fn hexagonal_2d(size: [usize; 3]) -> (Vec<Vector3D>, Vec<(usize, usize)>) {
+    let [nx, ny, _] = size;
+    let mut centers = vec![];
+    for j in 0..ny {
+        for i in 0..nx {
+            let x = i as f64 + 0.5 * (j % 2) as f64;
+            let y = 3.0f64.sqrt() / 2.0 * j as f64;
+            centers.push(Vector3D::new(x, y, 0.0));
+        }
+    }
+    let pairs = get_pairs_at_unit_distance(&centers);
+    (centers, pairs)
+}
+
+fn get_pairs_at_unit_distance(centers: &[Vector3D]) -> Vec<(usize, usize)> {
+    let tolerance = 1e-6;
+    let bin = |center: &Vector3D| {
+        (
+            center.x.floor() as i64,
+            center.y.floor() as i64,
+            center.z.floor() as i64,
+        )
+    };
+    let mut bins: HashMap<(i64, i64, i64), Vec<usize>> = HashMap::new();
+    for (index, center) in centers.iter().enumerate() {
+        bins.entry(bin(center)).or_default().push(index);
+    }
+    let mut pairs = vec![];
+    for (index, center) in centers.iter().enumerate() {
+        let (bx, by, bz) = bin(center);
+        for dx in -1..=1 {
+            for dy in -1..=1 {
+                for dz in -1..=1 {
+                    for other in bins.get(&(bx + dx, by + dy, bz + dz)).into_iter().flatten() {
+                        if *other > index
+                            && (centers[*other].sub(center).norm() - 1.0).abs() < tolerance
+                        {
+                            pairs.push((index, *other));
+                        }
+                    }
+                }
+            }
+        }
+    }
+    pairs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_neighbour_counts(grid: &Grid) -> Vec<usize> {
+        let mut counts = vec![0; grid.iter().count()];
+        for (cell, _, _) in grid.iter_neighbours() {
+            counts[cell.global_index] += 1;
+        }
+        counts
+    }
+
+    #[test]
+    fn cartesian_neighbours() {
+        let (centers, pairs) = cartesian([3, 3, 3], false);
+        let grid = build_grid(centers, &pairs, &Decomposition::blocks([1, 1, 1]));
+        let counts = get_neighbour_counts(&grid);
+        assert_eq!(counts.iter().max(), Some(&6));
+        assert_eq!(counts.iter().min(), Some(&3));
+        assert_eq!(counts.iter().sum::<usize>(), 2 * 3 * 3 * 2 * 3);
+    }
+
+    #[test]
+    fn hexagonal_close_packing_neighbours() {
+        let (_, pairs) = hexagonal_close_packing([5, 5, 5]);
+        let mut counts = vec![0; 125];
+        for (i0, i1) in pairs {
+            counts[i0] += 1;
+            counts[i1] += 1;
+        }
+        assert_eq!(counts.iter().max(), Some(&12));
+    }
+
+    #[test]
+    fn automatic_decomposition() {
+        let decomposition = Decomposition::automatic(8, [1.0, 1.0, 1.0]);
+        assert_eq!(decomposition.num_processors, [2, 2, 2]);
+        let decomposition = Decomposition::automatic(12, [1.0, 1.0, 0.0]);
+        assert_eq!(decomposition.total(), 12);
+        assert_eq!(decomposition.num_processors[2], 1);
+        let spec = GridSpec {
+            size: [4, 4, 4],
+            num_processors: 8,
+            ..GridSpec::default()
+        };
+        assert!(spec.validate().is_ok());
+        let grid = generate_grid(&spec);
+        for processor_num in 0..8 {
+            let num_cells = grid
+                .iter()
+                .filter(|cell| cell.processor_num == processor_num)
+                .count();
+            assert_eq!(num_cells, 8);
+        }
+        let invalid = GridSpec {
+            decomposition: Some(Decomposition::blocks([0, 2, 2])),
+            ..spec.clone()
+        };
+        assert!(invalid.validate().is_err());
+        let invalid = GridSpec {
+            num_processors: 0,
+            ..spec
+        };
+        assert!(invalid.validate().is_err());
+    }
+}
@@ -1,4 +1,5 @@
 use std::collections::HashMap;
+use std::collections::HashSet;
 use std::iter::FromIterator;
 
 use generational_arena::Arena;
@@ -48,6 +49,10 @@ impl<N, E> Graph<N, E> {
         Box::new(self.arena.iter().map(|(_, node)| &node.data))
     }
 
+    pub fn iter_mut(&mut self) -> Box<dyn Iterator<Item = &mut N> + '_> {
+        Box::new(self.arena.iter_mut().map(|(_, node)| &mut node.data))
+    }
+
     pub fn iter_nodes(&self) -> Box<dyn Iterator<Item = &Node<N, E>> + '_> {
         Box::new(self.arena.iter().map(|(_, node)| node))
     }
@@ -74,10 +79,63 @@ impl<N, E> Graph<N, E> {
         self.arena.get_mut(index)
     }
 
+    pub fn add_edge(&mut self, from: Index, to: Index, data: E) {
+        self.arena[from].edges.push(Edge { index: to, data });
+    }
+
+    /// Whether some node can be reached from itself.
+    pub fn has_cycle(&self) -> bool {
+        let mut num_incoming: HashMap<Index, usize> = HashMap::new();
+        for node in self.iter_nodes() {
+            for edge in node.edges.iter() {
+                *num_incoming.entry(edge.index).or_insert(0) += 1;
+            }
+        }
+        let mut stack: Vec<Index> = self
+            .iter_nodes()
+            .filter(|node| !num_incoming.contains_key(&node.index))
+            .map(|node| node.index)
+            .collect();
+        let mut num_visited = 0;
+        while let Some(index) = stack.pop() {
+            num_visited += 1;
+            for edge in self.arena[index].edges.iter() {
+                let num = num_incoming.get_mut(&edge.index).unwrap();
+                *num -= 1;
+                if *num == 0 {
+                    stack.push(edge.index);
+                }
+            }
+        }
+        num_visited < self.len()
+    }
+
     pub fn len(&self) -> usize {
         self.arena.len()
     }
 
+    pub fn traverse_depth_first(&self, start: &N) -> Vec<&Node<N, E>>
+    where
+        N: PartialEq,
+    {
+        let mut visited = HashSet::new();
+        let mut nodes = vec![];
+        let mut stack: Vec<Index> = self
+            .iter_nodes()
+            .filter(|node| &node.data == start)
+            .map(|node| node.index)
+            .collect();
+        while let Some(index) = stack.pop() {
+            if !visited.insert(index) {
+                continue;
+            }
+            let node = &self.arena[index];
+            nodes.push(node);
+            stack.extend(node.edges.iter().rev().map(|edge| edge.index));
+        }
+        nodes
+    }
+
     fn extend(&mut self, mut graph: Graph<N, E>) {
         let mut old_index_to_new_index: HashMap<Index, Index> = HashMap::new();
         for (old_index, mut node) in graph.arena.drain() {
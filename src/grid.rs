@@ -1,33 +1,65 @@
+use std::collections::HashMap;
+use std::collections::HashSet;
+
+use anyhow::bail;
+use anyhow::Result;
+use generational_arena::Index;
 use ordered_float::OrderedFloat;
 
+use crate::boundary::CrossDependency;
+use crate::boundary::LaggingStrategy;
+use crate::boundary::PeriodicBox;
 use crate::cell::Cell;
 use crate::dependency::Dependency;
 use crate::direction::Direction;
 use crate::face::Face;
 use crate::graph::Graph;
+use crate::grid_geometry::GridGeometry;
 use crate::task::Task;
+use crate::vector_3d::Vector3D;
 
 pub type DependencyGraph<'a> = Graph<Task<'a>, Dependency>;
 
+/// Relative tolerance below which a neighbour does not count as further out
+const BOUNDARY_TOLERANCE: f64 = 1.0e-9;
+
 pub struct Grid {
     data: Graph<Cell, Face>,
+    geometry: Option<GridGeometry>,
+    num_processors: usize,
+    periodic_box: Option<PeriodicBox>,
 }
 
 impl Grid {
-    pub fn get_dependency_graph<'b>(&self, direction: &'b Direction) -> DependencyGraph<'_> {
+    /// Faces whose projected area along the direction is below the tolerance
+    /// in magnitude do not create a dependency. The lagged dependencies of
+    /// the direction are left out.
+    pub fn get_dependency_graph(
+        &self,
+        direction: &Direction,
+        face_tolerance: f64,
+        lagged: &HashSet<(usize, usize)>,
+    ) -> DependencyGraph<'_> {
         let mut tasks: Vec<Task> = self
             .data
             .iter()
             .map(|cell| Task {
                 cell,
                 direction: direction.clone(),
+                group: 0,
                 processor_num: cell.processor_num,
                 num_upwind: 0,
+                num_neighbours: 0,
+                num_upwind_faces: 0,
+                upwind_finished: OrderedFloat(0.0),
             })
             .collect();
         let mut dependency_data = vec![];
         for (upwind_cell, downwind_cell, face) in self.data.iter_edges() {
-            if Grid::is_downwind(face, direction) {
+            tasks[upwind_cell.global_index].num_neighbours += 1;
+            if Grid::is_downwind(face, direction, face_tolerance)
+                && !lagged.contains(&(upwind_cell.global_index, downwind_cell.global_index))
+            {
                 dependency_data.push((
                     upwind_cell.global_index,
                     downwind_cell.global_index,
@@ -36,39 +68,312 @@ impl Grid {
                 tasks[downwind_cell.global_index].num_upwind += 1;
             }
         }
+        for task in tasks.iter_mut() {
+            task.num_upwind_faces = task.num_upwind;
+        }
         Graph::from_nodes_and_edge_list(tasks, dependency_data)
     }
 
-    fn is_downwind(face: &Face, direction: &Direction) -> bool {
-        let scalar_product = face.normal.dot(&direction.vector);
-        scalar_product < OrderedFloat(0.0)
+    /// Dependencies in the direction, given as global indices of the upwind
+    /// and downwind cell, which use the values of the previous iteration.
+    /// Only grids in a periodic box lag dependencies.
+    pub fn get_lagged_dependencies(
+        &self,
+        direction: &Direction,
+        face_tolerance: f64,
+    ) -> HashSet<(usize, usize)> {
+        let periodic_box = match &self.periodic_box {
+            Some(periodic_box) => periodic_box,
+            None => return HashSet::new(),
+        };
+        let mut crossing = HashSet::new();
+        let mut kept = vec![];
+        for (upwind_cell, downwind_cell, face) in self.iter_neighbours() {
+            if !Grid::is_downwind(face, direction, face_tolerance) {
+                continue;
+            }
+            let dependency = (upwind_cell.global_index, downwind_cell.global_index);
+            if periodic_box.crosses_boundary(&upwind_cell.center, &downwind_cell.center) {
+                crossing.insert(dependency);
+            } else {
+                kept.push(dependency);
+            }
+        }
+        match periodic_box.lagging {
+            LaggingStrategy::Boundary => crossing,
+            LaggingStrategy::FeedbackEdges => get_feedback_edges(self.data.len(), &kept, crossing),
+        }
+    }
+
+    /// Adds dependencies between tasks of different directions to a graph
+    /// containing the tasks of all directions.
+    pub fn add_cross_dependencies(graph: &mut DependencyGraph, dependencies: &[CrossDependency]) {
+        let indices: HashMap<(usize, usize), Index> = graph
+            .iter_nodes()
+            .map(|node| {
+                (
+                    (node.data.direction.index, node.data.cell.global_index),
+                    node.index,
+                )
+            })
+            .collect();
+        for (upwind, downwind) in dependencies.iter() {
+            graph.add_edge(indices[upwind], indices[downwind], Dependency);
+            graph.get_mut(indices[downwind]).unwrap().data.num_upwind += 1;
+        }
+    }
+
+    /// Cells without a neighbour further out along the normal, which are
+    /// taken to touch the plane of the domain box with this outward normal.
+    pub fn get_boundary_cells(&self, outward_normal: &Vector3D) -> Vec<usize> {
+        let mut is_boundary = vec![true; self.data.len()];
+        for (cell, neighbour, _) in self.iter_neighbours() {
+            let difference = neighbour.center.sub(&cell.center);
+            if *difference.dot(outward_normal) > BOUNDARY_TOLERANCE * difference.norm() {
+                is_boundary[cell.global_index] = false;
+            }
+        }
+        (0..is_boundary.len())
+            .filter(|cell| is_boundary[*cell])
+            .collect()
+    }
+
+    fn is_downwind(face: &Face, direction: &Direction, face_tolerance: f64) -> bool {
+        face.is_downwind(&direction.vector, face_tolerance)
     }
 
     pub fn from_cell_pairs(cells: Vec<Cell>, pairs: &[(usize, usize)]) -> Grid {
-        let edge_list: Vec<(usize, usize, Face)> = pairs
+        let edge_list = pairs.iter().map(|(i0, i1)| (*i0, *i1, None)).collect();
+        Grid::from_cells_and_faces(cells, edge_list)
+    }
+
+    /// Like `from_cell_pairs` but with known face geometry. Edges without a face
+    /// fall back to the difference of the cell centers.
+    pub fn from_cells_and_faces(
+        cells: Vec<Cell>,
+        edge_list: Vec<(usize, usize, Option<Face>)>,
+    ) -> Grid {
+        let num_processors = cells
             .iter()
-            .map(|(i0, i1)| (*i0, *i1, Grid::face_between(&cells[*i0], &cells[*i1])))
+            .map(|cell| cell.processor_num + 1)
+            .max()
+            .unwrap_or(0);
+        Grid {
+            data: Grid::build_graph(cells, edge_list, None),
+            geometry: None,
+            num_processors,
+            periodic_box: None,
+        }
+    }
+
+    fn build_graph(
+        cells: Vec<Cell>,
+        edge_list: Vec<(usize, usize, Option<Face>)>,
+        periodic_box: Option<&PeriodicBox>,
+    ) -> Graph<Cell, Face> {
+        let edge_list = edge_list
+            .into_iter()
+            .map(|(i0, i1, face)| {
+                let face = face
+                    .unwrap_or_else(|| Grid::face_between(&cells[i0], &cells[i1], periodic_box));
+                (i0, i1, face)
+            })
             .collect();
+        Graph::from_nodes_and_edge_list(cells, edge_list)
+    }
+
+    /// Places the grid in a periodic box. Faces without known geometry get
+    /// the normal towards the closest periodic image of the neighbour.
+    pub fn with_periodic_box(self, periodic_box: PeriodicBox) -> Grid {
+        let cells: Vec<Cell> = self.iter().cloned().collect();
+        let edge_list = self
+            .iter_neighbours()
+            .map(|(cell_0, cell_1, face)| {
+                let face = face.area.map(|_| face.clone());
+                (cell_0.global_index, cell_1.global_index, face)
+            })
+            .collect();
+        Grid {
+            data: Grid::build_graph(cells, edge_list, Some(&periodic_box)),
+            periodic_box: Some(periodic_box),
+            ..self
+        }
+    }
+
+    pub fn periodic_box(&self) -> Option<&PeriodicBox> {
+        self.periodic_box.as_ref()
+    }
+
+    /// Sets the number of processors explicitly, which allows processors
+    /// that own no cells, including ones beyond the largest processor number.
+    pub fn with_num_processors(self, num_processors: usize) -> Result<Grid> {
+        if num_processors < self.num_processors {
+            bail!(
+                "The grid has cells on {} processors, but the number of processors was set to {}",
+                self.num_processors,
+                num_processors
+            );
+        }
+        Ok(Grid {
+            num_processors,
+            ..self
+        })
+    }
+
+    pub fn with_geometry(self, geometry: GridGeometry) -> Grid {
         Grid {
-            data: Graph::from_nodes_and_edge_list(cells, edge_list),
+            geometry: Some(geometry),
+            ..self
         }
     }
 
-    fn face_between(cell_0: &Cell, cell_1: &Cell) -> Face {
+    pub fn geometry(&self) -> Option<&GridGeometry> {
+        self.geometry.as_ref()
+    }
+
+    fn face_between(cell_0: &Cell, cell_1: &Cell, periodic_box: Option<&PeriodicBox>) -> Face {
+        let difference = cell_0.center.sub(&cell_1.center);
+        let difference = match periodic_box {
+            Some(periodic_box) => periodic_box.wrap(&difference),
+            None => difference,
+        };
         Face {
-            normal: (cell_0.center.sub(&cell_1.center)),
+            normal: difference.scale(1.0 / difference.norm()),
+            area: None,
         }
     }
 
     pub fn iter(&self) -> Box<dyn Iterator<Item = &Cell> + '_> {
         self.data.iter()
     }
+
+    pub fn num_processors(&self) -> usize {
+        self.num_processors
+    }
+
+    pub fn get_num_cells_per_processor(&self) -> Vec<usize> {
+        let mut num_cells = vec![0; self.num_processors];
+        for cell in self.iter() {
+            num_cells[cell.processor_num] += 1;
+        }
+        num_cells
+    }
+
+    pub fn get_empty_processors(&self) -> Vec<usize> {
+        self.get_num_cells_per_processor()
+            .into_iter()
+            .enumerate()
+            .filter(|(_, num_cells)| *num_cells == 0)
+            .map(|(processor_num, _)| processor_num)
+            .collect()
+    }
+
+    /// Moves each cell to the processor returned by the function. Local indices
+    /// are renumbered such that cells keep their relative order within each
+    /// new processor.
+    pub fn reassign_processors(&self, get_new_processor: impl Fn(&Cell) -> usize) -> Grid {
+        let mut cells: Vec<Cell> = self
+            .iter()
+            .map(|cell| Cell {
+                processor_num: get_new_processor(cell),
+                ..cell.clone()
+            })
+            .collect();
+        let mut order: Vec<usize> = (0..cells.len()).collect();
+        order.sort_by_key(|index| (cells[*index].processor_num, *index));
+        let mut new_index = vec![0; cells.len()];
+        let mut num_cells_per_processor: HashMap<usize, usize> = HashMap::new();
+        for (global_index, old_index) in order.iter().enumerate() {
+            let cell = &mut cells[*old_index];
+            let num_cells = num_cells_per_processor
+                .entry(cell.processor_num)
+                .or_insert(0);
+            cell.local_index = *num_cells;
+            *num_cells += 1;
+            cell.global_index = global_index;
+            new_index[*old_index] = global_index;
+        }
+        cells.sort_by_key(|cell| cell.global_index);
+        let edges = self
+            .iter_neighbours()
+            .map(|(cell_0, cell_1, face)| {
+                (
+                    new_index[cell_0.global_index],
+                    new_index[cell_1.global_index],
+                    Some(face.clone()),
+                )
+            })
+            .collect();
+        let grid = Grid {
+            periodic_box: self.periodic_box.clone(),
+            ..Grid::from_cells_and_faces(cells, edges)
+        };
+        match &self.geometry {
+            Some(geometry) => {
+                let mut shapes = geometry.shapes.clone();
+                for (old_index, shape) in geometry.shapes.iter().enumerate() {
+                    shapes[new_index[old_index]] = shape.clone();
+                }
+                grid.with_geometry(GridGeometry {
+                    points: geometry.points.clone(),
+                    shapes,
+                })
+            }
+            None => grid,
+        }
+    }
+
+    pub fn iter_neighbours(&self) -> Box<dyn Iterator<Item = (&Cell, &Cell, &Face)> + '_> {
+        self.data.iter_edges()
+    }
+}
+
+/// The lagged edges which point backwards in a topological order of the
+/// graph of the kept edges. Lagging only them keeps the graph acyclic. If
+/// the kept edges contain a cycle themselves, all lagged edges are returned.
+fn get_feedback_edges(
+    num_cells: usize,
+    kept: &[(usize, usize)],
+    lagged: HashSet<(usize, usize)>,
+) -> HashSet<(usize, usize)> {
+    let mut downwind = vec![vec![]; num_cells];
+    let mut num_upwind = vec![0; num_cells];
+    for (upwind_cell, downwind_cell) in kept.iter() {
+        downwind[*upwind_cell].push(*downwind_cell);
+        num_upwind[*downwind_cell] += 1;
+    }
+    let mut order: Vec<usize> = (0..num_cells)
+        .filter(|cell| num_upwind[*cell] == 0)
+        .collect();
+    let mut index = 0;
+    while index < order.len() {
+        for cell in downwind[order[index]].iter() {
+            num_upwind[*cell] -= 1;
+            if num_upwind[*cell] == 0 {
+                order.push(*cell);
+            }
+        }
+        index += 1;
+    }
+    if order.len() < num_cells {
+        return lagged;
+    }
+    let mut position = vec![0; num_cells];
+    for (index, cell) in order.into_iter().enumerate() {
+        position[cell] = index;
+    }
+    lagged
+        .into_iter()
+        .filter(|(upwind_cell, downwind_cell)| position[*upwind_cell] > position[*downwind_cell])
+        .collect()
 }
 
 #[cfg(test)]
 mod tests {
     use itertools::Itertools;
 
+    use std::collections::BTreeMap;
+
     use super::*;
     use crate::vector_3d::Vector3D;
     #[test]
@@ -83,27 +388,83 @@ mod tests {
                 local_index: 0,
                 center: Vector3D::new(0., 0., 0.),
                 processor_num: 0,
+                weights: BTreeMap::new(),
             },
             Cell {
                 global_index: 1,
                 local_index: 0,
                 center: Vector3D::new(1., 0., 0.),
                 processor_num: 0,
+                weights: BTreeMap::new(),
             },
         ];
         let first_cell = cells[0].clone();
         let grid = Grid::from_cell_pairs(cells, &[(0, 1)]);
-        let graph = grid.get_dependency_graph(&direction);
+        let graph = grid.get_dependency_graph(&direction, 0.0, &HashSet::new());
         let nodes = graph.traverse_depth_first(&Task {
             cell: &first_cell,
             direction,
+            group: 0,
             processor_num: 0,
             num_upwind: 0,
+            num_neighbours: 1,
+            num_upwind_faces: 0,
+            upwind_finished: OrderedFloat(0.0),
         });
         let labels: Vec<Task> = nodes.iter().map(|node| node.data.clone()).collect();
         assert_tasks_equal(&labels, &[(0, 0), (1, 0)]);
     }
 
+    #[test]
+    fn small_grazing_faces_are_ignored() {
+        let direction = Direction {
+            index: 0,
+            vector: Vector3D::new(1.0, 0.0, 0.0),
+        };
+        let get_num_dependencies = |area: f64, tolerance: f64| {
+            let cells: Vec<Cell> = (0..2)
+                .map(|i| Cell {
+                    global_index: i,
+                    local_index: i,
+                    center: Vector3D::new(i as f64, 0., 0.),
+                    processor_num: 0,
+                    weights: BTreeMap::new(),
+                })
+                .collect();
+            let outward = Vector3D::new(1e-3, 1.0, 0.0);
+            let face = Face::from_outward_normal(&outward, area);
+            let grid = Grid::from_cells_and_faces(cells, vec![(0, 1, Some(face))]);
+            num_dependencies(&grid.get_dependency_graph(&direction, tolerance, &HashSet::new()))
+        };
+        assert_eq!(get_num_dependencies(1e-2, 0.0), 1);
+        assert_eq!(get_num_dependencies(1e-2, 1e-3), 0);
+        // A large face at the same angle still lets radiation through
+        assert_eq!(get_num_dependencies(100.0, 1e-3), 1);
+    }
+
+    #[test]
+    fn explicit_number_of_processors() {
+        let cells: Vec<Cell> = (0..2)
+            .map(|i| Cell {
+                global_index: i,
+                local_index: 0,
+                center: Vector3D::new(i as f64, 0., 0.),
+                processor_num: 2 * i,
+                weights: BTreeMap::new(),
+            })
+            .collect();
+        let grid = Grid::from_cell_pairs(cells, &[(0, 1), (1, 0)]);
+        assert_eq!(grid.num_processors(), 3);
+        assert_eq!(grid.get_empty_processors(), vec![1]);
+        let grid = grid.with_num_processors(4).unwrap();
+        assert_eq!(grid.get_empty_processors(), vec![1, 3]);
+        assert!(grid.with_num_processors(2).is_err());
+    }
+
+    fn num_dependencies(graph: &DependencyGraph) -> usize {
+        graph.iter_edges().count()
+    }
+
     fn assert_tasks_equal(tasks: &[Task], indices: &[(usize, usize)]) {
         for task_info in tasks.iter().zip_longest(indices.iter()) {
             match task_info {
@@ -112,7 +473,7 @@ mod tests {
                     assert_eq!(task.direction.index, *dir_index);
                 }
                 _ => {
-                    assert!(false);
+                    panic!("Mismatch in number of tasks");
                 }
             };
         }
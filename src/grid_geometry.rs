@@ -0,0 +1,149 @@
+use anyhow::anyhow;
+use anyhow::Result;
+
+use crate::face::Face;
+use crate::vector_3d::Vector3D;
+
+pub const VTK_VERTEX: u8 = 1;
+pub const VTK_TRIANGLE: u8 = 5;
+pub const VTK_POLYGON: u8 = 7;
+pub const VTK_PIXEL: u8 = 8;
+pub const VTK_QUAD: u8 = 9;
+pub const VTK_TETRA: u8 = 10;
+pub const VTK_VOXEL: u8 = 11;
+pub const VTK_HEXAHEDRON: u8 = 12;
+pub const VTK_WEDGE: u8 = 13;
+pub const VTK_PYRAMID: u8 = 14;
+pub const VTK_POLYHEDRON: u8 = 42;
+
+/// The original cell geometry of a grid that was read from a mesh file.
+/// Shapes are stored in the same order as the cells of the grid (by global index).
+#[derive(Clone, Debug)]
+pub struct GridGeometry {
+    pub points: Vec<Vector3D>,
+    pub shapes: Vec<CellShape>,
+}
+
+#[derive(Clone, Debug)]
+pub struct CellShape {
+    pub cell_type: u8,
+    pub connectivity: Vec<usize>,
+    /// Explicit face list, only used for polyhedra.
+    pub faces: Option<Vec<Vec<usize>>>,
+}
+
+impl CellShape {
+    /// Returns the faces of this cell as lists of point indices. For
+    /// two-dimensional cells, the faces are the edges of the cell.
+    pub fn get_faces(&self) -> Result<Vec<Vec<usize>>> {
+        let c = &self.connectivity;
+        let local_faces: &[&[usize]] = match self.cell_type {
+            VTK_TETRA => &[&[0, 1, 3], &[1, 2, 3], &[2, 0, 3], &[0, 2, 1]],
+            VTK_VOXEL => &[
+                &[0, 2, 6, 4],
+                &[1, 3, 7, 5],
+                &[0, 1, 5, 4],
+                &[2, 3, 7, 6],
+                &[0, 1, 3, 2],
+                &[4, 5, 7, 6],
+            ],
+            VTK_HEXAHEDRON => &[
+                &[0, 4, 7, 3],
+                &[1, 2, 6, 5],
+                &[0, 1, 5, 4],
+                &[3, 7, 6, 2],
+                &[0, 3, 2, 1],
+                &[4, 5, 6, 7],
+            ],
+            VTK_WEDGE => &[
+                &[0, 1, 2],
+                &[3, 5, 4],
+                &[0, 3, 4, 1],
+                &[1, 4, 5, 2],
+                &[2, 5, 3, 0],
+            ],
+            VTK_PYRAMID => &[
+                &[0, 3, 2, 1],
+                &[0, 1, 4],
+                &[1, 2, 4],
+                &[2, 3, 4],
+                &[3, 0, 4],
+            ],
+            VTK_PIXEL => &[&[0, 1], &[1, 3], &[3, 2], &[2, 0]],
+            VTK_TRIANGLE | VTK_QUAD | VTK_POLYGON => {
+                return Ok((0..c.len())
+                    .map(|i| vec![c[i], c[(i + 1) % c.len()]])
+                    .collect());
+            }
+            VTK_POLYHEDRON => {
+                return self
+                    .faces
+                    .clone()
+                    .ok_or_else(|| anyhow!("Polyhedron cell without face list"));
+            }
+            VTK_VERTEX => {
+                return Err(anyhow!(
+                    "Vertex cells carry no neighbour information, use a .dat grid file instead"
+                ))
+            }
+            cell_type => return Err(anyhow!("Unsupported VTK cell type: {}", cell_type)),
+        };
+        local_faces
+            .iter()
+            .map(|face| {
+                face.iter()
+                    .map(|i| {
+                        c.get(*i).copied().ok_or_else(|| {
+                            anyhow!("Too few points for cell type {}", self.cell_type)
+                        })
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    pub fn get_center(&self, points: &[Vector3D]) -> Vector3D {
+        let mut center = Vector3D::new(0.0, 0.0, 0.0);
+        for point in self.connectivity.iter() {
+            center += &points[*point];
+        }
+        center /= self.connectivity.len() as f64;
+        center
+    }
+}
+
+/// Computes the face between two cells from the points of the shared face,
+/// oriented as seen from the first cell. For two-dimensional cells the face
+/// is an edge and its length is used as the area.
+pub fn get_face_between(
+    points: &[Vector3D],
+    face: &[usize],
+    center_0: &Vector3D,
+    center_1: &Vector3D,
+) -> Face {
+    let difference = center_1.sub(center_0);
+    let (normal, area) = if face.len() == 2 {
+        let edge = points[face[1]].sub(&points[face[0]]);
+        let length = edge.norm();
+        let along_edge = edge.scale(*difference.dot(&edge) / (length * length));
+        (difference.sub(&along_edge), length)
+    } else {
+        // Newell's method: the length of the resulting vector is twice the area
+        let mut normal = Vector3D::new(0.0, 0.0, 0.0);
+        for (i, point) in face.iter().enumerate() {
+            let next = face[(i + 1) % face.len()];
+            normal += &points[*point].cross(&points[next]);
+        }
+        let area = normal.norm() / 2.0;
+        if *normal.dot(&difference) < 0.0 {
+            (normal.scale(-1.0), area)
+        } else {
+            (normal, area)
+        }
+    };
+    if normal.norm() == 0.0 {
+        Face::from_outward_normal(&difference, 0.0)
+    } else {
+        Face::from_outward_normal(&normal, area)
+    }
+}
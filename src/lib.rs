@@ -1,20 +1,42 @@
+mod boundary;
+pub mod calibrate;
 mod cell;
+pub mod coarsen;
 pub mod command_line_args;
 mod config;
+mod contention;
+pub mod cost;
+mod delaunay;
 mod dependency;
 mod direction;
 mod edge;
+mod expression;
 mod face;
+pub mod generate;
 mod graph;
-mod grid;
+pub mod grid;
+mod grid_geometry;
 mod node;
+mod noise;
 pub mod param_file;
+pub mod placement;
 mod processor;
 mod processor_priority;
 mod processors;
+mod protocol;
+pub mod replay;
 pub mod run;
 mod run_data;
+pub mod schedule;
+mod speed;
+pub mod statistics;
 mod sweep;
 mod task;
 mod task_priority;
+pub mod topology;
+pub mod trace;
+pub mod transport;
+pub mod validate;
 mod vector_3d;
+pub mod voronoi;
+mod vtu;
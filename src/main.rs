@@ -1,23 +1,358 @@
 use std::error::Error;
 
+use anyhow::anyhow;
 use clap::Clap;
+use voronoi_swim::calibrate::calibrate;
+use voronoi_swim::calibrate::get_default_parameters;
+use voronoi_swim::calibrate::load_data_points;
+use voronoi_swim::calibrate::read_measurements;
+use voronoi_swim::calibrate::CalibrationSettings;
+use voronoi_swim::coarsen::coarsen;
+use voronoi_swim::coarsen::get_strong_scaling_counts;
+use voronoi_swim::command_line_args::CalibrateArgs;
+use voronoi_swim::command_line_args::Command;
 use voronoi_swim::command_line_args::CommandLineArgs;
-use voronoi_swim::run::simulate_grid;
+use voronoi_swim::command_line_args::ExportScheduleArgs;
+use voronoi_swim::command_line_args::GenerateArgs;
+use voronoi_swim::command_line_args::MeasureSolveArgs;
+use voronoi_swim::command_line_args::OptimizePlacementArgs;
+use voronoi_swim::command_line_args::ReplayArgs;
+use voronoi_swim::command_line_args::SimulateArgs;
+use voronoi_swim::generate::generate_grid;
+use voronoi_swim::generate::Decomposition;
+use voronoi_swim::generate::GridSpec;
+use voronoi_swim::grid::Grid;
+use voronoi_swim::param_file::ParamFile;
+use voronoi_swim::placement::optimize_rank_placement;
+use voronoi_swim::placement::write_rankfile;
+use voronoi_swim::replay::replay;
+use voronoi_swim::run::convert_grids_to_vtu;
+use voronoi_swim::run::read_grid;
+use voronoi_swim::run::read_grid_with_params;
+use voronoi_swim::run::simulate_grid_with_trace;
+use voronoi_swim::run::simulate_grids;
+use voronoi_swim::run::simulate_repetitions;
+use voronoi_swim::run::write_grid;
+use voronoi_swim::schedule::Schedule;
+use voronoi_swim::statistics::Summary;
+use voronoi_swim::topology::write_placement_file;
+use voronoi_swim::transport::measure_solve_time;
+use voronoi_swim::voronoi::VoronoiSpec;
 
 fn main() -> Result<(), Box<dyn Error>> {
     let args = CommandLineArgs::parse();
-    let run_data_list = simulate_grid(&args.param_file, &args.grid_files)?;
+    match args.command {
+        Command::Simulate(args) => simulate(&args),
+        Command::Generate(args) => generate(&args),
+        Command::OptimizePlacement(args) => optimize_placement(&args),
+        Command::Calibrate(args) => calibrate_param_file(&args),
+        Command::MeasureSolve(args) => measure_solve(&args),
+        Command::ExportSchedule(args) => export_schedule(&args),
+        Command::Replay(args) => replay_schedule(&args),
+    }
+}
+
+fn simulate(args: &SimulateArgs) -> Result<(), Box<dyn Error>> {
+    if let Some(output_folder) = &args.vtu_output {
+        convert_grids_to_vtu(&args.grid_files, output_folder)?;
+    }
+    let param_file = ParamFile::read(&args.param_file)?;
+    let mut grids = vec![];
+    for file in args.grid_files.iter() {
+        let grid = read_grid_with_params(file, &param_file, args.num_processors)?;
+        let mut counts = args.coarsen.clone();
+        if args.strong_scaling {
+            counts.extend(get_strong_scaling_counts(grid.num_processors()));
+        }
+        counts.sort_unstable();
+        counts.dedup();
+        for num_processors in counts {
+            grids.push(coarsen(&grid, num_processors, args.coarsening_strategy)?);
+        }
+        grids.push(grid);
+    }
+    for grid in grids.iter() {
+        let empty_processors = grid.get_empty_processors();
+        if !empty_processors.is_empty() {
+            eprintln!(
+                "Warning: {} of {} processors own no cells: {:?}",
+                empty_processors.len(),
+                grid.num_processors(),
+                empty_processors
+            );
+        }
+    }
+    if args.repetitions > 1 {
+        return simulate_with_repetitions(&param_file, &grids, args.repetitions);
+    }
+    let run_data_list = simulate_grids(&param_file, &grids)?;
+    // Simulate again without the stragglers to see how much they delay the sweep
+    let undisturbed_run_data_list = match &param_file.speeds {
+        Some(speeds) if speeds.has_stragglers() => {
+            let mut undisturbed_param_file = param_file.clone();
+            undisturbed_param_file.speeds = Some(speeds.without_stragglers());
+            Some(simulate_grids(&undisturbed_param_file, &grids)?)
+        }
+        _ => None,
+    };
     let reference = &run_data_list[0];
-    for run_data in run_data_list.iter() {
-        println!(
+    for (i, run_data) in run_data_list.iter().enumerate() {
+        let label = match param_file.cores_per_processor {
+            1 => run_data.num_processors.to_string(),
+            cores => format!("{}x{}", run_data.num_processors, cores),
+        };
+        let mut row = format!(
             "{:>4} {:.3} (speedup: {:>6.2}, efficiency {:>6.2}), comm: {:.3}, idle: {:.3}",
-            run_data.num_processors,
+            label,
             run_data.time,
             run_data.get_speedup(reference),
             run_data.get_efficiency(reference),
             run_data.time_spent_communicating / run_data.time,
             run_data.time_spent_waiting / run_data.time,
         );
+        if run_data.num_lagged_dependencies > 0 {
+            row.push_str(&format!(
+                ", lagged dependencies: {}",
+                run_data.num_lagged_dependencies
+            ));
+        }
+        if let Some(transport) = &run_data.transport {
+            row.push_str(&format!(
+                ", transport error: {:.1e}, dependency violations: {}",
+                transport.max_relative_error, transport.num_dependency_violations
+            ));
+        }
+        if let Some(undisturbed_run_data_list) = &undisturbed_run_data_list {
+            let undisturbed_time = undisturbed_run_data_list[i].time;
+            row.push_str(&format!(
+                ", straggler delay: {:.3} ({:+.1}%)",
+                run_data.time - undisturbed_time,
+                (run_data.time / undisturbed_time - 1.0) * 100.0
+            ));
+        }
+        println!("{}", row);
+    }
+    Ok(())
+}
+
+fn simulate_with_repetitions(
+    param_file: &ParamFile,
+    grids: &[Grid],
+    num_repetitions: usize,
+) -> Result<(), Box<dyn Error>> {
+    if param_file.noise.is_none() {
+        eprintln!("Warning: Without noise in the param file, all repetitions are identical");
+    }
+    let runs = simulate_repetitions(param_file, grids, num_repetitions)?;
+    let references = &runs[0];
+    for grid_runs in runs.iter() {
+        let times: Vec<f64> = grid_runs.iter().map(|run_data| run_data.time).collect();
+        let efficiencies: Vec<f64> = grid_runs
+            .iter()
+            .zip(references.iter())
+            .map(|(run_data, reference)| run_data.get_efficiency(reference))
+            .collect();
+        let time = Summary::new(&times);
+        let efficiency = Summary::new(&efficiencies);
+        println!(
+            "{:>4} {:.3} +- {:.3} (95% CI {:.3}-{:.3}), efficiency {:.2} +- {:.2} (95% CI {:.2}-{:.2})",
+            grid_runs[0].num_processors,
+            time.mean,
+            time.std_dev,
+            time.confidence_interval.0,
+            time.confidence_interval.1,
+            efficiency.mean,
+            efficiency.std_dev,
+            efficiency.confidence_interval.0,
+            efficiency.confidence_interval.1,
+        );
     }
     Ok(())
 }
+
+fn generate(args: &GenerateArgs) -> Result<(), Box<dyn Error>> {
+    let size = get_triple(&args.size, "size")?;
+    let decomposition = match &args.blocks {
+        Some(blocks) => Some(Decomposition::blocks(get_triple(blocks, "blocks")?)),
+        None => None,
+    };
+    let spec = GridSpec {
+        kind: args.kind,
+        size,
+        num_processors: args.num_processors,
+        decomposition,
+        jitter: args.jitter,
+        periodic: args.periodic,
+        voronoi: VoronoiSpec {
+            distribution: args.distribution,
+            lloyd_iterations: args.lloyd_iterations,
+            periodic: args.periodic,
+            num_clusters: args.num_clusters,
+            cluster_width: args.cluster_width,
+        },
+        seed: args.seed,
+    };
+    spec.validate()?;
+    if args
+        .output
+        .extension()
+        .and_then(|extension| extension.to_str())
+        == Some("vtu")
+    {
+        return Err(anyhow!(
+            "Generated grids can only be written as .dat files, since vtu files of generated grids carry no neighbour information"
+        )
+        .into());
+    }
+    let grid = generate_grid(&spec);
+    write_grid(&grid, &args.output)?;
+    Ok(())
+}
+
+fn optimize_placement(args: &OptimizePlacementArgs) -> Result<(), Box<dyn Error>> {
+    let param_file = ParamFile::read(&args.param_file)?;
+    let grid = read_grid_with_params(&args.grid_file, &param_file, args.num_processors)?;
+    let result = optimize_rank_placement(&param_file, &grid)?;
+    println!(
+        "hop-bytes: {:.4e} -> {:.4e}",
+        result.original_hop_bytes, result.optimized_hop_bytes
+    );
+    println!(
+        "time: {:.6} -> {:.6} (speedup {:.3})",
+        result.original_run.time,
+        result.optimized_run.time,
+        result.optimized_run.get_speedup(&result.original_run)
+    );
+    write_rankfile(&result.optimized_nodes, &args.host_prefix, &args.rankfile)?;
+    if let Some(path) = &args.placement_file {
+        write_placement_file(&result.optimized_nodes, path)?;
+    }
+    Ok(())
+}
+
+fn calibrate_param_file(args: &CalibrateArgs) -> Result<(), Box<dyn Error>> {
+    let param_file = ParamFile::read(&args.param_file)?;
+    let measurements = read_measurements(&args.measurements)?;
+    let (grids, data_points) =
+        load_data_points(&measurements, &param_file, args.coarsening_strategy)?;
+    let parameters = match args.parameters.is_empty() {
+        true => get_default_parameters(&param_file),
+        false => args.parameters.clone(),
+    };
+    let settings = CalibrationSettings {
+        parameters,
+        max_iterations: args.max_iterations,
+        repetitions: args.repetitions,
+    };
+    let calibration = calibrate(&param_file, &grids, &data_points, &settings)?;
+    println!(
+        "squared relative error: {:.4e} after {} iterations",
+        calibration.error, calibration.num_iterations
+    );
+    let fitted = serde_yaml::to_value(&calibration.param_file)?;
+    for name in settings.parameters.iter() {
+        println!(
+            "{}: {:?}",
+            name,
+            fitted[name.as_str()].as_f64().unwrap_or(0.0)
+        );
+    }
+    println!("grid num_processors batch_size measured simulated relative_error");
+    for ((measurement, data_point), simulated_time) in measurements
+        .iter()
+        .zip(data_points.iter())
+        .zip(calibration.simulated_times.iter())
+    {
+        println!(
+            "{:?} {} {} {:.4e} {:.4e} {:+.2}%",
+            measurement.grid_file,
+            measurement.num_processors,
+            data_point.batch_size,
+            data_point.measured_time,
+            simulated_time,
+            (simulated_time / data_point.measured_time - 1.0) * 100.0
+        );
+    }
+    if let Some(output) = &args.output {
+        std::fs::write(output, serde_yaml::to_string(&calibration.param_file)?)?;
+    }
+    Ok(())
+}
+
+fn measure_solve(args: &MeasureSolveArgs) -> Result<(), Box<dyn Error>> {
+    let grid = read_grid(&args.grid_file, None)?;
+    let measurement = measure_solve_time(
+        &grid,
+        args.num_directions,
+        args.face_tolerance,
+        args.repetitions,
+    );
+    let fragment = format!(
+        "# Measured on {} tasks of {:?}\nsolve_time_offset: {:e}\nsolve_time_per_task: {:e}\n",
+        measurement.num_tasks,
+        args.grid_file,
+        measurement.solve_time_offset,
+        measurement.solve_time_per_task
+    );
+    match &args.output {
+        Some(output) => std::fs::write(output, fragment)?,
+        None => print!("{}", fragment),
+    }
+    Ok(())
+}
+
+fn export_schedule(args: &ExportScheduleArgs) -> Result<(), Box<dyn Error>> {
+    let param_file = ParamFile::read(&args.param_file)?;
+    if param_file.get_num_task_groups() > 1 {
+        return Err(anyhow!("Schedules of independent groups are not supported").into());
+    }
+    let grid = read_grid_with_params(&args.grid_file, &param_file, args.num_processors)?;
+    let (run_data, trace) = simulate_grid_with_trace(&param_file, &grid)?;
+    let schedule = Schedule::from_trace(&grid, &trace);
+    schedule.write(&args.output, args.with_times)?;
+    let num_batches: usize = schedule
+        .processors
+        .iter()
+        .map(|batches| batches.len())
+        .sum();
+    println!(
+        "time: {:.6}, {} tasks in {} batches",
+        run_data.time,
+        schedule.num_tasks(),
+        num_batches
+    );
+    Ok(())
+}
+
+fn replay_schedule(args: &ReplayArgs) -> Result<(), Box<dyn Error>> {
+    let param_file = ParamFile::read(&args.param_file)?;
+    let grid = read_grid_with_params(&args.grid_file, &param_file, args.num_processors)?;
+    let schedule = Schedule::read(&args.schedule_file)?;
+    let replay = replay(&param_file, &grid, &schedule)?;
+    println!("predicted time: {:.6}", replay.time);
+    let discrepancies = replay.get_discrepancies();
+    if !discrepancies.is_empty() {
+        let differences: Vec<f64> = discrepancies.iter().map(|(_, d)| *d).collect();
+        let mean = differences.iter().sum::<f64>() / differences.len() as f64;
+        let max = differences.iter().fold(0.0, |max: f64, d| max.max(d.abs()));
+        println!(
+            "discrepancy (modeled - measured) over {} tasks: mean {:e}, max abs {:e}",
+            differences.len(),
+            mean,
+            max
+        );
+    }
+    if let Some(path) = &args.discrepancy_output {
+        replay.write_discrepancies(path)?;
+    }
+    Ok(())
+}
+
+/// Pads two-dimensional input with a trailing 1.
+fn get_triple(values: &[usize], name: &str) -> Result<[usize; 3], Box<dyn Error>> {
+    match values {
+        [x, y] => Ok([*x, *y, 1]),
+        [x, y, z] => Ok([*x, *y, *z]),
+        _ => Err(anyhow!("Expected two or three values for {}", name).into()),
+    }
+}
@@ -0,0 +1,174 @@
+use anyhow::bail;
+use anyhow::Result;
+use rand::rngs::StdRng;
+use rand::Rng;
+use rand::SeedableRng;
+use rand_distr::Normal;
+use rand_distr::Pareto;
+use serde::Deserialize;
+use serde::Serialize;
+
+/// Random perturbations of the solve, send and receive times.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct NoiseParams {
+    #[serde(default)]
+    pub seed: u64,
+    /// Standard deviation of the Gaussian jitter, relative to each duration
+    #[serde(default)]
+    pub jitter: f64,
+    /// Time between two interrupts of a processor by the operating system.
+    /// Zero means no interrupts.
+    #[serde(default)]
+    pub interrupt_period: f64,
+    #[serde(default)]
+    pub interrupt_duration: f64,
+    /// Probability that a duration is extended by a delay drawn from a
+    /// Pareto distribution
+    #[serde(default)]
+    pub delay_probability: f64,
+    /// Smallest possible delay
+    #[serde(default)]
+    pub delay_scale: f64,
+    /// Tail index of the delays. Smaller values give heavier tails.
+    #[serde(default = "default_delay_shape")]
+    pub delay_shape: f64,
+}
+
+/// The noise of a single processor, with its own random number generator.
+#[derive(Debug)]
+pub struct Noise {
+    params: NoiseParams,
+    rng: StdRng,
+    jitter: Option<Normal<f64>>,
+    delay: Option<Pareto<f64>>,
+    /// Time of the first interrupt
+    interrupt_phase: f64,
+}
+
+impl NoiseParams {
+    /// Checks that the distributions of the enabled kinds of noise are well
+    /// defined.
+    pub fn validate(&self) -> Result<()> {
+        if !(0.0..=1.0).contains(&self.delay_probability) {
+            bail!(
+                "delay_probability needs to be between 0 and 1, got {}",
+                self.delay_probability
+            );
+        }
+        if self.delay_probability > 0.0 && (self.delay_scale <= 0.0 || self.delay_shape <= 0.0) {
+            bail!(
+                "Delays need a positive delay_scale and delay_shape, got {} and {}",
+                self.delay_scale,
+                self.delay_shape
+            );
+        }
+        if self.jitter < 0.0 {
+            bail!("jitter needs to be non-negative, got {}", self.jitter);
+        }
+        if self.interrupt_period < 0.0 || self.interrupt_duration < 0.0 {
+            bail!("interrupt_period and interrupt_duration need to be non-negative");
+        }
+        Ok(())
+    }
+}
+
+impl Noise {
+    /// Expects validated params.
+    pub fn new(params: &NoiseParams, processor_num: usize) -> Self {
+        let mut rng = StdRng::seed_from_u64(
+            params
+                .seed
+                .wrapping_mul(0x9e37_79b9_7f4a_7c15)
+                .wrapping_add(processor_num as u64),
+        );
+        let interrupt_phase = match params.interrupt_period > 0.0 {
+            true => rng.gen_range(0.0..params.interrupt_period),
+            false => 0.0,
+        };
+        Noise {
+            jitter: (params.jitter > 0.0).then(|| Normal::new(1.0, params.jitter).unwrap()),
+            delay: (params.delay_probability > 0.0)
+                .then(|| Pareto::new(params.delay_scale, params.delay_shape).unwrap()),
+            params: params.clone(),
+            rng,
+            interrupt_phase,
+        }
+    }
+
+    /// The perturbed length of an activity of the given duration which
+    /// starts at `start`.
+    pub fn perturb(&mut self, start: f64, duration: f64) -> f64 {
+        let mut duration = match &self.jitter {
+            Some(jitter) => duration * self.rng.sample(jitter).max(0.0),
+            None => duration,
+        };
+        if let Some(delay) = &self.delay {
+            if self.rng.gen_bool(self.params.delay_probability) {
+                duration += self.rng.sample(delay);
+            }
+        }
+        if self.params.interrupt_period > 0.0 {
+            duration += self.params.interrupt_duration
+                * self.get_num_interrupts(start, start + duration) as f64;
+        }
+        duration
+    }
+
+    fn get_num_interrupts(&self, start: f64, end: f64) -> usize {
+        let get_num_before = |time: f64| {
+            ((time - self.interrupt_phase) / self.params.interrupt_period)
+                .ceil()
+                .max(0.0) as usize
+        };
+        get_num_before(end) - get_num_before(start)
+    }
+}
+
+fn default_delay_shape() -> f64 {
+    2.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interrupts_extend_durations() {
+        let params = NoiseParams {
+            interrupt_period: 1.0,
+            interrupt_duration: 0.1,
+            ..NoiseParams::default()
+        };
+        let mut noise = Noise::new(&params, 0);
+        assert_eq!(noise.perturb(0.0, 5.0), 5.5);
+        assert_eq!(noise.perturb(0.0, 0.0), 0.0);
+        let mut noise = Noise::new(
+            &NoiseParams {
+                jitter: 0.1,
+                ..NoiseParams::default()
+            },
+            0,
+        );
+        let durations: Vec<f64> = (0..100).map(|_| noise.perturb(0.0, 1.0)).collect();
+        assert!(durations.iter().any(|duration| *duration != 1.0));
+        assert!(durations.iter().all(|duration| *duration > 0.5));
+        for invalid in [
+            NoiseParams {
+                delay_probability: 0.5,
+                ..NoiseParams::default()
+            },
+            NoiseParams {
+                jitter: -0.1,
+                ..NoiseParams::default()
+            },
+            NoiseParams {
+                delay_probability: 1.5,
+                delay_scale: 1.0,
+                delay_shape: 2.0,
+                ..NoiseParams::default()
+            },
+        ] {
+            assert!(invalid.validate().is_err());
+        }
+    }
+}
@@ -1,11 +1,26 @@
+use std::collections::BTreeMap;
 use std::fs;
 use std::path::Path;
+use std::rc::Rc;
 
+use anyhow::bail;
 use anyhow::Context;
 use anyhow::Result;
 use serde::Deserialize;
 use serde::Serialize;
 
+use crate::boundary::BoundaryPlane;
+use crate::boundary::PeriodicBox;
+use crate::cost::CostModel;
+use crate::cost::CostModelParams;
+use crate::cost::ExpressionCostModel;
+use crate::cost::SOLVE_TIME_VARIABLES;
+use crate::expression::Expression;
+use crate::noise::NoiseParams;
+use crate::protocol::ProtocolParams;
+use crate::speed::SpeedParams;
+use crate::topology::TopologyParams;
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ParamFile {
     #[serde(default = "default_num_directions")]
@@ -18,18 +33,283 @@ pub struct ParamFile {
     pub recv_time_per_byte: f64,
     pub solve_time_offset: f64,
     pub solve_time_per_task: f64,
+    /// How the solve time of a task depends on its attributes
+    #[serde(default)]
+    pub cost_model: CostModelParams,
+    /// Named values which the expressions below can refer to
+    #[serde(default)]
+    pub constants: BTreeMap<String, f64>,
+    /// Solve time of a task as an expression of its attributes. Replaces
+    /// `solve_time_per_task` and the cost model. Negative results of this
+    /// and the following expressions count as zero, while infinite or NaN
+    /// results stop the simulation with an error.
+    #[serde(default)]
+    pub solve_time: Option<Expression>,
+    /// Send time of a message as an expression of its attributes. Replaces
+    /// `send_time_offset` and `send_time_per_byte`.
+    #[serde(default)]
+    pub send_time: Option<Expression>,
+    /// Receive time of a message as an expression of its attributes.
+    /// Replaces `recv_time_offset` and `recv_time_per_byte`.
+    #[serde(default)]
+    pub recv_time: Option<Expression>,
     #[serde(default = "default_message_size")]
     pub size_per_message: f64,
+    /// Faces whose area projected onto a direction is below this value
+    /// create no dependency in that direction. Faces without a known area
+    /// count as unit area.
+    #[serde(default)]
+    pub face_tolerance: f64,
+    /// Total number of processors, including ones that own no cells. Defaults
+    /// to the largest processor number in the grid plus one.
+    #[serde(default)]
+    pub num_processors: Option<usize>,
+    /// Places processors on the nodes of a network. Without it, messages
+    /// arrive immediately.
+    #[serde(default)]
+    pub topology: Option<TopologyParams>,
+    /// Number of cores of each processor which solve tasks from a shared
+    /// queue concurrently.
+    #[serde(default = "default_cores_per_processor")]
+    pub cores_per_processor: usize,
+    #[serde(default)]
+    pub communication_thread: CommunicationThread,
+    #[serde(default)]
+    pub communication_mode: CommunicationMode,
+    /// Time to post a non-blocking send or to complete the receives of a
+    /// batch in the asynchronous communication mode.
+    #[serde(default)]
+    pub post_time_offset: f64,
+    /// Switches to the rendezvous protocol above a message size and limits
+    /// the send buffers. Without it, all messages are sent eagerly.
+    #[serde(default)]
+    pub protocol: Option<ProtocolParams>,
+    /// Relative speeds of the processors and straggler ranks. Without it, all
+    /// processors run at the same speed.
+    #[serde(default)]
+    pub speeds: Option<SpeedParams>,
+    /// Random noise on the solve, send and receive times. Without it, the
+    /// simulation is deterministic.
+    #[serde(default)]
+    pub noise: Option<NoiseParams>,
+    /// Computes the intensity of each task during the sweep and compares
+    /// the result to a serial reference sweep.
+    #[serde(default)]
+    pub solve_transport: bool,
+    /// Planes of the domain box at which radiation is reflected. All other
+    /// boundaries are vacuum boundaries.
+    #[serde(default)]
+    pub reflective_boundaries: Vec<BoundaryPlane>,
+    /// Makes grids wrap around, such that neighbours across the boundary
+    /// see each other at their closest periodic image.
+    #[serde(default)]
+    pub periodic_box: Option<PeriodicBox>,
+    /// Number of energy or frequency groups of the radiation
+    #[serde(default = "default_num_groups")]
+    pub num_groups: usize,
+    #[serde(default)]
+    pub group_mode: GroupMode,
+    /// Additional solve time of a task for every group beyond the first in
+    /// the vectorized group mode
+    #[serde(default)]
+    pub solve_time_per_group: f64,
+}
+
+/// How the tasks of a sweep handle multiple groups.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum GroupMode {
+    /// One task solves all groups of its cell and direction, and its message
+    /// carries the data of all groups.
+    #[default]
+    Vectorized,
+    /// Every group is a separate task, which is solved and sent on its own.
+    Independent,
+}
+
+/// Which core sends and receives the messages of a processor.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum CommunicationThread {
+    /// Whichever worker core reaches the end of a batch.
+    #[default]
+    Worker,
+    /// One of the cores does nothing but communicate.
+    Dedicated,
 }
 
 impl ParamFile {
     pub fn read(file: &Path) -> Result<Self> {
         let data =
             fs::read_to_string(file).context(format!("While reading param file at {:?}", file))?;
-        serde_yaml::from_str(&data).context("Reading param file contents")
+        ParamFile::from_yaml(&data)
+    }
+
+    pub fn from_yaml(data: &str) -> Result<Self> {
+        let mut param_file: ParamFile =
+            serde_yaml::from_str(data).context("Reading param file contents")?;
+        if param_file.num_groups == 0 {
+            bail!("num_groups needs to be at least 1");
+        }
+        if param_file.batch_size == 0 {
+            bail!("batch_size needs to be at least 1");
+        }
+        if let Some(noise) = &param_file.noise {
+            noise.validate()?;
+        }
+        param_file.prepare_expressions()?;
+        Ok(param_file)
+    }
+
+    /// Substitutes the constants into the expressions and checks that they
+    /// only use the variables of their context.
+    fn prepare_expressions(&mut self) -> Result<()> {
+        if self.solve_time.is_some() && self.cost_model != CostModelParams::Constant {
+            bail!("Either specify a solve_time expression or a cost_model, not both");
+        }
+        let constants = &self.constants;
+        if let Some(expression) = &mut self.solve_time {
+            expression.substitute(constants);
+            expression.check_variables(
+                "solve_time",
+                |name| SOLVE_TIME_VARIABLES.contains(&name) || name.starts_with("weight_"),
+                &[SOLVE_TIME_VARIABLES, &["weight_<column>"]].concat(),
+            )?;
+            expression.check_constant_time("solve_time")?;
+        }
+        for (name, expression) in [
+            ("send_time", &mut self.send_time),
+            ("recv_time", &mut self.recv_time),
+        ] {
+            if let Some(expression) = expression {
+                expression.substitute(constants);
+                expression.check_variables(
+                    name,
+                    |name| MESSAGE_TIME_VARIABLES.contains(&name),
+                    MESSAGE_TIME_VARIABLES,
+                )?;
+                expression.check_constant_time(name)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The cost model given by the `solve_time` expression or, without one,
+    /// by `cost_model`.
+    pub fn get_cost_model(&self) -> Rc<dyn CostModel> {
+        match &self.solve_time {
+            Some(expression) => Rc::new(ExpressionCostModel::new(expression.clone())),
+            None => self.cost_model.build(self.solve_time_per_task),
+        }
+    }
+}
+
+/// Whether processors wait for their messages to be sent and received.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum CommunicationMode {
+    /// Sending and receiving take up processor time.
+    #[default]
+    Blocking,
+    /// Messages are posted non-blocking and transferred by a progress thread
+    /// in the background. Only the posting overhead takes up processor time.
+    Asynchronous,
+}
+
+/// Variables which the `send_time` and `recv_time` expressions can use.
+const MESSAGE_TIME_VARIABLES: &[&str] = &["bytes", "num_tasks", "hops", "sender", "receiver"];
+
+/// The attributes of a message which its send and receive times can depend
+/// on.
+pub struct MessageAttributes {
+    pub sender: usize,
+    pub receiver: usize,
+    pub num_tasks: usize,
+    pub num_bytes: f64,
+    /// Number of network links between sender and receiver
+    pub num_hops: usize,
+}
+
+impl MessageAttributes {
+    fn get_variable(&self, name: &str) -> Option<f64> {
+        match name {
+            "bytes" => Some(self.num_bytes),
+            "num_tasks" => Some(self.num_tasks as f64),
+            "hops" => Some(self.num_hops as f64),
+            "sender" => Some(self.sender as f64),
+            "receiver" => Some(self.receiver as f64),
+            _ => None,
+        }
     }
 }
 
+impl ParamFile {
+    /// Time the sender spends on transferring a message.
+    pub fn get_send_time(&self, message: &MessageAttributes) -> Result<f64> {
+        match &self.send_time {
+            Some(expression) => expression.evaluate_time(|name| message.get_variable(name)),
+            None => Ok(self.send_time_offset + message.num_bytes * self.send_time_per_byte),
+        }
+    }
+
+    /// Time the receiver spends on transferring a message.
+    pub fn get_receive_time(&self, message: &MessageAttributes) -> Result<f64> {
+        match &self.recv_time {
+            Some(expression) => expression.evaluate_time(|name| message.get_variable(name)),
+            None => Ok(self.recv_time_offset + message.num_bytes * self.recv_time_per_byte),
+        }
+    }
+
+    /// Number of tasks into which the groups split every pair of cell and
+    /// direction.
+    pub fn get_num_task_groups(&self) -> usize {
+        match self.group_mode {
+            GroupMode::Vectorized => 1,
+            GroupMode::Independent => self.num_groups,
+        }
+    }
+
+    /// Number of bytes a task adds to its message.
+    pub fn get_size_per_task(&self) -> f64 {
+        match self.group_mode {
+            GroupMode::Vectorized => self.num_groups as f64 * self.size_per_message,
+            GroupMode::Independent => self.size_per_message,
+        }
+    }
+
+    /// Time a task spends on its groups in addition to the time given by the
+    /// cost model.
+    pub fn get_group_solve_time(&self) -> f64 {
+        match self.group_mode {
+            GroupMode::Vectorized => (self.num_groups - 1) as f64 * self.solve_time_per_group,
+            GroupMode::Independent => 0.0,
+        }
+    }
+
+    /// Whether send and receive times are given per message by expressions.
+    pub fn has_message_time_expressions(&self) -> bool {
+        self.send_time.is_some() || self.recv_time.is_some()
+    }
+}
+
+/// A param file for tests with eight directions and fixed message and solve
+/// times, followed by the given lines.
+#[cfg(test)]
+pub(crate) fn get_test_param_file(extra: &str) -> ParamFile {
+    ParamFile::from_yaml(&format!(
+        "num_directions: 8
+send_time_offset: 1.0e-6
+send_time_per_byte: 0.0
+recv_time_offset: 1.0e-6
+recv_time_per_byte: 0.0
+solve_time_offset: 0.0
+solve_time_per_task: 1.0e-5
+{}",
+        extra
+    ))
+    .unwrap()
+}
+
 fn default_num_directions() -> usize {
     84
 }
@@ -38,6 +318,14 @@ fn default_batch_size() -> usize {
     usize::MAX
 }
 
+fn default_cores_per_processor() -> usize {
+    1
+}
+
+fn default_num_groups() -> usize {
+    1
+}
+
 fn default_message_size() -> f64 {
     32.0 * 2.0 + 64.0 * 5.0
 }
@@ -0,0 +1,309 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+use anyhow::anyhow;
+use anyhow::Context;
+use anyhow::Result;
+
+use crate::grid::Grid;
+use crate::param_file::ParamFile;
+use crate::run::simulate_grids;
+use crate::run_data::RunData;
+use crate::topology::Network;
+use crate::topology::Placement;
+use crate::topology::Topology;
+
+const MAX_NUM_SWAP_PASSES: usize = 20;
+
+pub struct PlacementOptimization {
+    pub original_nodes: Vec<usize>,
+    pub optimized_nodes: Vec<usize>,
+    pub original_hop_bytes: f64,
+    pub optimized_hop_bytes: f64,
+    pub original_run: RunData,
+    pub optimized_run: RunData,
+}
+
+/// Simulates the sweep with the placement of the param file, optimizes the
+/// placement for the resulting communication and simulates it again.
+pub fn optimize_rank_placement(
+    param_file: &ParamFile,
+    grid: &Grid,
+) -> Result<PlacementOptimization> {
+    let params = param_file
+        .topology
+        .as_ref()
+        .ok_or_else(|| anyhow!("Optimizing the placement requires a topology in the param file"))?;
+    let num_processors = grid.num_processors();
+    let original_nodes = Topology::new(params, num_processors)?.nodes().to_vec();
+    let original_run = simulate_grids(param_file, std::slice::from_ref(grid))?.remove(0);
+    let communication = Communication::new(&original_run.bytes_sent);
+    let num_nodes = num_processors.div_ceil(params.ranks_per_node);
+    let hops = HopTable::new(
+        &params.network,
+        num_nodes.max(original_nodes.iter().max().unwrap() + 1),
+    );
+    let optimized_nodes = optimize_placement(
+        &communication,
+        &hops,
+        params.ranks_per_node,
+        &original_nodes,
+    );
+    let mut optimized_param_file = param_file.clone();
+    optimized_param_file.topology.as_mut().unwrap().placement =
+        Placement::Nodes(optimized_nodes.clone());
+    let optimized_run =
+        simulate_grids(&optimized_param_file, std::slice::from_ref(grid))?.remove(0);
+    Ok(PlacementOptimization {
+        original_hop_bytes: communication.get_hop_bytes(&original_nodes, &hops),
+        optimized_hop_bytes: communication.get_hop_bytes(&optimized_nodes, &hops),
+        original_nodes,
+        optimized_nodes,
+        original_run,
+        optimized_run,
+    })
+}
+
+/// Writes an Open MPI rankfile which places each rank on the host named by
+/// the prefix and its node index.
+pub fn write_rankfile(nodes: &[usize], host_prefix: &str, path: &Path) -> Result<()> {
+    let mut num_ranks_on_node: BTreeMap<usize, usize> = BTreeMap::new();
+    let mut contents = String::new();
+    for (rank, node) in nodes.iter().enumerate() {
+        let slot = num_ranks_on_node.entry(*node).or_insert(0);
+        contents.push_str(&format!(
+            "rank {}={}{} slot={}\n",
+            rank, host_prefix, node, slot
+        ));
+        *slot += 1;
+    }
+    fs::write(path, contents).context(format!("While writing rankfile {:?}", path))
+}
+
+/// Symmetric communication volume between pairs of ranks.
+struct Communication {
+    neighbours: Vec<Vec<(usize, f64)>>,
+}
+
+impl Communication {
+    fn new(bytes_sent: &[BTreeMap<usize, f64>]) -> Self {
+        let mut volumes: Vec<BTreeMap<usize, f64>> = vec![BTreeMap::new(); bytes_sent.len()];
+        for (sender, sent) in bytes_sent.iter().enumerate() {
+            for (receiver, num_bytes) in sent.iter() {
+                *volumes[sender].entry(*receiver).or_insert(0.0) += num_bytes;
+                *volumes[*receiver].entry(sender).or_insert(0.0) += num_bytes;
+            }
+        }
+        Communication {
+            neighbours: volumes
+                .into_iter()
+                .map(|volume| volume.into_iter().collect())
+                .collect(),
+        }
+    }
+
+    fn num_ranks(&self) -> usize {
+        self.neighbours.len()
+    }
+
+    /// Sum of the bytes of all messages times the number of hops they travel.
+    fn get_hop_bytes(&self, nodes: &[usize], hops: &HopTable) -> f64 {
+        let mut hop_bytes = 0.0;
+        for (rank, neighbours) in self.neighbours.iter().enumerate() {
+            for (neighbour, num_bytes) in neighbours.iter() {
+                hop_bytes += num_bytes * hops.get(nodes[rank], nodes[*neighbour]) as f64;
+            }
+        }
+        // Every message is counted from both ends
+        hop_bytes / 2.0
+    }
+
+    /// Hop-bytes of the messages of the rank if it were on the node.
+    fn get_rank_hop_bytes(
+        &self,
+        rank: usize,
+        node: usize,
+        nodes: &[usize],
+        hops: &HopTable,
+    ) -> f64 {
+        self.neighbours[rank]
+            .iter()
+            .map(|(neighbour, num_bytes)| num_bytes * hops.get(node, nodes[*neighbour]) as f64)
+            .sum()
+    }
+}
+
+struct HopTable {
+    num_nodes: usize,
+    hops: Vec<usize>,
+}
+
+impl HopTable {
+    fn new(network: &Network, num_nodes: usize) -> Self {
+        let mut hops = vec![0; num_nodes * num_nodes];
+        for node_0 in 0..num_nodes {
+            for node_1 in 0..num_nodes {
+                hops[node_0 * num_nodes + node_1] = network.get_num_hops(node_0, node_1);
+            }
+        }
+        HopTable { num_nodes, hops }
+    }
+
+    fn get(&self, node_0: usize, node_1: usize) -> usize {
+        self.hops[node_0 * self.num_nodes + node_1]
+    }
+}
+
+/// Refines both a greedy placement and the initial placement by swaps and
+/// returns the better one.
+fn optimize_placement(
+    communication: &Communication,
+    hops: &HopTable,
+    ranks_per_node: usize,
+    initial_nodes: &[usize],
+) -> Vec<usize> {
+    let greedy = get_greedy_placement(communication, hops, ranks_per_node);
+    [greedy, initial_nodes.to_vec()]
+        .iter()
+        .map(|nodes| refine_by_swaps(communication, hops, ranks_per_node, nodes))
+        .min_by(|nodes_0, nodes_1| {
+            communication
+                .get_hop_bytes(nodes_0, hops)
+                .partial_cmp(&communication.get_hop_bytes(nodes_1, hops))
+                .unwrap()
+        })
+        .unwrap()
+}
+
+/// Places the rank with the most traffic to already placed ranks on the
+/// free node closest to them, one rank at a time.
+fn get_greedy_placement(
+    communication: &Communication,
+    hops: &HopTable,
+    ranks_per_node: usize,
+) -> Vec<usize> {
+    let num_ranks = communication.num_ranks();
+    let num_nodes = hops.num_nodes;
+    let mut free_slots = vec![ranks_per_node; num_nodes];
+    let mut placed = vec![false; num_ranks];
+    let mut connection = vec![0.0; num_ranks];
+    let total: Vec<f64> = communication
+        .neighbours
+        .iter()
+        .map(|neighbours| neighbours.iter().map(|(_, num_bytes)| num_bytes).sum())
+        .collect();
+    let mut nodes = vec![0; num_ranks];
+    for _ in 0..num_ranks {
+        let rank = (0..num_ranks)
+            .filter(|rank| !placed[*rank])
+            .max_by(|r0, r1| {
+                (connection[*r0], total[*r0])
+                    .partial_cmp(&(connection[*r1], total[*r1]))
+                    .unwrap()
+                    .then(r1.cmp(r0))
+            })
+            .unwrap();
+        let node = (0..num_nodes)
+            .filter(|node| free_slots[*node] > 0)
+            .map(|node| {
+                let cost: f64 = communication.neighbours[rank]
+                    .iter()
+                    .filter(|(neighbour, _)| placed[*neighbour])
+                    .map(|(neighbour, num_bytes)| {
+                        num_bytes * hops.get(node, nodes[*neighbour]) as f64
+                    })
+                    .sum();
+                (node, cost)
+            })
+            .min_by(|(n0, c0), (n1, c1)| c0.partial_cmp(c1).unwrap().then(n0.cmp(n1)))
+            .map(|(node, _)| node)
+            .unwrap();
+        nodes[rank] = node;
+        placed[rank] = true;
+        free_slots[node] -= 1;
+        for (neighbour, num_bytes) in communication.neighbours[rank].iter() {
+            connection[*neighbour] += num_bytes;
+        }
+    }
+    nodes
+}
+
+/// Swaps pairs of ranks, or ranks and free slots, while that reduces the
+/// hop-bytes.
+fn refine_by_swaps(
+    communication: &Communication,
+    hops: &HopTable,
+    ranks_per_node: usize,
+    initial_nodes: &[usize],
+) -> Vec<usize> {
+    let num_ranks = communication.num_ranks();
+    // Entries beyond num_ranks represent free slots.
+    let mut nodes = initial_nodes.to_vec();
+    let mut free_slots = vec![ranks_per_node; hops.num_nodes];
+    for node in initial_nodes.iter() {
+        free_slots[*node] -= 1;
+    }
+    for (node, num_free) in free_slots.iter().enumerate() {
+        nodes.extend(std::iter::repeat_n(node, *num_free));
+    }
+    let get_hop_bytes = |rank: usize, node: usize, nodes: &[usize]| {
+        if rank < num_ranks {
+            communication.get_rank_hop_bytes(rank, node, nodes, hops)
+        } else {
+            0.0
+        }
+    };
+    for _ in 0..MAX_NUM_SWAP_PASSES {
+        let mut improved = false;
+        for rank_0 in 0..num_ranks {
+            for rank_1 in rank_0 + 1..nodes.len() {
+                let (node_0, node_1) = (nodes[rank_0], nodes[rank_1]);
+                if node_0 == node_1 {
+                    continue;
+                }
+                let before =
+                    get_hop_bytes(rank_0, node_0, &nodes) + get_hop_bytes(rank_1, node_1, &nodes);
+                nodes.swap(rank_0, rank_1);
+                let after =
+                    get_hop_bytes(rank_0, node_1, &nodes) + get_hop_bytes(rank_1, node_0, &nodes);
+                if after < before * (1.0 - 1e-12) {
+                    improved = true;
+                } else {
+                    nodes.swap(rank_0, rank_1);
+                }
+            }
+        }
+        if !improved {
+            break;
+        }
+    }
+    nodes.truncate(num_ranks);
+    nodes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chain_is_placed_along_ring() {
+        // Ranks communicate along the chain 0 - 2 - 4 - 1 - 3 - 5
+        let chain = [0, 2, 4, 1, 3, 5];
+        let mut bytes_sent = vec![BTreeMap::new(); 6];
+        for pair in chain.windows(2) {
+            bytes_sent[pair[0]].insert(pair[1], 1.0);
+        }
+        let communication = Communication::new(&bytes_sent);
+        let hops = HopTable::new(
+            &Network::Torus {
+                dimensions: [6, 1, 1],
+            },
+            6,
+        );
+        let identity: Vec<usize> = (0..6).collect();
+        assert_eq!(communication.get_hop_bytes(&identity, &hops), 11.0);
+        let nodes = optimize_placement(&communication, &hops, 1, &identity);
+        assert_eq!(communication.get_hop_bytes(&nodes, &hops), 5.0);
+    }
+}
@@ -1,32 +1,116 @@
+use std::collections::BTreeMap;
 use std::collections::VecDeque;
+use std::rc::Rc;
 
+use anyhow::Result;
 use generational_arena::Index;
 use ordered_float::OrderedFloat;
 use priority_queue::PriorityQueue;
 
+use crate::cost::CostModel;
+use crate::cost::TaskAttributes;
+use crate::noise::Noise;
+use crate::param_file::CommunicationMode;
+use crate::param_file::CommunicationThread;
+use crate::param_file::MessageAttributes;
 use crate::param_file::ParamFile;
 use crate::task::Task;
 use crate::task_priority::TaskPriority;
+use crate::topology::Topology;
 use crate::vector_3d::Vector3D;
 
 type TaskQueue = PriorityQueue<Index, TaskPriority>;
-type SendQueue = VecDeque<(usize, Index)>;
-type ReceiveQueue = PriorityQueue<Index, TaskPriority>;
+type PendingTasks = Vec<(Index, TaskPriority, OrderedFloat<f64>)>;
+type SendQueue = VecDeque<(usize, Index, OrderedFloat<f64>)>;
+type ReceiveQueue = Vec<(Index, TaskPriority, OrderedFloat<f64>, usize)>;
+type SentTasks = Vec<(usize, Index)>;
 
+#[derive(Debug, Clone)]
+struct Core {
+    time: OrderedFloat<f64>,
+    asleep: bool,
+    woken_up: bool,
+    sleep_start: OrderedFloat<f64>,
+    currently_solving: bool,
+    time_spent_communicating: f64,
+    time_spent_waiting: f64,
+    /// Number of sends the core is blocked in and the time at which the last
+    /// of them that completed let it continue
+    num_blocking_sends: usize,
+    blocked_until: OrderedFloat<f64>,
+}
+
+impl Core {
+    fn new() -> Self {
+        Core {
+            time: OrderedFloat(0.0),
+            asleep: false,
+            woken_up: false,
+            sleep_start: OrderedFloat(0.0),
+            currently_solving: false,
+            time_spent_communicating: 0.0,
+            time_spent_waiting: 0.0,
+            num_blocking_sends: 0,
+            blocked_until: OrderedFloat(0.0),
+        }
+    }
+
+    fn is_awake(&self) -> bool {
+        !self.asleep || self.woken_up
+    }
+
+    /// Schedules a sleeping core to continue once a message arrives. Until it
+    /// actually continues, a message arriving earlier can still advance the
+    /// wake up time, but not before its last blocking send completed.
+    fn wake_up_at(&mut self, time: OrderedFloat<f64>) {
+        if self.asleep {
+            if self.woken_up {
+                self.time = self.time.min(time.max(self.sleep_start));
+            } else {
+                self.sleep_start = self.time;
+                self.time = time.max(self.sleep_start);
+                self.woken_up = true;
+            }
+            if self.num_blocking_sends == 0 {
+                self.time = self.time.max(self.blocked_until);
+            }
+        }
+    }
+
+    /// Time spent communicating and waiting by the end of the sweep.
+    fn get_statistics(&self, end_time: f64, solved_any: bool) -> (f64, f64) {
+        if solved_any {
+            (self.time_spent_communicating, self.time_spent_waiting)
+        } else {
+            (self.time_spent_communicating, end_time)
+        }
+    }
+}
+
+/// A rank whose worker cores pull tasks from a shared queue. At any point,
+/// the processor acts through the awake core that is furthest behind in time.
 #[derive(Debug)]
 pub struct Processor {
     param_file: ParamFile,
     pub queue: TaskQueue,
+    /// Tasks released by a core that will only be available once it finishes
+    pending: PendingTasks,
     send_queue: SendQueue,
     receive_queue: ReceiveQueue,
     _domain_center: Vector3D,
     pub num_solved: usize,
-    pub time: OrderedFloat<f64>,
     pub num: usize,
-    pub asleep: bool,
-    pub time_spent_communicating: f64,
-    pub time_spent_waiting: f64,
-    currently_solving: bool,
+    /// Solve times are divided by the speed
+    speed: f64,
+    noise: Option<Noise>,
+    cost_model: Rc<dyn CostModel>,
+    cores: Vec<Core>,
+    current_core: usize,
+    communication_thread: Option<Core>,
+    /// Needed for the hop counts of messages
+    topology: Option<Rc<Topology>>,
+    /// Total number of bytes sent to each other processor
+    pub bytes_sent: BTreeMap<usize, f64>,
 }
 
 impl Processor {
@@ -35,94 +119,404 @@ impl Processor {
         queue: TaskQueue,
         domain_center: Vector3D,
         param_file: &ParamFile,
+        speed: f64,
+        cost_model: Rc<dyn CostModel>,
+        topology: Option<Rc<Topology>>,
     ) -> Self {
+        let (num_workers, communication_thread) = match param_file.communication_thread {
+            CommunicationThread::Worker => (param_file.cores_per_processor, None),
+            CommunicationThread::Dedicated => {
+                (param_file.cores_per_processor - 1, Some(Core::new()))
+            }
+        };
         Processor {
             num,
             queue,
+            pending: PendingTasks::new(),
             _domain_center: domain_center,
             send_queue: SendQueue::new(),
             receive_queue: ReceiveQueue::new(),
             num_solved: 0,
-            time: OrderedFloat(0.0),
-            asleep: false,
-            time_spent_waiting: 0.0,
-            time_spent_communicating: 0.0,
+            speed,
+            noise: param_file
+                .noise
+                .as_ref()
+                .map(|params| Noise::new(params, num)),
+            cost_model,
+            cores: vec![Core::new(); num_workers],
+            current_core: 0,
+            communication_thread,
+            topology,
+            bytes_sent: BTreeMap::new(),
             param_file: param_file.clone(),
-            currently_solving: false,
         }
     }
 
+    /// The time of the core through which the processor currently acts.
+    pub fn time(&self) -> OrderedFloat<f64> {
+        self.cores[self.current_core].time
+    }
+
+    pub fn get_end_time(&self) -> OrderedFloat<f64> {
+        self.cores
+            .iter()
+            .chain(self.communication_thread.iter())
+            .map(|core| core.time)
+            .max()
+            .unwrap()
+    }
+
+    pub fn current_core(&self) -> usize {
+        self.current_core
+    }
+
+    fn core(&mut self) -> &mut Core {
+        &mut self.cores[self.current_core]
+    }
+
     pub fn get_next_task(&mut self) -> Option<Index> {
+        let time = self.time();
+        let queue = &mut self.queue;
+        self.pending.retain(|(task, priority, ready)| {
+            if *ready <= time {
+                queue.push(
+                    *task,
+                    TaskPriority {
+                        priority: priority.priority,
+                    },
+                );
+                false
+            } else {
+                true
+            }
+        });
         self.queue.pop().map(|(index, _)| index)
     }
 
-    pub fn solve(&mut self, _task: &Task) {
+    pub fn solve(&mut self, task: &Task) -> Result<()> {
         self.num_solved += 1;
-        self.time += self.param_file.solve_time_per_task;
+        let solve_time = self.cost_model.get_solve_time(&TaskAttributes::new(task))?
+            + self.param_file.get_group_solve_time();
+        let solve_time = self.perturb(self.time(), solve_time / self.speed);
+        self.core().time += solve_time;
+        Ok(())
     }
 
     pub fn start_solving(&mut self) {
-        if !self.currently_solving {
-            self.time += self.param_file.solve_time_offset;
-            self.currently_solving = true;
+        if !self.core().currently_solving {
+            let solve_time_offset =
+                self.perturb(self.time(), self.param_file.solve_time_offset / self.speed);
+            let core = self.core();
+            core.time += solve_time_offset;
+            core.currently_solving = true;
+        }
+    }
+
+    /// The duration of an activity starting at `start` including noise.
+    fn perturb(&mut self, start: OrderedFloat<f64>, duration: f64) -> f64 {
+        match &mut self.noise {
+            Some(noise) => noise.perturb(*start, duration),
+            None => duration,
         }
     }
 
     pub fn stop_solving(&mut self) {
-        self.currently_solving = false;
+        self.core().currently_solving = false;
     }
 
-    pub fn send_tasks(&mut self) -> SendQueue {
-        let sent_tasks: SendQueue = self.send_queue.drain(..).collect();
-        let send_time = self.get_send_time(sent_tasks.len());
-        self.time_spent_communicating += send_time;
-        self.time += send_time;
-        sent_tasks
+    /// Sends all tasks that are finished by now. Returns them along with the
+    /// time at which the messages leave, which is when the worker core or the
+    /// communication thread starts sending them.
+    pub fn send_tasks(&mut self) -> Result<(SentTasks, OrderedFloat<f64>)> {
+        let time = self.time();
+        let (ready, not_ready): (SendQueue, SendQueue) = self
+            .send_queue
+            .drain(..)
+            .partition(|(_, _, ready)| *ready <= time);
+        self.send_queue = not_ready;
+        let start = match &self.communication_thread {
+            Some(thread) => thread.time.max(time),
+            None => time,
+        };
+        let send_time = self.get_send_time(&ready)?;
+        let send_time = self.perturb(start, send_time);
+        match &mut self.communication_thread {
+            Some(thread) => {
+                thread.time = start + send_time;
+                thread.time_spent_communicating += send_time;
+            }
+            None => {
+                let core = self.core();
+                core.time_spent_communicating += send_time;
+                core.time += send_time;
+            }
+        };
+        let sent_tasks = ready
+            .into_iter()
+            .map(|(receiver, task, _)| (receiver, task))
+            .collect();
+        Ok((sent_tasks, start))
+    }
+
+    /// Receives all tasks whose messages have arrived by now and returns the
+    /// sender of each of them.
+    pub fn receive_tasks(&mut self) -> Result<Vec<usize>> {
+        let time = match &self.communication_thread {
+            Some(thread) => thread.time.max(self.time()),
+            None => self.time(),
+        };
+        let (arrived, pending): (ReceiveQueue, ReceiveQueue) = self
+            .receive_queue
+            .drain(..)
+            .partition(|(_, _, arrival, _)| *arrival <= time);
+        self.receive_queue = pending;
+        let num_received = arrived.len();
+        let receive_time = self.get_receive_time(&arrived)?;
+        let receive_time = self.perturb(time, receive_time);
+        let core = match &mut self.communication_thread {
+            Some(thread) => {
+                thread.time = time;
+                thread
+            }
+            None => &mut self.cores[self.current_core],
+        };
+        core.time_spent_communicating += receive_time;
+        core.time += receive_time;
+        let ready = core.time;
+        let mut senders = vec![];
+        for (task, priority, _, sender) in arrived {
+            self.pending.push((task, priority, ready));
+            senders.push(sender);
+        }
+        self.wake_idle_cores(ready, num_received);
+        Ok(senders)
     }
 
-    pub fn receive_tasks(&mut self) -> usize {
-        let num_received = self.receive_queue.len();
-        let receive_time = self.get_receive_time(num_received);
-        self.time_spent_communicating += receive_time;
-        self.time += receive_time;
-        self.queue.append(&mut self.receive_queue);
-        num_received
+    /// The next time at which a message arrives or a pending task becomes
+    /// available.
+    pub fn get_next_event(&self) -> Option<OrderedFloat<f64>> {
+        self.receive_queue
+            .iter()
+            .map(|(_, _, arrival, _)| *arrival)
+            .chain(self.pending.iter().map(|(_, _, ready)| *ready))
+            .min()
     }
 
-    fn get_send_time(&self, num_sent: usize) -> f64 {
-        self.param_file.send_time_offset
-            + num_sent as f64
-                * self.param_file.send_time_per_byte
-                * self.param_file.size_per_message
+    pub fn wait_until(&mut self, time: OrderedFloat<f64>) {
+        let core = self.core();
+        if time > core.time {
+            core.time_spent_waiting += *time - *core.time;
+            core.time = time;
+        }
+    }
+
+    /// Time the current core spends on sending the tasks. With a
+    /// `send_time` expression, every message is charged separately.
+    fn get_send_time(&self, ready: &SendQueue) -> Result<f64> {
+        let mut messages: BTreeMap<usize, usize> = BTreeMap::new();
+        for (receiver, _, _) in ready.iter() {
+            *messages.entry(*receiver).or_insert(0) += 1;
+        }
+        match self.param_file.communication_mode {
+            CommunicationMode::Blocking if self.param_file.has_message_time_expressions() => {
+                messages
+                    .into_iter()
+                    .map(|(receiver, num_tasks)| {
+                        let message = self.get_message_attributes(self.num, receiver, num_tasks);
+                        self.param_file.get_send_time(&message)
+                    })
+                    .sum()
+            }
+            CommunicationMode::Blocking => Ok(self.param_file.send_time_offset
+                + ready.len() as f64
+                    * self.param_file.send_time_per_byte
+                    * self.param_file.get_size_per_task()),
+            CommunicationMode::Asynchronous => {
+                Ok(messages.len() as f64 * self.param_file.post_time_offset)
+            }
+        }
+    }
+
+    /// Time the current core spends on receiving the tasks. With a
+    /// `recv_time` expression, every message is charged separately. Without
+    /// one, the receive coefficients are charged once for all received bytes,
+    /// like the send coefficients in `get_send_time`.
+    fn get_receive_time(&self, arrived: &ReceiveQueue) -> Result<f64> {
+        match self.param_file.communication_mode {
+            CommunicationMode::Blocking if self.param_file.has_message_time_expressions() => {
+                let mut messages: BTreeMap<(usize, OrderedFloat<f64>), usize> = BTreeMap::new();
+                for (_, _, arrival, sender) in arrived.iter() {
+                    *messages.entry((*sender, *arrival)).or_insert(0) += 1;
+                }
+                messages
+                    .into_iter()
+                    .map(|((sender, _), num_tasks)| {
+                        let message = self.get_message_attributes(sender, self.num, num_tasks);
+                        self.param_file.get_receive_time(&message)
+                    })
+                    .sum()
+            }
+            CommunicationMode::Blocking => Ok(self.param_file.recv_time_offset
+                + arrived.len() as f64
+                    * self.param_file.recv_time_per_byte
+                    * self.param_file.get_size_per_task()),
+            CommunicationMode::Asynchronous if !arrived.is_empty() => {
+                Ok(self.param_file.post_time_offset)
+            }
+            CommunicationMode::Asynchronous => Ok(0.0),
+        }
+    }
+
+    pub fn get_message_attributes(
+        &self,
+        sender: usize,
+        receiver: usize,
+        num_tasks: usize,
+    ) -> MessageAttributes {
+        MessageAttributes {
+            sender,
+            receiver,
+            num_tasks,
+            num_bytes: num_tasks as f64 * self.param_file.get_size_per_task(),
+            num_hops: self
+                .topology
+                .as_ref()
+                .map(|topology| topology.get_num_hops(sender, receiver))
+                .unwrap_or(0),
+        }
     }
 
-    fn get_receive_time(&self, num_received: usize) -> f64 {
-        self.get_send_time(num_received)
+    pub fn record_message(&mut self, receiver: usize, num_bytes: f64) {
+        *self.bytes_sent.entry(receiver).or_insert(0.0) += num_bytes;
     }
 
-    pub fn add_task_to_queue(&mut self, task_index: Index, priority: TaskPriority) {
-        self.queue.push(task_index, priority);
+    /// Adds a task that becomes available once the current core finishes
+    /// and the time `ready` is reached.
+    pub fn add_task_to_queue(
+        &mut self,
+        task_index: Index,
+        priority: TaskPriority,
+        ready: OrderedFloat<f64>,
+    ) {
+        let ready = self.time().max(ready);
+        self.pending.push((task_index, priority, ready));
+        self.wake_idle_cores(ready, 1);
     }
 
-    pub fn add_task_to_send_queue(&mut self, task_index: Index, processor_num: usize) {
-        self.send_queue.push_back((processor_num, task_index));
+    pub fn add_task_to_send_queue(
+        &mut self,
+        task_index: Index,
+        processor_num: usize,
+        ready: OrderedFloat<f64>,
+    ) {
+        let ready = self.time().max(ready);
+        self.send_queue
+            .push_back((processor_num, task_index, ready));
     }
 
-    pub fn add_task_to_receive_queue(&mut self, task: Index, priority: TaskPriority) {
-        self.receive_queue.push(task, priority);
+    pub fn add_task_to_receive_queue(
+        &mut self,
+        task: Index,
+        priority: TaskPriority,
+        arrival: OrderedFloat<f64>,
+        sender: usize,
+    ) {
+        self.receive_queue.push((task, priority, arrival, sender));
     }
 
     pub fn go_to_sleep(&mut self) {
-        self.asleep = true;
+        let core = self.core();
+        core.asleep = true;
+        core.woken_up = false;
     }
 
+    /// Wakes up the processor if all of its cores are asleep.
     pub fn wake_up_at(&mut self, time: OrderedFloat<f64>) {
-        if self.asleep {
-            if *time > *self.time {
-                self.time_spent_waiting += *time - *self.time;
-                self.time = time;
+        if self.cores.iter().all(|core| core.asleep) {
+            self.core().wake_up_at(time);
+        }
+    }
+
+    fn wake_idle_cores(&mut self, time: OrderedFloat<f64>, num_tasks: usize) {
+        let current_core = self.current_core;
+        for (_, core) in self
+            .cores
+            .iter_mut()
+            .enumerate()
+            .filter(|(index, core)| *index != current_core && !core.is_awake())
+            .take(num_tasks)
+        {
+            core.wake_up_at(time);
+        }
+    }
+
+    pub fn continue_after_sleep(&mut self) {
+        let core = self.core();
+        if core.asleep {
+            core.time_spent_waiting += *core.time - *core.sleep_start;
+            core.asleep = false;
+        }
+        if core.num_blocking_sends == 0 && core.time < core.blocked_until {
+            core.time_spent_waiting += *core.blocked_until - *core.time;
+            core.time = core.blocked_until;
+        }
+    }
+
+    /// Blocks the current core in a send until it is unblocked. Returns the
+    /// index of the core.
+    pub fn block(&mut self) -> usize {
+        self.core().num_blocking_sends += 1;
+        self.current_core
+    }
+
+    pub fn is_blocked(&self) -> bool {
+        self.cores[self.current_core].num_blocking_sends > 0
+    }
+
+    /// Completes one of the sends the core is blocked in. Once all of them
+    /// are completed, the core continues at the latest completion time.
+    pub fn unblock(&mut self, core: usize, time: OrderedFloat<f64>) {
+        let core = &mut self.cores[core];
+        core.num_blocking_sends -= 1;
+        core.blocked_until = core.blocked_until.max(time);
+        if core.num_blocking_sends == 0 {
+            let blocked_until = core.blocked_until;
+            core.wake_up_at(blocked_until);
+        }
+    }
+
+    /// Lets the processor act through the awake core that is furthest behind.
+    /// Returns false if all cores are asleep.
+    pub fn select_next_core(&mut self) -> bool {
+        let next_core = self
+            .cores
+            .iter()
+            .enumerate()
+            .filter(|(_, core)| core.is_awake())
+            .min_by_key(|(index, core)| (core.time, *index))
+            .map(|(index, _)| index);
+        match next_core {
+            Some(index) => {
+                self.current_core = index;
+                true
             }
-            self.asleep = false;
+            None => false,
         }
     }
+
+    /// Time spent communicating and waiting by each core, including the
+    /// communication thread, which waits whenever it is not communicating.
+    pub fn get_core_statistics(&self, end_time: f64) -> Vec<(f64, f64)> {
+        let solved_any = self.num_solved > 0;
+        self.cores
+            .iter()
+            .map(|core| core.get_statistics(end_time, solved_any))
+            .chain(self.communication_thread.iter().map(|thread| {
+                (
+                    thread.time_spent_communicating,
+                    end_time - thread.time_spent_communicating,
+                )
+            }))
+            .collect()
+    }
 }
@@ -1,13 +1,17 @@
 use std::ops::Index;
 use std::ops::IndexMut;
+use std::rc::Rc;
 
 use ordered_float::OrderedFloat;
 use priority_queue::PriorityQueue;
 
+use crate::cell::Cell;
+use crate::cost::CostModel;
 use crate::grid::DependencyGraph;
 use crate::param_file::ParamFile;
 use crate::processor::Processor;
 use crate::processor_priority::ProcessorPriority;
+use crate::topology::Topology;
 use crate::vector_3d::Vector3D;
 pub struct Processors {
     processors: Vec<Processor>,
@@ -15,40 +19,81 @@ pub struct Processors {
 }
 
 impl Processors {
-    pub fn new(graph: &DependencyGraph, num_processors: usize, param_file: &ParamFile) -> Self {
-        let centers = Processors::get_centers(graph, num_processors);
+    pub fn new(
+        graph: &DependencyGraph,
+        num_processors: usize,
+        param_file: &ParamFile,
+        speeds: &[f64],
+        cost_model: &Rc<dyn CostModel>,
+        topology: &Option<Rc<Topology>>,
+    ) -> Self {
+        let cells = graph.iter().map(|task| task.cell);
+        let centers = Processors::get_centers(cells, num_processors);
         let mut processors: Vec<Processor> = centers
             .into_iter()
             .enumerate()
-            .map(|(num, center)| Processor::new(num, PriorityQueue::new(), center, param_file))
+            .map(|(num, center)| {
+                Processor::new(
+                    num,
+                    PriorityQueue::new(),
+                    center,
+                    param_file,
+                    speeds[num],
+                    cost_model.clone(),
+                    topology.clone(),
+                )
+            })
             .collect();
         for task_node in graph.iter_nodes() {
             let task = &task_node.data;
             let priority = task.get_priority();
             if task.num_upwind == 0 {
-                processors[task.processor_num].add_task_to_queue(task_node.index, priority);
+                processors[task.processor_num].add_task_to_queue(
+                    task_node.index,
+                    priority,
+                    OrderedFloat(0.0),
+                );
             }
         }
         let queue = processors
             .iter()
-            .map(|processor| Processors::get_queue_element(processor))
+            .map(Processors::get_queue_element)
             .collect();
         Processors { processors, queue }
     }
 
-    pub fn get_centers(graph: &DependencyGraph, num_processors: usize) -> Vec<Vector3D> {
+    /// The mean cell center of each processor. Processors without cells get
+    /// the mean center of all cells.
+    pub fn get_centers<'a>(
+        cells: impl Iterator<Item = &'a Cell>,
+        num_processors: usize,
+    ) -> Vec<Vector3D> {
         let mut centers: Vec<Vector3D> = (0..num_processors)
             .map(|_| Vector3D::new(0., 0., 0.))
             .collect();
         let mut num_cells: Vec<usize> = (0..num_processors).map(|_| 0).collect();
-        for task_node in graph.iter_nodes() {
-            centers[task_node.data.processor_num] += &task_node.data.cell.center;
-            num_cells[task_node.data.processor_num] += 1;
+        for cell in cells {
+            centers[cell.processor_num] += &cell.center;
+            num_cells[cell.processor_num] += 1;
         }
-        for (mut center, num) in centers.iter_mut().zip(num_cells) {
-            center /= num as f64;
+        let mut total = Vector3D::new(0., 0., 0.);
+        for center in centers.iter() {
+            total += center;
         }
+        let total_num_cells: usize = num_cells.iter().sum();
+        total /= total_num_cells.max(1) as f64;
         centers
+            .into_iter()
+            .zip(num_cells)
+            .map(|(mut center, num)| {
+                if num == 0 {
+                    total.clone()
+                } else {
+                    center /= num as f64;
+                    center
+                }
+            })
+            .collect()
     }
 
     pub fn len(&self) -> usize {
@@ -59,9 +104,17 @@ impl Processors {
         Box::new(self.processors.iter())
     }
 
-    pub fn get_next_free(&mut self) -> &mut Processor {
-        let (index, _) = self.queue.pop().unwrap();
-        &mut self.processors[index]
+    pub fn get_next_time(&self) -> Option<OrderedFloat<f64>> {
+        self.queue.peek().map(|(_, priority)| -priority.time)
+    }
+
+    /// The processor that continues next. Returns None if all processors
+    /// are asleep.
+    pub fn get_next_free(&mut self) -> Option<&mut Processor> {
+        let (index, _) = self.queue.pop()?;
+        let processor = &mut self.processors[index];
+        processor.continue_after_sleep();
+        Some(processor)
     }
 
     pub fn reinsert_with_new_priority(&mut self, processor_num: usize) {
@@ -74,11 +127,19 @@ impl Processors {
         (
             processor.num,
             ProcessorPriority {
-                time: -processor.time,
+                time: -processor.time(),
             },
         )
     }
 
+    pub fn unblock(&mut self, processor_num: usize, core: usize, time: OrderedFloat<f64>) {
+        let processor = &mut self.processors[processor_num];
+        processor.unblock(core, time);
+        if processor.select_next_core() {
+            self.reinsert_with_new_priority(processor_num);
+        }
+    }
+
     pub fn wake_up_at(&mut self, processor_num: usize, time: OrderedFloat<f64>) {
         self.processors[processor_num].wake_up_at(time);
         self.reinsert_with_new_priority(processor_num);
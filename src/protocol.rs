@@ -0,0 +1,160 @@
+use std::collections::VecDeque;
+
+use generational_arena::Index;
+use serde::Deserialize;
+use serde::Serialize;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ProtocolParams {
+    /// Messages with more bytes than this use the rendezvous protocol: the
+    /// sender blocks until the receiver posts a matching receive.
+    pub eager_threshold: f64,
+    /// Time between the receiver posting the receive of a rendezvous message
+    /// and the start of its transfer.
+    #[serde(default)]
+    pub handshake_time: f64,
+    /// Maximum number of bytes of eager messages of a processor that are sent
+    /// but not yet received. A sender exceeding it blocks until the receivers
+    /// have received enough of them.
+    #[serde(default)]
+    pub max_outstanding_bytes: Option<f64>,
+}
+
+/// A message whose transfer has not started yet, along with the core of the
+/// sender which is blocked until it does.
+pub struct PendingMessage {
+    pub sender: usize,
+    pub core: usize,
+    pub receiver: usize,
+    pub tasks: Vec<Index>,
+    pub num_bytes: f64,
+    /// For rendezvous messages, the arrival of the request at the receiver
+    pub time: f64,
+}
+
+/// State of the rendezvous requests and send buffers of all processors.
+pub struct Protocol {
+    params: ProtocolParams,
+    requests: Vec<PendingMessage>,
+    outstanding_bytes: Vec<f64>,
+    held: Vec<VecDeque<PendingMessage>>,
+}
+
+impl Protocol {
+    pub fn new(params: &ProtocolParams, num_processors: usize) -> Self {
+        Protocol {
+            params: params.clone(),
+            requests: vec![],
+            outstanding_bytes: vec![0.0; num_processors],
+            held: (0..num_processors).map(|_| VecDeque::new()).collect(),
+        }
+    }
+
+    pub fn params(&self) -> &ProtocolParams {
+        &self.params
+    }
+
+    pub fn is_rendezvous(&self, num_bytes: f64) -> bool {
+        num_bytes > self.params.eager_threshold
+    }
+
+    pub fn post_request(&mut self, message: PendingMessage) {
+        self.requests.push(message);
+    }
+
+    /// Takes the space for an eager message in the send buffer of the
+    /// sender. Returns false if the message has to wait. A message larger
+    /// than the buffer is only sent once the buffer is empty.
+    pub fn try_reserve(&mut self, sender: usize, num_bytes: f64) -> bool {
+        if !self.held[sender].is_empty() || !self.fits(sender, num_bytes) {
+            return false;
+        }
+        self.outstanding_bytes[sender] += num_bytes;
+        true
+    }
+
+    pub fn hold(&mut self, message: PendingMessage) {
+        self.held[message.sender].push_back(message);
+    }
+
+    /// The earliest arrival of a rendezvous request at the receiver that
+    /// has not been matched yet.
+    pub fn get_next_request(&self, receiver: usize) -> Option<f64> {
+        self.requests
+            .iter()
+            .filter(|request| request.receiver == receiver)
+            .map(|request| request.time)
+            .min_by(f64::total_cmp)
+    }
+
+    /// Removes the requests that arrived at the receiver by the given time.
+    pub fn match_requests(&mut self, receiver: usize, time: f64) -> Vec<PendingMessage> {
+        let (matched, requests) = std::mem::take(&mut self.requests)
+            .into_iter()
+            .partition(|request| request.receiver == receiver && request.time <= time);
+        self.requests = requests;
+        matched
+    }
+
+    /// Frees the buffer space of received messages and returns the held
+    /// messages of the sender which fit now.
+    pub fn release(&mut self, sender: usize, num_bytes: f64) -> Vec<PendingMessage> {
+        self.outstanding_bytes[sender] = (self.outstanding_bytes[sender] - num_bytes).max(0.0);
+        let mut released = vec![];
+        while let Some(message) = self.held[sender].front() {
+            if !self.fits(sender, message.num_bytes) {
+                break;
+            }
+            self.outstanding_bytes[sender] += message.num_bytes;
+            released.push(self.held[sender].pop_front().unwrap());
+        }
+        released
+    }
+
+    fn fits(&self, sender: usize, num_bytes: f64) -> bool {
+        match self.params.max_outstanding_bytes {
+            Some(max) => {
+                self.outstanding_bytes[sender] == 0.0
+                    || self.outstanding_bytes[sender] + num_bytes <= max
+            }
+            None => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn held_messages_are_released_in_order() {
+        let mut protocol = Protocol::new(
+            &ProtocolParams {
+                eager_threshold: 100.0,
+                handshake_time: 0.0,
+                max_outstanding_bytes: Some(100.0),
+            },
+            2,
+        );
+        let message = |num_bytes| PendingMessage {
+            sender: 0,
+            core: 0,
+            receiver: 1,
+            tasks: vec![],
+            num_bytes,
+            time: 0.0,
+        };
+        assert!(!protocol.is_rendezvous(100.0));
+        assert!(protocol.try_reserve(0, 60.0));
+        assert!(!protocol.try_reserve(0, 60.0));
+        protocol.hold(message(60.0));
+        protocol.hold(message(30.0));
+        assert!(!protocol.try_reserve(0, 10.0));
+        let released = protocol.release(0, 30.0);
+        assert_eq!(released.len(), 1);
+        assert_eq!(released[0].num_bytes, 60.0);
+        let released = protocol.release(0, 60.0);
+        assert_eq!(released.len(), 1);
+        assert_eq!(released[0].num_bytes, 30.0);
+    }
+}
@@ -0,0 +1,474 @@
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::fs;
+use std::path::Path;
+
+use anyhow::bail;
+use anyhow::Context;
+use anyhow::Result;
+
+use crate::boundary::get_reflective_dependencies;
+use crate::cost::TaskAttributes;
+use crate::direction::get_directions;
+use crate::grid::Grid;
+use crate::param_file::CommunicationMode;
+use crate::param_file::CommunicationThread;
+use crate::param_file::MessageAttributes;
+use crate::param_file::ParamFile;
+use crate::schedule::Schedule;
+use crate::topology::Topology;
+use crate::transport::UpwindStencil;
+
+/// A task of a replayed schedule.
+#[derive(Debug, Clone)]
+pub struct ReplayedTask {
+    pub processor: usize,
+    pub direction: usize,
+    pub local_index: usize,
+    /// Start time predicted by the cost model
+    pub modeled_time: f64,
+    /// Start time recorded in the schedule, if any
+    pub measured_time: Option<f64>,
+}
+
+pub struct Replay {
+    /// Predicted time of the whole sweep
+    pub time: f64,
+    /// The tasks in the order of the schedule
+    pub tasks: Vec<ReplayedTask>,
+}
+
+/// A message sent at the end of a batch.
+struct Message {
+    sender: usize,
+    num_tasks: usize,
+    arrival: f64,
+}
+
+/// Replays the schedule through the cost model of the param file. Every
+/// processor solves its batches in the given order on a single core. Before
+/// a batch, it waits for and receives the messages carrying tasks of the
+/// batch, and no task starts before its upwind tasks finished. After a
+/// batch, it sends the tasks whose last dependency it resolved to their
+/// processors, with one message per receiver. Param files with settings
+/// beyond this model are rejected.
+pub fn replay(param_file: &ParamFile, grid: &Grid, schedule: &Schedule) -> Result<Replay> {
+    if let CommunicationMode::Asynchronous = param_file.communication_mode {
+        bail!("Replaying a schedule requires blocking communication");
+    }
+    if param_file.get_num_task_groups() > 1 {
+        bail!("Replaying a schedule of independent groups is not supported");
+    }
+    if param_file.cores_per_processor != 1
+        || param_file.communication_thread != CommunicationThread::Worker
+    {
+        bail!("Replaying a schedule requires a single core per processor without a dedicated communication thread");
+    }
+    let unsupported = [
+        ("protocol", param_file.protocol.is_some()),
+        ("speeds", param_file.speeds.is_some()),
+        ("noise", param_file.noise.is_some()),
+        (
+            "contention",
+            param_file
+                .topology
+                .as_ref()
+                .is_some_and(|topology| topology.contention.is_some()),
+        ),
+    ];
+    if let Some((name, _)) = unsupported.iter().find(|(_, is_set)| *is_set) {
+        bail!("Replaying a schedule does not support the {} setting", name);
+    }
+    let num_processors = grid.num_processors();
+    if schedule.processors.len() > num_processors {
+        bail!(
+            "The schedule contains {} processors, but the grid only has {}",
+            schedule.processors.len(),
+            num_processors
+        );
+    }
+    let topology = match &param_file.topology {
+        Some(params) => Some(Topology::new(params, num_processors)?),
+        None => None,
+    };
+    let grid_cells: Vec<_> = grid.iter().collect();
+    let cost_model = param_file.get_cost_model();
+    let owners: Vec<usize> = grid.iter().map(|cell| cell.processor_num).collect();
+    let mut cells: Vec<Vec<usize>> = grid
+        .get_num_cells_per_processor()
+        .into_iter()
+        .map(|num_cells| vec![0; num_cells])
+        .collect();
+    for cell in grid.iter() {
+        cells[cell.processor_num][cell.local_index] = cell.global_index;
+    }
+    let mut num_neighbours = vec![0; owners.len()];
+    for (cell, _, _) in grid.iter_neighbours() {
+        num_neighbours[cell.global_index] += 1;
+    }
+    let directions = get_directions(param_file.num_directions);
+    let stencils: Vec<UpwindStencil> = directions
+        .iter()
+        .map(|direction| UpwindStencil::new(grid, direction, param_file.face_tolerance))
+        .collect();
+    // The upwind and downwind tasks of every task as (direction, cell)
+    let mut upwind: Vec<Vec<Vec<(usize, usize)>>> =
+        vec![vec![vec![]; owners.len()]; directions.len()];
+    let mut downwind: Vec<Vec<Vec<(usize, usize)>>> =
+        vec![vec![vec![]; owners.len()]; directions.len()];
+    for (direction, stencil) in stencils.iter().enumerate() {
+        for (cell, upwind_tasks) in upwind[direction].iter_mut().enumerate() {
+            for (upwind_cell, _) in stencil.get_upwind(cell) {
+                upwind_tasks.push((direction, *upwind_cell));
+                downwind[direction][*upwind_cell].push((direction, cell));
+            }
+        }
+    }
+    for (upwind_task, (direction, cell)) in
+        get_reflective_dependencies(grid, &directions, &param_file.reflective_boundaries)
+    {
+        upwind[direction][cell].push(upwind_task);
+        downwind[upwind_task.0][upwind_task.1].push((direction, cell));
+    }
+    // The global cell of every scheduled task
+    let mut batches: Vec<Vec<Vec<(usize, usize)>>> = vec![vec![]; num_processors];
+    let mut is_scheduled = vec![vec![false; owners.len()]; directions.len()];
+    for (processor_num, processor_batches) in schedule.processors.iter().enumerate() {
+        for batch in processor_batches.iter() {
+            let mut tasks = vec![];
+            for task in batch.iter() {
+                if task.direction >= directions.len() {
+                    bail!(
+                        "Processor {} schedules direction {}, but there are only {} directions",
+                        processor_num,
+                        task.direction,
+                        directions.len()
+                    );
+                }
+                let cell = match cells[processor_num].get(task.local_index) {
+                    Some(cell) => *cell,
+                    None => bail!(
+                        "Processor {} schedules local index {}, but only owns {} cells",
+                        processor_num,
+                        task.local_index,
+                        cells[processor_num].len()
+                    ),
+                };
+                if is_scheduled[task.direction][cell] {
+                    bail!(
+                        "Processor {} schedules task (direction {}, local index {}) twice",
+                        processor_num,
+                        task.direction,
+                        task.local_index
+                    );
+                }
+                is_scheduled[task.direction][cell] = true;
+                tasks.push((task.direction, cell));
+            }
+            batches[processor_num].push(tasks);
+        }
+    }
+    for (direction, scheduled) in is_scheduled.iter().enumerate() {
+        if let Some(cell) = scheduled.iter().position(|scheduled| !scheduled) {
+            bail!(
+                "Task (direction {}, local index {}) of processor {} is not scheduled",
+                direction,
+                grid_cells[cell].local_index,
+                owners[cell]
+            );
+        }
+    }
+    let get_message_attributes =
+        |sender: usize, receiver: usize, num_tasks: usize| MessageAttributes {
+            sender,
+            receiver,
+            num_tasks,
+            num_bytes: num_tasks as f64 * param_file.get_size_per_task(),
+            num_hops: topology
+                .as_ref()
+                .map(|topology| topology.get_num_hops(sender, receiver))
+                .unwrap_or(0),
+        };
+    let mut start_times: HashMap<(usize, usize), f64> = HashMap::new();
+    let mut end_times: Vec<Vec<Option<f64>>> = vec![vec![None; owners.len()]; directions.len()];
+    let mut num_unsolved_upwind: Vec<Vec<usize>> = upwind
+        .iter()
+        .map(|tasks| {
+            tasks
+                .iter()
+                .map(|upwind_tasks| upwind_tasks.len())
+                .collect()
+        })
+        .collect();
+    let mut messages: Vec<Message> = vec![];
+    // The message with which a task was sent to its processor
+    let mut message_of: HashMap<(usize, usize), usize> = HashMap::new();
+    let mut times = vec![0.0; num_processors];
+    let mut next_batch = vec![0; num_processors];
+    // The upwind task that the next batch of each processor last waited for
+    let mut blocked_on: Vec<Option<(usize, usize)>> = vec![None; num_processors];
+    // Whether all upwind tasks of the next batch are solved
+    let mut is_ready = vec![false; num_processors];
+    loop {
+        // Continue with the processor that is furthest behind, such that
+        // tasks are resolved roughly in the order of time, like in the
+        // simulation.
+        let mut next = None;
+        for processor_num in 0..num_processors {
+            let batch = match batches[processor_num].get(next_batch[processor_num]) {
+                Some(batch) => batch,
+                None => continue,
+            };
+            if !is_ready[processor_num] {
+                if let Some((direction, cell)) = blocked_on[processor_num] {
+                    if end_times[direction][cell].is_none() {
+                        continue;
+                    }
+                }
+                blocked_on[processor_num] =
+                    find_unsolved_upwind(batch, &upwind, &end_times).map(|(_, upwind)| upwind);
+                is_ready[processor_num] = blocked_on[processor_num].is_none();
+            }
+            if is_ready[processor_num]
+                && next.is_none_or(|next: usize| times[processor_num] < times[next])
+            {
+                next = Some(processor_num);
+            }
+        }
+        let processor_num = match next {
+            Some(processor_num) => processor_num,
+            None => break,
+        };
+        let batch = &batches[processor_num][next_batch[processor_num]];
+        let mut time = times[processor_num];
+        let received: BTreeSet<usize> = batch
+            .iter()
+            .filter_map(|task| message_of.get(task).copied())
+            .collect();
+        let mut num_received = 0;
+        for message in received.iter() {
+            let message = &messages[*message];
+            time = f64::max(time, message.arrival);
+            num_received += message.num_tasks;
+            if param_file.has_message_time_expressions() {
+                time += param_file.get_receive_time(&get_message_attributes(
+                    message.sender,
+                    processor_num,
+                    message.num_tasks,
+                ))?;
+            }
+        }
+        // Without expressions, every communication costs the offset, like in
+        // the simulation.
+        if !param_file.has_message_time_expressions() {
+            time += param_file.recv_time_offset
+                + num_received as f64
+                    * param_file.recv_time_per_byte
+                    * param_file.get_size_per_task();
+        }
+        time += param_file.solve_time_offset;
+        let mut outgoing: BTreeMap<usize, Vec<(usize, usize)>> = BTreeMap::new();
+        for (direction, cell) in batch.iter() {
+            time = upwind[*direction][*cell]
+                .iter()
+                .map(|(upwind_direction, upwind_cell)| {
+                    end_times[*upwind_direction][*upwind_cell].unwrap()
+                })
+                .fold(time, f64::max);
+            start_times.insert((*direction, *cell), time);
+            time += cost_model.get_solve_time(&TaskAttributes {
+                global_index: *cell,
+                processor_num,
+                num_neighbours: num_neighbours[*cell],
+                num_upwind_faces: stencils[*direction].get_upwind(*cell).len(),
+                direction_index: *direction,
+                direction: [
+                    *directions[*direction].vector.x,
+                    *directions[*direction].vector.y,
+                    *directions[*direction].vector.z,
+                ],
+                weights: &grid_cells[*cell].weights,
+            })? + param_file.get_group_solve_time();
+            end_times[*direction][*cell] = Some(time);
+            // Like in the simulation, a task is sent by the processor that
+            // resolves its last dependency.
+            for (downwind_direction, downwind_cell) in downwind[*direction][*cell].iter() {
+                num_unsolved_upwind[*downwind_direction][*downwind_cell] -= 1;
+                let owner = owners[*downwind_cell];
+                if num_unsolved_upwind[*downwind_direction][*downwind_cell] == 0
+                    && owner != processor_num
+                {
+                    outgoing
+                        .entry(owner)
+                        .or_default()
+                        .push((*downwind_direction, *downwind_cell));
+                }
+            }
+        }
+        // All messages depart when the processor starts sending
+        let departure = time;
+        let num_sent: usize = outgoing.values().map(|tasks| tasks.len()).sum();
+        if !param_file.has_message_time_expressions() {
+            time += param_file.send_time_offset
+                + num_sent as f64 * param_file.send_time_per_byte * param_file.get_size_per_task();
+        }
+        for (receiver, tasks) in outgoing.into_iter() {
+            let attributes = get_message_attributes(processor_num, receiver, tasks.len());
+            if param_file.has_message_time_expressions() {
+                time += param_file.get_send_time(&attributes)?;
+            }
+            let arrival = match &topology {
+                Some(topology) => {
+                    departure
+                        + topology.get_transfer_time(processor_num, receiver, attributes.num_bytes)
+                }
+                None => departure,
+            };
+            for task in tasks.iter() {
+                message_of.insert(*task, messages.len());
+            }
+            messages.push(Message {
+                sender: processor_num,
+                num_tasks: tasks.len(),
+                arrival,
+            });
+        }
+        times[processor_num] = time;
+        next_batch[processor_num] += 1;
+        is_ready[processor_num] = false;
+    }
+    for (processor_num, processor_batches) in batches.iter().enumerate() {
+        if let Some(batch) = processor_batches.get(next_batch[processor_num]) {
+            let ((direction, cell), _) = find_unsolved_upwind(batch, &upwind, &end_times).unwrap();
+            bail!(
+                "The schedule deadlocks: processor {} waits for an upwind task of task (direction {}, local index {}) that is never solved before it",
+                processor_num,
+                direction,
+                grid_cells[cell].local_index
+            );
+        }
+    }
+    let tasks = schedule
+        .processors
+        .iter()
+        .enumerate()
+        .flat_map(|(processor_num, batches)| {
+            batches
+                .iter()
+                .flatten()
+                .map(move |task| (processor_num, task))
+        })
+        .map(|(processor_num, task)| ReplayedTask {
+            processor: processor_num,
+            direction: task.direction,
+            local_index: task.local_index,
+            modeled_time: start_times[&(task.direction, cells[processor_num][task.local_index])],
+            measured_time: task.time,
+        })
+        .collect();
+    Ok(Replay {
+        time: times.into_iter().fold(0.0, f64::max),
+        tasks,
+    })
+}
+
+/// The first task of the batch with an upwind task that is neither solved
+/// nor solved earlier in the batch, along with that upwind task.
+fn find_unsolved_upwind(
+    batch: &[(usize, usize)],
+    upwind: &[Vec<Vec<(usize, usize)>>],
+    end_times: &[Vec<Option<f64>>],
+) -> Option<((usize, usize), (usize, usize))> {
+    let mut in_batch: BTreeSet<(usize, usize)> = BTreeSet::new();
+    for (direction, cell) in batch.iter() {
+        for (upwind_direction, upwind_cell) in upwind[*direction][*cell].iter() {
+            if end_times[*upwind_direction][*upwind_cell].is_none()
+                && !in_batch.contains(&(*upwind_direction, *upwind_cell))
+            {
+                return Some(((*direction, *cell), (*upwind_direction, *upwind_cell)));
+            }
+        }
+        in_batch.insert((*direction, *cell));
+    }
+    None
+}
+
+impl Replay {
+    /// Modeled minus measured start time of every task with a measured time.
+    /// The measured times are shifted such that the earliest one is zero,
+    /// like the start of the modeled sweep.
+    pub fn get_discrepancies(&self) -> Vec<(&ReplayedTask, f64)> {
+        let first = self
+            .tasks
+            .iter()
+            .filter_map(|task| task.measured_time)
+            .fold(f64::INFINITY, f64::min);
+        self.tasks
+            .iter()
+            .filter_map(|task| {
+                task.measured_time
+                    .map(|measured| (task, task.modeled_time - (measured - first)))
+            })
+            .collect()
+    }
+
+    /// Writes the discrepancy of every task with a measured time as CSV.
+    pub fn write_discrepancies(&self, path: &Path) -> Result<()> {
+        let mut contents =
+            String::from("processor,direction,local_index,measured,modeled,discrepancy\n");
+        for (task, discrepancy) in self.get_discrepancies() {
+            writeln!(
+                contents,
+                "{},{},{},{:e},{:e},{:e}",
+                task.processor,
+                task.direction,
+                task.local_index,
+                task.modeled_time - discrepancy,
+                task.modeled_time,
+                discrepancy
+            )
+            .unwrap();
+        }
+        fs::write(path, contents).context(format!("While writing discrepancies to {:?}", path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generate::generate_grid;
+    use crate::generate::GridSpec;
+    use crate::param_file::get_test_param_file;
+    use crate::run::simulate_grid_with_trace;
+
+    #[test]
+    fn replay_simulated_schedule() {
+        let grid = generate_grid(&GridSpec {
+            size: [4, 4, 4],
+            num_processors: 4,
+            ..GridSpec::default()
+        });
+        let mut param_file = get_test_param_file("batch_size: 4");
+        param_file.send_time_per_byte = 1.0e-9;
+        param_file.recv_time_per_byte = 1.0e-9;
+        param_file.solve_time_offset = 1.0e-7;
+        let (run_data, trace) = simulate_grid_with_trace(&param_file, &grid).unwrap();
+        let schedule = Schedule::from_trace(&grid, &trace);
+        let replayed = replay(&param_file, &grid, &schedule).unwrap();
+        assert_eq!(replayed.tasks.len(), 64 * 8);
+        // Every processor solves 128 tasks one after the other
+        assert!(replayed.time >= 128.0e-5);
+        assert!((replayed.time - run_data.time).abs() < 0.05 * run_data.time);
+        assert_eq!(replayed.get_discrepancies().len(), 64 * 8);
+        // Solving the last task of a processor first deadlocks
+        let mut schedule = schedule;
+        let last = schedule.processors[0].last_mut().unwrap().pop().unwrap();
+        schedule.processors[0].insert(0, vec![last]);
+        assert!(replay(&param_file, &grid, &schedule).is_err());
+        // Several cores per processor are beyond the replay model
+        param_file.cores_per_processor = 4;
+        assert!(replay(&param_file, &grid, &schedule).is_err());
+    }
+}
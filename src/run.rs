@@ -1,55 +1,160 @@
+use std::collections::BTreeMap;
 use std::collections::HashMap;
 use std::fs;
 use std::io;
 use std::path::Path;
+use std::rc::Rc;
 
 use anyhow::anyhow;
 use anyhow::Context;
 use anyhow::Result;
+use ordered_float::OrderedFloat;
 
 use crate::cell::Cell;
 use crate::cell::CellId;
+use crate::cost::CostModel;
 use crate::direction::get_directions;
 use crate::direction::Direction;
+use crate::face::Face;
 use crate::grid::Grid;
 use crate::param_file::ParamFile;
 use crate::run_data::RunData;
 use crate::sweep::Sweep;
+use crate::trace::Trace;
 use crate::vector_3d::Vector3D;
+use crate::vtu::read_vtu_file;
+use crate::vtu::write_vtu_file;
 
 pub fn simulate_grid<U: AsRef<Path>, V: AsRef<Path>>(
     param_file_path: U,
     grid_files: &[V],
 ) -> Result<Vec<RunData>> {
-    let param_file = ParamFile::read(&param_file_path.as_ref())?;
-    let directions = get_directions(param_file.num_directions);
+    let param_file = ParamFile::read(param_file_path.as_ref())?;
     let grids: Result<Vec<_>> = grid_files
         .iter()
-        .map(|file| convert_to_grid(file.as_ref()))
-        .collect();
-    let run_data_list: Vec<_> = grids?
-        .into_iter()
-        .map(|mut grid| run_sweep_on_processors(&param_file, &mut grid, &directions))
+        .map(|file| read_grid_with_params(file.as_ref(), &param_file, None))
         .collect();
-    Ok(run_data_list)
+    simulate_grids(&param_file, &grids?)
+}
+
+pub fn simulate_grids(param_file: &ParamFile, grids: &[Grid]) -> Result<Vec<RunData>> {
+    let cost_model = param_file.get_cost_model();
+    simulate_grids_with_cost_model(param_file, grids, &cost_model)
+}
+
+/// Simulates the grids with a cost model supplied by the caller instead of
+/// the one of the param file.
+pub fn simulate_grids_with_cost_model(
+    param_file: &ParamFile,
+    grids: &[Grid],
+    cost_model: &Rc<dyn CostModel>,
+) -> Result<Vec<RunData>> {
+    let directions = get_directions(param_file.num_directions);
+    grids
+        .iter()
+        .map(|grid| run_sweep_on_processors(param_file, grid, &directions, cost_model))
+        .collect()
 }
 
-fn convert_to_grid(file: &Path) -> Result<Grid> {
-    if let Some(extension) = file.extension() {
-        let ext_str = extension.to_str().unwrap();
-        assert_eq!(ext_str, "dat");
-        return read_grid_file(file).context("While reading file as grid file");
+/// Simulates the grids repeatedly, each time with the next noise seed.
+/// Returns the runs of each grid.
+pub fn simulate_repetitions(
+    param_file: &ParamFile,
+    grids: &[Grid],
+    num_repetitions: usize,
+) -> Result<Vec<Vec<RunData>>> {
+    let mut runs: Vec<Vec<RunData>> = grids.iter().map(|_| vec![]).collect();
+    for repetition in 0..num_repetitions {
+        let mut param_file = param_file.clone();
+        if let Some(noise) = param_file.noise.as_mut() {
+            noise.seed = noise.seed.wrapping_add(repetition as u64);
+        }
+        for (grid_runs, run_data) in runs.iter_mut().zip(simulate_grids(&param_file, grids)?) {
+            grid_runs.push(run_data);
+        }
+    }
+    Ok(runs)
+}
+
+/// Simulates the grid and records a trace of the events of the sweep.
+pub fn simulate_grid_with_trace(param_file: &ParamFile, grid: &Grid) -> Result<(RunData, Trace)> {
+    let directions = get_directions(param_file.num_directions);
+    let cost_model = param_file.get_cost_model();
+    let mut sweep = Sweep::new(
+        param_file,
+        grid,
+        &directions,
+        grid.num_processors(),
+        &cost_model,
+    )?;
+    sweep.enable_trace();
+    let run_data = sweep.run()?;
+    Ok((run_data, sweep.take_trace().unwrap()))
+}
+
+pub fn convert_grids_to_vtu<U: AsRef<Path>, V: AsRef<Path>>(
+    grid_files: &[U],
+    output_folder: V,
+) -> Result<()> {
+    let output_folder = output_folder.as_ref();
+    fs::create_dir_all(output_folder).context("While creating vtu output folder")?;
+    for file in grid_files.iter() {
+        let file = file.as_ref();
+        let grid = read_grid(file, None)?;
+        let stem = file
+            .file_stem()
+            .ok_or_else(|| anyhow!("Invalid grid file name: {:?}", file))?;
+        write_vtu_file(&grid, &output_folder.join(stem).with_extension("vtu"))?;
+    }
+    Ok(())
+}
+
+/// Reads a grid to simulate with the param file, which provides the number
+/// of processors unless it is given and places the grid in its periodic box.
+pub fn read_grid_with_params(
+    file: &Path,
+    param_file: &ParamFile,
+    num_processors: Option<usize>,
+) -> Result<Grid> {
+    let grid = read_grid(file, num_processors.or(param_file.num_processors))?;
+    Ok(match &param_file.periodic_box {
+        Some(periodic_box) => grid.with_periodic_box(periodic_box.clone()),
+        None => grid,
+    })
+}
+
+/// Reads a .dat or .vtu grid. If `num_processors` is given, it overrides the
+/// number of processors derived from the processor numbers in the file.
+pub fn read_grid(file: &Path, num_processors: Option<usize>) -> Result<Grid> {
+    let grid = match file.extension().and_then(|extension| extension.to_str()) {
+        Some("dat") => read_grid_file(file).context("While reading file as grid file")?,
+        Some("vtu") => read_vtu_file(file).context("While reading file as vtu file")?,
+        _ => return Err(anyhow!("Unknown file ending: {:?}", file)),
+    };
+    match num_processors {
+        Some(num_processors) => grid
+            .with_num_processors(num_processors)
+            .context(format!("While reading {:?}", file)),
+        None => Ok(grid),
+    }
+}
+
+pub fn write_grid(grid: &Grid, file: &Path) -> Result<()> {
+    match file.extension().and_then(|extension| extension.to_str()) {
+        Some("dat") => write_grid_file(grid, file).context("While writing grid file"),
+        Some("vtu") => write_vtu_file(grid, file),
+        _ => Err(anyhow!("Unknown file ending: {:?}", file)),
     }
-    Err(anyhow!("Unknown file ending"))
 }
 
 fn run_sweep_on_processors(
     param_file: &ParamFile,
-    grid: &mut Grid,
+    grid: &Grid,
     directions: &[Direction],
-) -> RunData {
-    let num_processors = grid.iter().map(|cell| cell.processor_num).max().unwrap() + 1;
-    let mut sweep = Sweep::new(param_file, grid, directions, num_processors);
+    cost_model: &Rc<dyn CostModel>,
+) -> Result<RunData> {
+    let num_processors = grid.num_processors();
+    let mut sweep = Sweep::new(param_file, grid, directions, num_processors, cost_model)?;
     sweep.run()
 }
 
@@ -59,38 +164,100 @@ fn read_grid_file(grid_file: &Path) -> io::Result<Grid> {
     let mut edges = vec![];
     for line in contents.lines() {
         let (cell, neighbours) = get_cell_and_neighbour_list_from_line(line);
-        for neighbour in neighbours {
-            edges.push((cell.get_id(), neighbour));
+        for (neighbour, face) in neighbours {
+            edges.push((cell.get_id(), neighbour, face));
         }
         cells.push(cell);
     }
     let mut label_to_indices: HashMap<CellId, usize> = HashMap::new();
     cells.sort_by_key(|cell| (cell.processor_num, cell.local_index));
-    for (index, mut cell) in cells.iter_mut().enumerate() {
+    for (index, cell) in cells.iter_mut().enumerate() {
         label_to_indices.insert(cell.get_id(), index);
         cell.global_index = index;
     }
-    let edges: Vec<(usize, usize)> = edges
+    let edges: Vec<(usize, usize, Option<Face>)> = edges
         .into_iter()
-        .map(|(id, neighbour)| (label_to_indices[&id], label_to_indices[&neighbour]))
+        .map(|(id, neighbour, face)| (label_to_indices[&id], label_to_indices[&neighbour], face))
         .collect();
-    Ok(Grid::from_cell_pairs(cells, &edges))
+    Ok(Grid::from_cells_and_faces(cells, edges))
 }
 
-fn get_cell_and_neighbour_list_from_line(line: &str) -> (Cell, Vec<CellId>) {
+fn write_grid_file(grid: &Grid, grid_file: &Path) -> io::Result<()> {
+    let mut neighbours: Vec<Vec<String>> = grid.iter().map(|_| vec![]).collect();
+    for (cell, neighbour, face) in grid.iter_neighbours() {
+        let mut entry = format!("{},{}", neighbour.processor_num, neighbour.local_index);
+        if let Some(area) = face.area {
+            let outward_normal = face.normal.scale(-1.0);
+            entry += &format!(
+                ",{},{},{},{}",
+                outward_normal.x, outward_normal.y, outward_normal.z, area
+            );
+        }
+        neighbours[cell.global_index].push(entry);
+    }
+    let mut contents = String::new();
+    for cell in grid.iter() {
+        contents += &format!(
+            "{} {} {} {} {}",
+            cell.local_index, cell.processor_num, cell.center.x, cell.center.y, cell.center.z
+        );
+        for entry in neighbours[cell.global_index].iter() {
+            contents += " ";
+            contents += entry;
+        }
+        for (name, value) in cell.weights.iter() {
+            contents += &format!(" {}={}", name, value);
+        }
+        contents += "\n";
+    }
+    fs::write(grid_file, contents)
+}
+
+fn get_cell_and_neighbour_list_from_line(line: &str) -> (Cell, Vec<(CellId, Option<Face>)>) {
     let mut split = line.split_ascii_whitespace();
     let index = split.next().unwrap().parse::<usize>().unwrap();
     let processor_num = split.next().unwrap().parse::<usize>().unwrap();
     let x = split.next().unwrap().parse::<f64>().unwrap();
     let y = split.next().unwrap().parse::<f64>().unwrap();
     let z = split.next().unwrap().parse::<f64>().unwrap();
-    let neighbours = split.map(|num| num.parse::<CellId>().unwrap()).collect();
+    let mut neighbours = vec![];
+    let mut weights = BTreeMap::new();
+    for entry in split {
+        match entry.split_once('=') {
+            Some((name, value)) => {
+                weights.insert(
+                    name.to_string(),
+                    OrderedFloat(value.parse::<f64>().unwrap()),
+                );
+            }
+            None => neighbours.push(get_neighbour_and_face(entry)),
+        }
+    }
     let center = Vector3D::new(x, y, z);
     let cell = Cell {
         global_index: 0,
         local_index: index,
         processor_num,
         center,
+        weights,
     };
     (cell, neighbours)
 }
+
+/// A neighbour is given as `processor_num,local_index`, optionally followed by
+/// the outward face normal and the face area: `processor_num,local_index,nx,ny,nz,area`.
+/// Entries of the form `name=value` are per-cell weights instead.
+fn get_neighbour_and_face(entry: &str) -> (CellId, Option<Face>) {
+    let neighbour = entry.parse::<CellId>().unwrap();
+    let face_data: Vec<f64> = entry
+        .split(',')
+        .skip(2)
+        .map(|num| num.parse::<f64>().unwrap())
+        .collect();
+    let face = match face_data[..] {
+        [] => None,
+        [x, y, z, area] => Some(Face::from_outward_normal(&Vector3D::new(x, y, z), area)),
+        _ => panic!("Invalid face data in neighbour entry: {}", entry),
+    };
+    (neighbour, face)
+}
@@ -1,35 +1,62 @@
+use std::collections::BTreeMap;
+
 use crate::processors::Processors;
+use crate::transport::TransportVerification;
 
 pub struct RunData {
     pub time: f64,
     pub num_processors: usize,
+    /// Total number of cores of all processors
+    pub num_cores: usize,
     pub time_spent_communicating: f64,
     pub time_spent_waiting: f64,
+    /// Bytes sent from each processor to each other processor
+    pub bytes_sent: Vec<BTreeMap<usize, f64>>,
+    /// Only present if the param file asks for the transport solve
+    pub transport: Option<TransportVerification>,
+    /// Dependencies across periodic boundaries that use the values of the
+    /// previous iteration, summed over all directions
+    pub num_lagged_dependencies: usize,
 }
 
 impl RunData {
     pub fn new(processors: &Processors) -> Self {
         let time = *processors
             .iter()
-            .map(|processor| processor.time)
+            .map(|processor| processor.get_end_time())
             .max()
             .unwrap();
         let num_processors = processors.len();
-        let time_spent_communicating = processors
+        // Averaged over all cores. Processors without cells wait for the
+        // entire sweep.
+        let core_statistics: Vec<(f64, f64)> = processors
+            .iter()
+            .flat_map(|processor| processor.get_core_statistics(time))
+            .collect();
+        let num_cores = core_statistics.len();
+        let time_spent_communicating = core_statistics
             .iter()
-            .map(|processor| processor.time_spent_communicating)
+            .map(|(communicating, _)| communicating)
             .sum::<f64>()
-            / num_processors as f64;
-        let time_spent_waiting = processors
+            / num_cores as f64;
+        let time_spent_waiting = core_statistics
             .iter()
-            .map(|processor| processor.time_spent_waiting)
+            .map(|(_, waiting)| waiting)
             .sum::<f64>()
-            / num_processors as f64;
+            / num_cores as f64;
+        let bytes_sent = processors
+            .iter()
+            .map(|processor| processor.bytes_sent.clone())
+            .collect();
         RunData {
             time,
             num_processors,
+            num_cores,
             time_spent_communicating,
             time_spent_waiting,
+            bytes_sent,
+            transport: None,
+            num_lagged_dependencies: 0,
         }
     }
 
@@ -38,6 +65,6 @@ impl RunData {
     }
 
     pub fn get_efficiency(&self, reference: &RunData) -> f64 {
-        reference.time / self.time / (self.num_processors as f64 / reference.num_processors as f64)
+        reference.time / self.time / (self.num_cores as f64 / reference.num_cores as f64)
     }
 }
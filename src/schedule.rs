@@ -0,0 +1,216 @@
+use std::fmt::Write as _;
+use std::fs;
+use std::path::Path;
+
+use anyhow::anyhow;
+use anyhow::bail;
+use anyhow::Context;
+use anyhow::Result;
+
+use crate::grid::Grid;
+use crate::trace::EventKind;
+use crate::trace::Trace;
+
+/// The order in which each processor solves its tasks, split into batches
+/// at the points where it communicated.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Schedule {
+    /// The batches of each processor
+    pub processors: Vec<Vec<Vec<ScheduledTask>>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScheduledTask {
+    pub direction: usize,
+    /// Index of the cell among the cells of its processor
+    pub local_index: usize,
+    /// Time at which the task started, if known
+    pub time: Option<f64>,
+}
+
+impl Schedule {
+    /// The schedule a simulated sweep followed. The tasks of a processor are
+    /// ordered by start time, even if several cores solved them.
+    pub fn from_trace(grid: &Grid, trace: &Trace) -> Self {
+        let local_indices: Vec<usize> = grid.iter().map(|cell| cell.local_index).collect();
+        let mut processors = vec![vec![]; grid.num_processors()];
+        let mut events: Vec<_> = trace.events.iter().collect();
+        events.sort_by(|a, b| a.start.total_cmp(&b.start));
+        let mut current_batches: Vec<Vec<ScheduledTask>> = vec![vec![]; grid.num_processors()];
+        for event in events {
+            let batch = &mut current_batches[event.processor];
+            match event.kind {
+                EventKind::Solve {
+                    direction, cell, ..
+                } => batch.push(ScheduledTask {
+                    direction,
+                    local_index: local_indices[cell],
+                    time: Some(event.start),
+                }),
+                EventKind::Communicate => {
+                    if !batch.is_empty() {
+                        processors[event.processor].push(std::mem::take(batch));
+                    }
+                }
+            }
+        }
+        for (batches, batch) in processors.iter_mut().zip(current_batches) {
+            if !batch.is_empty() {
+                batches.push(batch);
+            }
+        }
+        Schedule { processors }
+    }
+
+    /// Writes the schedule as text. Each processor starts with a line
+    /// `processor <num>`, followed by one line per batch with the tasks in
+    /// execution order as `<direction>:<local_index>`, optionally followed by
+    /// `@<time>`.
+    pub fn write(&self, path: &Path, with_times: bool) -> Result<()> {
+        let mut contents = String::new();
+        for (processor_num, batches) in self.processors.iter().enumerate() {
+            writeln!(contents, "processor {}", processor_num).unwrap();
+            for batch in batches.iter() {
+                let tasks: Vec<String> = batch
+                    .iter()
+                    .map(|task| match (with_times, task.time) {
+                        (true, Some(time)) => {
+                            format!("{}:{}@{:e}", task.direction, task.local_index, time)
+                        }
+                        _ => format!("{}:{}", task.direction, task.local_index),
+                    })
+                    .collect();
+                writeln!(contents, "{}", tasks.join(" ")).unwrap();
+            }
+        }
+        fs::write(path, contents).context(format!("While writing schedule to {:?}", path))
+    }
+
+    /// Reads a schedule in the format written by `write`. Empty lines and
+    /// lines starting with `#` are ignored.
+    pub fn read(path: &Path) -> Result<Self> {
+        let contents =
+            fs::read_to_string(path).context(format!("While reading schedule {:?}", path))?;
+        Schedule::parse(&contents).context(format!("While parsing schedule {:?}", path))
+    }
+
+    fn parse(contents: &str) -> Result<Self> {
+        let mut processors: Vec<Vec<Vec<ScheduledTask>>> = vec![];
+        let mut current = None;
+        for (line_num, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if let Some(processor_num) = line.strip_prefix("processor") {
+                let processor_num: usize = processor_num.trim().parse().context(format!(
+                    "Invalid processor number in line {}: {}",
+                    line_num + 1,
+                    line
+                ))?;
+                if processor_num >= processors.len() {
+                    processors.resize(processor_num + 1, vec![]);
+                }
+                current = Some(processor_num);
+                continue;
+            }
+            let processor_num = match current {
+                Some(processor_num) => processor_num,
+                None => bail!(
+                    "Line {} contains tasks before the first processor line",
+                    line_num + 1
+                ),
+            };
+            let batch = line
+                .split_whitespace()
+                .map(ScheduledTask::parse)
+                .collect::<Result<Vec<_>>>()
+                .context(format!("In line {}", line_num + 1))?;
+            processors[processor_num].push(batch);
+        }
+        Ok(Schedule { processors })
+    }
+
+    pub fn num_tasks(&self) -> usize {
+        self.processors
+            .iter()
+            .flat_map(|batches| batches.iter())
+            .map(|batch| batch.len())
+            .sum()
+    }
+}
+
+impl ScheduledTask {
+    /// Parses `<direction>:<local_index>` with an optional `@<time>`.
+    fn parse(text: &str) -> Result<Self> {
+        let (task, time) = match text.split_once('@') {
+            Some((task, time)) => (
+                task,
+                Some(
+                    time.parse()
+                        .context(format!("Invalid time in task `{}`", text))?,
+                ),
+            ),
+            None => (text, None),
+        };
+        let (direction, local_index) = task
+            .split_once(':')
+            .ok_or_else(|| anyhow!("Expected <direction>:<local_index>, found `{}`", text))?;
+        Ok(ScheduledTask {
+            direction: direction
+                .parse()
+                .context(format!("Invalid direction in task `{}`", text))?,
+            local_index: local_index
+                .parse()
+                .context(format!("Invalid local index in task `{}`", text))?,
+            time,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generate::generate_grid;
+    use crate::generate::GridSpec;
+    use crate::param_file::get_test_param_file;
+    use crate::run::simulate_grid_with_trace;
+
+    #[test]
+    fn schedule_follows_batches() {
+        let grid = generate_grid(&GridSpec {
+            size: [4, 4, 4],
+            num_processors: 4,
+            ..GridSpec::default()
+        });
+        let param_file = get_test_param_file("batch_size: 3");
+        let (_, trace) = simulate_grid_with_trace(&param_file, &grid).unwrap();
+        let schedule = Schedule::from_trace(&grid, &trace);
+        assert_eq!(schedule.num_tasks(), 64 * 8);
+        for (processor_num, batches) in schedule.processors.iter().enumerate() {
+            let num_tasks: usize = batches.iter().map(|batch| batch.len()).sum();
+            assert_eq!(
+                num_tasks,
+                grid.get_num_cells_per_processor()[processor_num] * 8
+            );
+            let times: Vec<f64> = batches
+                .iter()
+                .flatten()
+                .map(|task| task.time.unwrap())
+                .collect();
+            assert!(times.windows(2).all(|pair| pair[0] <= pair[1]));
+        }
+        let mut unbatched_param_file = param_file.clone();
+        unbatched_param_file.batch_size = usize::MAX;
+        let (_, trace) = simulate_grid_with_trace(&unbatched_param_file, &grid).unwrap();
+        let unbatched_schedule = Schedule::from_trace(&grid, &trace);
+        let get_num_batches = |schedule: &Schedule| -> usize {
+            schedule
+                .processors
+                .iter()
+                .map(|batches| batches.len())
+                .sum()
+        };
+        assert!(get_num_batches(&schedule) > get_num_batches(&unbatched_schedule));
+    }
+}
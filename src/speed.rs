@@ -0,0 +1,175 @@
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+
+use anyhow::bail;
+use anyhow::Context;
+use anyhow::Result;
+use rand::rngs::StdRng;
+use rand::Rng;
+use rand::SeedableRng;
+use rand_distr::Normal;
+use serde::Deserialize;
+use serde::Serialize;
+
+/// Slowest speed a processor can draw from a distribution, relative to its
+/// mean.
+const MIN_RELATIVE_SPEED: f64 = 0.01;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum SpeedDistribution {
+    /// All processors run at the same speed.
+    #[default]
+    Constant,
+    /// A file containing the speed of each processor, one per line.
+    File(PathBuf),
+    /// Speeds drawn from a normal distribution.
+    Normal { mean: f64, std_dev: f64 },
+    /// Speeds drawn uniformly from an interval.
+    Uniform { min: f64, max: f64 },
+}
+
+/// Relative speeds of the processors. The solve time of a processor is
+/// divided by its speed.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct SpeedParams {
+    #[serde(default)]
+    pub distribution: SpeedDistribution,
+    #[serde(default)]
+    pub seed: u64,
+    /// Processors which run `straggler_slowdown` times slower than their
+    /// speed.
+    #[serde(default)]
+    pub stragglers: Vec<usize>,
+    #[serde(default = "default_straggler_slowdown")]
+    pub straggler_slowdown: f64,
+}
+
+impl SpeedParams {
+    pub fn get_speeds(&self, num_processors: usize) -> Result<Vec<f64>> {
+        if self.straggler_slowdown <= 0.0 {
+            bail!(
+                "straggler_slowdown needs to be positive, got {}",
+                self.straggler_slowdown
+            );
+        }
+        let mut rng = StdRng::seed_from_u64(self.seed);
+        let mut speeds = match &self.distribution {
+            SpeedDistribution::Constant => vec![1.0; num_processors],
+            SpeedDistribution::File(path) => {
+                let speeds = read_speed_file(path)?;
+                if speeds.len() != num_processors {
+                    bail!(
+                        "Expected a speed for each of the {} processors, found {}",
+                        num_processors,
+                        speeds.len()
+                    );
+                }
+                speeds
+            }
+            SpeedDistribution::Normal { mean, std_dev } => {
+                let normal = Normal::new(*mean, *std_dev).context("Invalid speed distribution")?;
+                (0..num_processors)
+                    .map(|_| rng.sample(normal).max(mean * MIN_RELATIVE_SPEED))
+                    .collect()
+            }
+            SpeedDistribution::Uniform { min, max } => {
+                if min > max {
+                    bail!(
+                        "The minimum speed {} exceeds the maximum speed {}",
+                        min,
+                        max
+                    );
+                }
+                (0..num_processors)
+                    .map(|_| rng.gen_range(*min..=*max))
+                    .collect()
+            }
+        };
+        for straggler in self.stragglers.iter() {
+            match speeds.get_mut(*straggler) {
+                Some(speed) => *speed /= self.straggler_slowdown,
+                None => bail!(
+                    "Straggler {} does not exist, there are only {} processors",
+                    straggler,
+                    num_processors
+                ),
+            }
+        }
+        if let Some(speed) = speeds
+            .iter()
+            .find(|speed| **speed <= 0.0 || !speed.is_finite())
+        {
+            bail!("Processor speeds need to be positive, found {}", speed);
+        }
+        Ok(speeds)
+    }
+
+    pub fn has_stragglers(&self) -> bool {
+        !self.stragglers.is_empty() && self.straggler_slowdown != 1.0
+    }
+
+    pub fn without_stragglers(&self) -> Self {
+        SpeedParams {
+            stragglers: vec![],
+            ..self.clone()
+        }
+    }
+}
+
+fn read_speed_file(path: &Path) -> Result<Vec<f64>> {
+    let contents =
+        fs::read_to_string(path).context(format!("While reading speed file {:?}", path))?;
+    contents
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            line.parse::<f64>()
+                .context(format!("Invalid processor speed: {}", line))
+        })
+        .collect()
+}
+
+fn default_straggler_slowdown() -> f64 {
+    1.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stragglers_are_slowed_down() {
+        let params = SpeedParams {
+            distribution: SpeedDistribution::Normal {
+                mean: 1.0,
+                std_dev: 0.1,
+            },
+            seed: 1,
+            stragglers: vec![2],
+            straggler_slowdown: 4.0,
+        };
+        let speeds = params.get_speeds(4).unwrap();
+        let undisturbed = params.without_stragglers().get_speeds(4).unwrap();
+        assert_eq!(speeds[2] * 4.0, undisturbed[2]);
+        assert_eq!(speeds[0], undisturbed[0]);
+        assert!(undisturbed.iter().all(|speed| *speed > 0.5 && *speed < 1.5));
+        let invalid = SpeedParams {
+            straggler_slowdown: 0.0,
+            ..params.clone()
+        };
+        assert!(invalid.get_speeds(4).is_err());
+        let invalid = SpeedParams {
+            stragglers: vec![2, 4],
+            ..params.clone()
+        };
+        assert!(invalid.get_speeds(4).is_err());
+        let invalid = SpeedParams {
+            distribution: SpeedDistribution::Uniform { min: 2.0, max: 1.0 },
+            ..params
+        };
+        assert!(invalid.get_speeds(4).is_err());
+    }
+}
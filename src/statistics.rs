@@ -0,0 +1,63 @@
+/// Two-sided 95% quantiles of the Student t-distribution for 1 to 30 degrees
+/// of freedom
+const T_QUANTILES_95: [f64; 30] = [
+    12.706, 4.303, 3.182, 2.776, 2.571, 2.447, 2.365, 2.306, 2.262, 2.228, 2.201, 2.179, 2.160,
+    2.145, 2.131, 2.120, 2.110, 2.101, 2.093, 2.086, 2.080, 2.074, 2.069, 2.064, 2.060, 2.056,
+    2.052, 2.048, 2.045, 2.042,
+];
+
+/// Quantile of the normal distribution used beyond the table
+const NORMAL_QUANTILE_95: f64 = 1.960;
+
+/// Mean, sample standard deviation and 95% confidence interval of the mean of
+/// a set of samples.
+pub struct Summary {
+    pub mean: f64,
+    pub std_dev: f64,
+    pub confidence_interval: (f64, f64),
+}
+
+impl Summary {
+    pub fn new(samples: &[f64]) -> Self {
+        let num_samples = samples.len();
+        let mean = samples.iter().sum::<f64>() / num_samples as f64;
+        if num_samples < 2 {
+            return Summary {
+                mean,
+                std_dev: 0.0,
+                confidence_interval: (mean, mean),
+            };
+        }
+        let variance = samples
+            .iter()
+            .map(|sample| (sample - mean).powi(2))
+            .sum::<f64>()
+            / (num_samples - 1) as f64;
+        let std_dev = variance.sqrt();
+        let quantile = T_QUANTILES_95
+            .get(num_samples - 2)
+            .copied()
+            .unwrap_or(NORMAL_QUANTILE_95);
+        let half_width = quantile * std_dev / (num_samples as f64).sqrt();
+        Summary {
+            mean,
+            std_dev,
+            confidence_interval: (mean - half_width, mean + half_width),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn summary_of_samples() {
+        let summary = Summary::new(&[1.0, 2.0, 3.0]);
+        assert_eq!(summary.mean, 2.0);
+        assert_eq!(summary.std_dev, 1.0);
+        let (low, high) = summary.confidence_interval;
+        assert!((high - 2.0 - 4.303 / 3.0f64.sqrt()).abs() < 1e-12);
+        assert!((2.0 - low - (high - 2.0)).abs() < 1e-12);
+    }
+}
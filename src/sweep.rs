@@ -1,17 +1,42 @@
+use std::collections::HashSet;
+use std::rc::Rc;
+
+use anyhow::bail;
+use anyhow::Result;
 use generational_arena::Index;
+use ordered_float::OrderedFloat;
 
+use crate::boundary::get_reflective_dependencies;
+use crate::contention::FlowNetwork;
+use crate::contention::Message;
+use crate::cost::CostModel;
 use crate::direction::Direction;
 use crate::grid::DependencyGraph;
 use crate::grid::Grid;
+use crate::param_file::CommunicationMode;
+use crate::param_file::CommunicationThread;
 use crate::param_file::ParamFile;
 use crate::processor::Processor;
 use crate::processors::Processors;
+use crate::protocol::PendingMessage;
+use crate::protocol::Protocol;
 use crate::run_data::RunData;
+use crate::topology::Topology;
+use crate::trace::Arrival;
+use crate::trace::EventKind;
+use crate::trace::Trace;
+use crate::transport::TransportSolution;
 
 pub struct Sweep<'a> {
     graph: DependencyGraph<'a>,
     processors: Processors,
     param_file: ParamFile,
+    topology: Option<Rc<Topology>>,
+    flow_network: Option<FlowNetwork>,
+    protocol: Option<Protocol>,
+    transport: Option<TransportSolution>,
+    trace: Option<Trace>,
+    num_lagged_dependencies: usize,
 }
 
 impl<'a> Sweep<'a> {
@@ -20,50 +45,369 @@ impl<'a> Sweep<'a> {
         grid: &'a Grid,
         directions: &[Direction],
         num_processors: usize,
-    ) -> Self {
-        let graph: DependencyGraph = directions
+        cost_model: &Rc<dyn CostModel>,
+    ) -> Result<Self> {
+        cost_model.validate(grid)?;
+        let num_workers = match param_file.communication_thread {
+            CommunicationThread::Worker => param_file.cores_per_processor,
+            CommunicationThread::Dedicated => param_file.cores_per_processor.saturating_sub(1),
+        };
+        if num_workers == 0 {
+            bail!(
+                "Processors need at least one core to solve tasks, got {} cores with {:?} communication",
+                param_file.cores_per_processor,
+                param_file.communication_thread
+            );
+        }
+        let num_task_groups = param_file.get_num_task_groups();
+        if param_file.solve_transport && num_task_groups > 1 {
+            bail!("The transport solve does not support independent groups");
+        }
+        let lagged: Vec<HashSet<(usize, usize)>> = directions
             .iter()
-            .map(|dir| grid.get_dependency_graph(dir))
+            .map(|dir| grid.get_lagged_dependencies(dir, param_file.face_tolerance))
             .collect();
-        let processors = Processors::new(&graph, num_processors, param_file);
-
-        Sweep {
+        let graph = (0..num_task_groups)
+            .map(|group| get_group_graph(param_file, grid, directions, &lagged, group))
+            .collect::<Result<DependencyGraph>>()?;
+        let num_lagged_dependencies =
+            lagged.iter().map(|lagged| lagged.len()).sum::<usize>() * num_task_groups;
+        let speeds = match &param_file.speeds {
+            Some(params) => params.get_speeds(num_processors)?,
+            None => vec![1.0; num_processors],
+        };
+        let topology = match &param_file.topology {
+            Some(params) => Some(Rc::new(Topology::new(params, num_processors)?)),
+            None => None,
+        };
+        let processors = Processors::new(
+            &graph,
+            num_processors,
+            param_file,
+            &speeds,
+            cost_model,
+            &topology,
+        );
+        let flow_network = topology.as_ref().and_then(|topology| {
+            topology
+                .params()
+                .contention
+                .as_ref()
+                .map(|params| FlowNetwork::new(topology, params))
+        });
+        let protocol = param_file
+            .protocol
+            .as_ref()
+            .map(|params| Protocol::new(params, num_processors));
+        let transport = match param_file.solve_transport {
+            true => Some(TransportSolution::new(
+                grid,
+                directions,
+                param_file.face_tolerance,
+                &lagged,
+            )),
+            false => None,
+        };
+        Ok(Sweep {
             graph,
             processors,
             param_file: param_file.clone(),
+            topology,
+            flow_network,
+            protocol,
+            transport,
+            trace: None,
+            num_lagged_dependencies,
+        })
+    }
+
+    /// Records a trace of all events during the next run.
+    pub fn enable_trace(&mut self) {
+        self.trace = Some(Trace::default());
+    }
+
+    pub fn take_trace(&mut self) -> Option<Trace> {
+        self.trace.take()
+    }
+
+    /// Sends the tasks as one message which leaves the sender at `time`. A
+    /// rendezvous message or an eager message that does not fit into the
+    /// send buffer blocks the current core of the sender instead.
+    fn send_message(
+        &mut self,
+        sender: usize,
+        receiver: usize,
+        tasks: Vec<Index>,
+        time: f64,
+    ) -> Result<()> {
+        let num_bytes = tasks.len() as f64 * self.param_file.get_size_per_task();
+        self.processors[sender].record_message(receiver, num_bytes);
+        let protocol = match &mut self.protocol {
+            Some(protocol) => protocol,
+            None => return self.transmit(sender, receiver, tasks, time),
+        };
+        if protocol.is_rendezvous(num_bytes) {
+            let latency = match &self.topology {
+                Some(topology) => topology.get_latency(sender, receiver),
+                None => 0.0,
+            };
+            protocol.post_request(PendingMessage {
+                sender,
+                core: self.processors[sender].block(),
+                receiver,
+                tasks,
+                num_bytes,
+                time: time + latency,
+            });
+            self.processors
+                .wake_up_at(receiver, OrderedFloat(time + latency));
+        } else if protocol.try_reserve(sender, num_bytes) {
+            self.transmit(sender, receiver, tasks, time)?;
+        } else {
+            protocol.hold(PendingMessage {
+                sender,
+                core: self.processors[sender].block(),
+                receiver,
+                tasks,
+                num_bytes,
+                time,
+            });
+        }
+        Ok(())
+    }
+
+    /// Starts the transfer of a message at `time`. In the asynchronous mode,
+    /// the progress thread of the sender first spends the send time on it in
+    /// the background.
+    fn transmit(
+        &mut self,
+        sender: usize,
+        receiver: usize,
+        tasks: Vec<Index>,
+        time: f64,
+    ) -> Result<()> {
+        let num_bytes = tasks.len() as f64 * self.param_file.get_size_per_task();
+        let time = match self.param_file.communication_mode {
+            CommunicationMode::Blocking => time,
+            CommunicationMode::Asynchronous => {
+                let message =
+                    self.processors[sender].get_message_attributes(sender, receiver, tasks.len());
+                time + self.param_file.get_send_time(&message)?
+            }
+        };
+        match (&self.topology, &mut self.flow_network) {
+            (Some(topology), Some(flow_network)) if !topology.is_same_node(sender, receiver) => {
+                flow_network.start_flow(time, sender, receiver, num_bytes, tasks);
+            }
+            (Some(topology), _) => {
+                let arrival = time + topology.get_transfer_time(sender, receiver, num_bytes);
+                self.deliver(Message {
+                    sender,
+                    receiver,
+                    tasks,
+                    arrival,
+                })?;
+            }
+            (None, _) => self.deliver(Message {
+                sender,
+                receiver,
+                tasks,
+                arrival: time,
+            })?,
+        }
+        Ok(())
+    }
+
+    /// Hands the tasks to the receiver. In the asynchronous mode, they only
+    /// become available once its progress thread has received them.
+    fn deliver(&mut self, message: Message) -> Result<()> {
+        let arrival = match self.param_file.communication_mode {
+            CommunicationMode::Blocking => message.arrival,
+            CommunicationMode::Asynchronous => {
+                let attributes = self.processors[message.receiver].get_message_attributes(
+                    message.sender,
+                    message.receiver,
+                    message.tasks.len(),
+                );
+                message.arrival + self.param_file.get_receive_time(&attributes)?
+            }
+        };
+        if let Some(trace) = &mut self.trace {
+            for task in message.tasks.iter() {
+                let task = &self.graph.get(*task).unwrap().data;
+                trace.arrivals.push(Arrival {
+                    direction: task.direction.index,
+                    cell: task.cell.global_index,
+                    group: task.group,
+                    sender: message.sender,
+                    receiver: message.receiver,
+                    time: arrival,
+                });
+            }
+        }
+        let arrival = OrderedFloat(arrival);
+        for task in message.tasks {
+            let priority = self.graph.get(task).unwrap().data.get_priority();
+            self.processors[message.receiver].add_task_to_receive_queue(
+                task,
+                priority,
+                arrival,
+                message.sender,
+            );
+            self.processors.wake_up_at(message.receiver, arrival);
+        }
+        Ok(())
+    }
+
+    /// After a processor received tasks, frees their space in the send
+    /// buffers and starts the held messages that fit now. Then matches the
+    /// rendezvous requests that arrived at the processor.
+    fn make_progress(&mut self, processor_num: usize, senders: Vec<usize>) -> Result<()> {
+        let protocol = match &mut self.protocol {
+            Some(protocol) => protocol,
+            None => return Ok(()),
+        };
+        let time = *self.processors[processor_num].time();
+        let mut released = vec![];
+        for sender in senders {
+            released.extend(protocol.release(sender, self.param_file.get_size_per_task()));
+        }
+        let start = time + protocol.params().handshake_time;
+        let matched = protocol.match_requests(processor_num, time);
+        // A held message cannot leave before the sender reached the end of
+        // its batch
+        let messages = released
+            .into_iter()
+            .map(|message| {
+                let start = time.max(message.time);
+                (message, start)
+            })
+            .chain(matched.into_iter().map(|message| (message, start)));
+        for (message, start) in messages {
+            self.processors
+                .unblock(message.sender, message.core, OrderedFloat(start));
+            self.transmit(message.sender, message.receiver, message.tasks, start)?;
+        }
+        Ok(())
+    }
+
+    /// Completes the next message in the network if that happens before the
+    /// next processor continues.
+    fn complete_next_flow(&mut self) -> Result<bool> {
+        let flow_network = match &mut self.flow_network {
+            Some(flow_network) => flow_network,
+            None => return Ok(false),
+        };
+        let completion = match flow_network.get_next_event() {
+            Some(completion) => completion,
+            None => return Ok(false),
+        };
+        if let Some(time) = self.processors.get_next_time() {
+            if *time <= completion {
+                return Ok(false);
+            }
+        }
+        for message in flow_network.complete_next() {
+            self.deliver(message)?;
         }
+        Ok(true)
     }
 
-    pub fn run(&mut self) -> RunData {
+    pub fn run(&mut self) -> Result<RunData> {
         let mut num_to_solve = self.graph.len();
         let mut num_solved_without_sending = 0;
         loop {
-            let processor = &mut self.processors.get_next_free();
+            if self.complete_next_flow()? {
+                continue;
+            }
+            let processor = match self.processors.get_next_free() {
+                Some(processor) => processor,
+                None => bail!(
+                    "Deadlock: all processors are asleep with {} tasks left to solve",
+                    num_to_solve
+                ),
+            };
             let processor_num = processor.num;
-            let current_time = processor.time;
-            let task_index = processor.get_next_task();
-            let mut asleep = false;
+            // A core blocked in a send only receives messages
+            let blocked = processor.is_blocked();
+            let task_index = match blocked {
+                true => None,
+                false => processor.get_next_task(),
+            };
             if let Some(task_index) = task_index {
                 processor.start_solving();
-                handle_task_solving(&mut self.graph, processor, task_index);
+                let start = *processor.time();
+                handle_task_solving(&mut self.graph, processor, task_index, &mut self.transport)?;
                 num_to_solve -= 1;
                 num_solved_without_sending += 1;
+                if let Some(trace) = &mut self.trace {
+                    let task = &self.graph.get(task_index).unwrap().data;
+                    trace.record(
+                        processor_num,
+                        processor.current_core(),
+                        start,
+                        *processor.time(),
+                        EventKind::Solve {
+                            direction: task.direction.index,
+                            cell: task.cell.global_index,
+                            group: task.group,
+                        },
+                    );
+                }
             }
             if task_index.is_none() || num_solved_without_sending >= self.param_file.batch_size {
                 processor.stop_solving();
                 num_solved_without_sending = 0;
-                let num_received = processor.receive_tasks();
-                if num_received == 0 && task_index.is_none() {
-                    asleep = true;
+                let communication_start = *processor.time();
+                let senders = processor.receive_tasks()?;
+                if blocked {
                     processor.go_to_sleep();
+                } else if senders.is_empty() && task_index.is_none() {
+                    // Rendezvous requests are only matched while receiving
+                    let next_request = self
+                        .protocol
+                        .as_ref()
+                        .and_then(|protocol| protocol.get_next_request(processor_num))
+                        .map(OrderedFloat);
+                    match processor
+                        .get_next_event()
+                        .into_iter()
+                        .chain(next_request)
+                        .min()
+                    {
+                        Some(arrival) => processor.wait_until(arrival),
+                        None => processor.go_to_sleep(),
+                    }
+                }
+                let mut messages: Vec<(usize, Vec<Index>)> = vec![];
+                let (sent_tasks, departure) = match blocked {
+                    true => (vec![], processor.time()),
+                    false => processor.send_tasks()?,
+                };
+                if let Some(trace) = &mut self.trace {
+                    trace.record(
+                        processor_num,
+                        processor.current_core(),
+                        communication_start,
+                        *processor.time(),
+                        EventKind::Communicate,
+                    );
+                }
+                for (receiver, task) in sent_tasks {
+                    match messages.iter_mut().find(|(r, _)| *r == receiver) {
+                        Some((_, tasks)) => tasks.push(task),
+                        None => messages.push((receiver, vec![task])),
+                    }
                 }
-                let sent_tasks = processor.send_tasks();
-                for (processor_index, task) in sent_tasks {
-                    let priority = self.graph.get(task).unwrap().data.get_priority();
-                    self.processors[processor_index].add_task_to_receive_queue(task, priority);
-                    self.processors.wake_up_at(processor_index, current_time);
+                self.make_progress(processor_num, senders)?;
+                for (receiver, tasks) in messages {
+                    self.send_message(processor_num, receiver, tasks, *departure)?;
+                }
+                if !blocked && self.processors[processor_num].is_blocked() {
+                    self.processors[processor_num].go_to_sleep();
                 }
             }
+            let asleep = !self.processors[processor_num].select_next_core();
             if num_to_solve == 0 {
                 break;
             }
@@ -71,31 +415,236 @@ impl<'a> Sweep<'a> {
                 self.processors.reinsert_with_new_priority(processor_num);
             }
         }
-        RunData::new(&self.processors)
+        let mut run_data = RunData::new(&self.processors);
+        run_data.transport = self.transport.as_ref().map(|transport| transport.verify());
+        run_data.num_lagged_dependencies = self.num_lagged_dependencies;
+        Ok(run_data)
     }
 }
 
+/// The tasks of all directions in one group, including the dependencies
+/// between mirrored directions at reflective boundaries.
+fn get_group_graph<'a>(
+    param_file: &ParamFile,
+    grid: &'a Grid,
+    directions: &[Direction],
+    lagged: &[HashSet<(usize, usize)>],
+    group: usize,
+) -> Result<DependencyGraph<'a>> {
+    let mut graph: DependencyGraph = directions
+        .iter()
+        .zip(lagged.iter())
+        .map(|(dir, lagged)| grid.get_dependency_graph(dir, param_file.face_tolerance, lagged))
+        .collect();
+    for task in graph.iter_mut() {
+        task.group = group;
+    }
+    if !param_file.reflective_boundaries.is_empty() {
+        if param_file.solve_transport {
+            bail!("The transport solve does not support reflective boundaries");
+        }
+        if let Some(periodic_box) = grid.periodic_box() {
+            if let Some(plane) = param_file
+                .reflective_boundaries
+                .iter()
+                .find(|plane| periodic_box.periodic[plane.axis()])
+            {
+                bail!(
+                    "The boundary {:?} is periodic and cannot be reflective",
+                    plane
+                );
+            }
+        }
+        let dependencies =
+            get_reflective_dependencies(grid, directions, &param_file.reflective_boundaries);
+        Grid::add_cross_dependencies(&mut graph, &dependencies);
+        if graph.has_cycle() {
+            bail!(
+                "The reflective boundaries {:?} create a dependency cycle between mirrored directions",
+                param_file.reflective_boundaries
+            );
+        }
+    } else if graph.has_cycle() {
+        bail!("The dependency graph contains cycles. Grids with neighbours across a periodic boundary need a periodic_box in the param file.");
+    }
+    Ok(graph)
+}
+
 fn handle_task_solving<'a>(
     graph: &mut DependencyGraph<'a>,
     processor: &mut Processor,
     task_index: Index,
-) {
+    transport: &mut Option<TransportSolution>,
+) -> Result<()> {
     let task_node = graph.get(task_index).unwrap();
     let edge_indices: Vec<Index> = task_node.edges.iter().map(|edge| edge.index).collect();
     let task = &task_node.data;
-    processor.solve(task);
+    processor.solve(task)?;
+    if let Some(transport) = transport {
+        transport.solve_task(task.direction.index, task.cell.global_index);
+    }
     for dependency_index in edge_indices.iter() {
         let downwind_task_node = graph.get_mut(*dependency_index).unwrap();
         let downwind_task = &mut downwind_task_node.data;
         let priority = downwind_task.get_priority();
         downwind_task.num_upwind -= 1;
+        downwind_task.upwind_finished = downwind_task.upwind_finished.max(processor.time());
         if downwind_task.num_upwind == 0 {
+            // Another core or processor may have finished an upwind task
+            // later than this one
+            let ready = downwind_task.upwind_finished;
             if downwind_task.processor_num == processor.num {
-                processor.add_task_to_queue(downwind_task_node.index, priority);
+                processor.add_task_to_queue(downwind_task_node.index, priority, ready);
             } else {
-                processor
-                    .add_task_to_send_queue(downwind_task_node.index, downwind_task.processor_num);
+                processor.add_task_to_send_queue(
+                    downwind_task_node.index,
+                    downwind_task.processor_num,
+                    ready,
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::generate::generate_grid;
+    use crate::generate::GridKind;
+    use crate::generate::GridSpec;
+    use crate::param_file::get_test_param_file;
+    use crate::run::simulate_grid_with_trace;
+    use crate::run::simulate_grids;
+    use crate::trace::EventKind;
+    use crate::validate::validate;
+
+    #[test]
+    fn cores_share_the_work_of_a_processor() {
+        let grid = generate_grid(&GridSpec {
+            size: [4, 4, 4],
+            num_processors: 2,
+            ..GridSpec::default()
+        });
+        let get_time = |extra: &str| {
+            let param_file = get_test_param_file(extra);
+            simulate_grids(&param_file, std::slice::from_ref(&grid)).unwrap()[0].time
+        };
+        let one_core = get_time("");
+        let four_cores = get_time("cores_per_processor: 4");
+        let dedicated = get_time("cores_per_processor: 4\ncommunication_thread: dedicated");
+        assert!(four_cores < one_core / 2.0);
+        assert!(four_cores * 4.0 > one_core);
+        assert!(dedicated > four_cores && dedicated < one_core);
+    }
+
+    #[test]
+    fn asynchronous_communication_only_charges_posting() {
+        let grid = generate_grid(&GridSpec {
+            size: [4, 4, 4],
+            num_processors: 8,
+            ..GridSpec::default()
+        });
+        let param_file =
+            get_test_param_file("communication_mode: asynchronous\npost_time_offset: 1.0e-7");
+        let run_data = simulate_grids(&param_file, &[grid]).unwrap().remove(0);
+        let num_messages: usize = run_data.bytes_sent.iter().map(|sent| sent.len()).sum();
+        assert!(run_data.time_spent_communicating > 0.0);
+        assert!(run_data.time_spent_communicating * 8.0 < num_messages as f64 * 1.0e-6);
+    }
+
+    #[test]
+    fn rendezvous_messages_delay_senders() {
+        let grid = generate_grid(&GridSpec {
+            size: [4, 4, 4],
+            num_processors: 8,
+            ..GridSpec::default()
+        });
+        let get_time = |extra: &str| {
+            simulate_grids(&get_test_param_file(extra), std::slice::from_ref(&grid)).unwrap()[0]
+                .time
+        };
+        let eager = get_time("protocol:\n  eager_threshold: 1.0e9");
+        let rendezvous = get_time("protocol:\n  eager_threshold: 0.0\n  handshake_time: 1.0e-5");
+        assert_eq!(eager, get_time(""));
+        assert!(rendezvous > eager);
+    }
+
+    #[test]
+    fn blocked_senders_are_woken_up() {
+        let grid = generate_grid(&GridSpec {
+            size: [6, 6, 6],
+            num_processors: 8,
+            ..GridSpec::default()
+        });
+        for extra in [
+            "protocol:\n  eager_threshold: 0.0\nbatch_size: 3",
+            "protocol:\n  eager_threshold: 100.0\n  max_outstanding_bytes: 500.0\nbatch_size: 3",
+            "protocol:\n  eager_threshold: 1.0e9\n  max_outstanding_bytes: 64.0\nbatch_size: 7",
+            "protocol:\n  eager_threshold: 0.0\ncores_per_processor: 2\ncommunication_thread: dedicated",
+            "protocol:\n  eager_threshold: 200.0\n  max_outstanding_bytes: 100.0\nbatch_size: 3\ncores_per_processor: 3",
+        ] {
+            let mut param_file = get_test_param_file(extra);
+            param_file.send_time_per_byte = 1.0e-9;
+            param_file.recv_time_per_byte = 1.0e-9;
+            let (_, trace) = simulate_grid_with_trace(&param_file, &grid).unwrap();
+            if let Err(error) = validate(&grid, &param_file, &trace) {
+                panic!("{:?}: {}", extra, error);
             }
         }
     }
+
+    #[test]
+    fn groups_are_vectorized_or_solved_independently() {
+        let grid = generate_grid(&GridSpec {
+            size: [4, 4, 4],
+            num_processors: 8,
+            ..GridSpec::default()
+        });
+        let simulate = |extra: &str| {
+            let param_file = get_test_param_file(extra);
+            let (run_data, trace) = simulate_grid_with_trace(&param_file, &grid).unwrap();
+            validate(&grid, &param_file, &trace).unwrap();
+            let num_solved = trace
+                .events
+                .iter()
+                .filter(|event| matches!(event.kind, EventKind::Solve { .. }))
+                .count();
+            let num_bytes: f64 = run_data
+                .bytes_sent
+                .iter()
+                .flat_map(|sent| sent.values())
+                .sum();
+            (run_data.time, num_solved, num_bytes)
+        };
+        let (time, num_solved, num_bytes) = simulate("");
+        let (vectorized_time, vectorized_solved, vectorized_bytes) =
+            simulate("num_groups: 4\nsolve_time_per_group: 1.0e-5");
+        assert_eq!(vectorized_solved, num_solved);
+        assert_eq!(vectorized_bytes, 4.0 * num_bytes);
+        assert!(vectorized_time > 3.0 * time);
+        let (independent_time, independent_solved, independent_bytes) =
+            simulate("num_groups: 4\ngroup_mode: independent");
+        assert_eq!(independent_solved, 4 * num_solved);
+        assert_eq!(independent_bytes, 4.0 * num_bytes);
+        assert!(independent_time > time);
+    }
+
+    #[test]
+    fn transport_solution_matches_serial_sweep() {
+        let grid = generate_grid(&GridSpec {
+            kind: GridKind::JitteredCartesian,
+            size: [6, 6, 6],
+            num_processors: 8,
+            ..GridSpec::default()
+        });
+        let param_file = get_test_param_file(
+            "solve_transport: true
+batch_size: 3
+cores_per_processor: 2",
+        );
+        let run_data = simulate_grids(&param_file, &[grid]).unwrap().remove(0);
+        let transport = run_data.transport.unwrap();
+        assert_eq!(transport.num_dependency_violations, 0);
+        assert!(transport.max_relative_error < 1e-12);
+    }
 }
@@ -1,3 +1,5 @@
+use ordered_float::OrderedFloat;
+
 use crate::cell::Cell;
 use crate::direction::Direction;
 use crate::task_priority::TaskPriority;
@@ -6,14 +8,20 @@ use crate::task_priority::TaskPriority;
 pub struct Task<'a> {
     pub cell: &'a Cell,
     pub direction: Direction,
+    /// Group of the task if the groups are solved as independent tasks
+    pub group: usize,
     pub processor_num: usize,
     pub num_upwind: usize,
+    pub num_neighbours: usize,
+    pub num_upwind_faces: usize,
+    /// Latest time at which one of the upwind tasks solved so far finished
+    pub upwind_finished: OrderedFloat<f64>,
 }
 
 impl<'a> Task<'a> {
     pub fn get_priority(&self) -> TaskPriority {
         TaskPriority {
-            priority: self.cell.global_index + self.direction.index * 1000000,
+            priority: (self.group, self.direction.index, self.cell.global_index),
         }
     }
 }
@@ -22,8 +30,8 @@ impl<'a> std::fmt::Debug for Task<'a> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
-            "({},{})@{}",
-            self.cell.global_index, self.direction.index, self.processor_num
+            "({},{},{})@{}",
+            self.cell.global_index, self.direction.index, self.group, self.processor_num
         )
     }
 }
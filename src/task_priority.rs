@@ -1,4 +1,5 @@
 #[derive(Eq, PartialEq, Ord, PartialOrd, Debug)]
 pub struct TaskPriority {
-    pub priority: usize,
+    /// Group, direction and global index of the cell, compared in order
+    pub priority: (usize, usize, usize),
 }
@@ -0,0 +1,407 @@
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+
+use anyhow::bail;
+use anyhow::Context;
+use anyhow::Result;
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::contention::ContentionParams;
+
+/// The network connecting the nodes. The hop count is the number of links
+/// a message traverses between two nodes.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Network {
+    /// Direct network in which each node is connected to its six neighbours
+    /// on a periodic lattice.
+    Torus { dimensions: [usize; 3] },
+    /// Tree of switches in which each switch connects `arity` nodes or
+    /// switches of the level below.
+    FatTree { arity: usize },
+    /// Groups of fully connected routers, with one global link between
+    /// every pair of groups.
+    Dragonfly {
+        nodes_per_router: usize,
+        routers_per_group: usize,
+    },
+}
+
+#[derive(Hash, PartialEq, Eq, Clone, Debug)]
+pub enum Vertex {
+    Node(usize),
+    Switch { level: usize, index: usize },
+    Router(usize),
+}
+
+pub type Link = (Vertex, Vertex);
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Placement {
+    /// Consecutive ranks fill up one node after another.
+    #[default]
+    Block,
+    /// Consecutive ranks are dealt out to the nodes in turn.
+    RoundRobin,
+    /// A file containing the node index of each rank, one per line.
+    File(PathBuf),
+    /// The node index of each rank.
+    Nodes(Vec<usize>),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TopologyParams {
+    pub ranks_per_node: usize,
+    pub network: Network,
+    #[serde(default)]
+    pub placement: Placement,
+    pub intra_node_latency: f64,
+    pub intra_node_time_per_byte: f64,
+    pub inter_node_latency: f64,
+    #[serde(default)]
+    pub latency_per_hop: f64,
+    pub inter_node_time_per_byte: f64,
+    /// Share the bandwidth of links and network interfaces between concurrent
+    /// inter-node messages.
+    #[serde(default)]
+    pub contention: Option<ContentionParams>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Topology {
+    params: TopologyParams,
+    nodes: Vec<usize>,
+}
+
+impl Topology {
+    pub fn new(params: &TopologyParams, num_processors: usize) -> Result<Self> {
+        if params.ranks_per_node == 0 {
+            bail!("ranks_per_node needs to be positive");
+        }
+        let num_nodes = num_processors.div_ceil(params.ranks_per_node);
+        let nodes = match &params.placement {
+            Placement::Block => (0..num_processors)
+                .map(|rank| rank / params.ranks_per_node)
+                .collect(),
+            Placement::RoundRobin => (0..num_processors).map(|rank| rank % num_nodes).collect(),
+            Placement::File(path) => read_placement_file(path)
+                .context(format!("While reading placement file {:?}", path))?,
+            Placement::Nodes(nodes) => nodes.clone(),
+        };
+        if nodes.len() != num_processors {
+            bail!(
+                "Expected a node for each of the {} processors, found {}",
+                num_processors,
+                nodes.len()
+            );
+        }
+        Topology::from_placement(params, nodes)
+    }
+
+    /// Uses the given node of each rank instead of the placement in the params.
+    pub fn from_placement(params: &TopologyParams, nodes: Vec<usize>) -> Result<Self> {
+        params.network.validate()?;
+        let mut num_ranks_on_node = vec![];
+        for node in nodes.iter() {
+            if *node >= num_ranks_on_node.len() {
+                num_ranks_on_node.resize(node + 1, 0);
+            }
+            num_ranks_on_node[*node] += 1;
+        }
+        if let Some(node) = num_ranks_on_node
+            .iter()
+            .position(|num_ranks| *num_ranks > params.ranks_per_node)
+        {
+            bail!(
+                "Node {} has {} ranks, but ranks_per_node is {}",
+                node,
+                num_ranks_on_node[node],
+                params.ranks_per_node
+            );
+        }
+        if let Some(max_nodes) = params.network.num_nodes() {
+            if num_ranks_on_node.len() > max_nodes {
+                bail!(
+                    "The placement requires {} nodes, but the network has only {}",
+                    num_ranks_on_node.len(),
+                    max_nodes
+                );
+            }
+        }
+        Ok(Topology {
+            params: params.clone(),
+            nodes,
+        })
+    }
+
+    pub fn params(&self) -> &TopologyParams {
+        &self.params
+    }
+
+    pub fn get_node(&self, rank: usize) -> usize {
+        self.nodes[rank]
+    }
+
+    pub fn nodes(&self) -> &[usize] {
+        &self.nodes
+    }
+
+    pub fn get_num_hops(&self, rank_0: usize, rank_1: usize) -> usize {
+        self.params
+            .network
+            .get_num_hops(self.nodes[rank_0], self.nodes[rank_1])
+    }
+
+    pub fn get_route(&self, rank_0: usize, rank_1: usize) -> Vec<Link> {
+        self.params
+            .network
+            .get_route(self.nodes[rank_0], self.nodes[rank_1])
+    }
+
+    pub fn is_same_node(&self, rank_0: usize, rank_1: usize) -> bool {
+        self.nodes[rank_0] == self.nodes[rank_1]
+    }
+
+    pub fn get_latency(&self, sender: usize, receiver: usize) -> f64 {
+        if self.is_same_node(sender, receiver) {
+            self.params.intra_node_latency
+        } else {
+            self.params.inter_node_latency
+                + self.get_num_hops(sender, receiver) as f64 * self.params.latency_per_hop
+        }
+    }
+
+    pub fn get_time_per_byte(&self, sender: usize, receiver: usize) -> f64 {
+        if self.is_same_node(sender, receiver) {
+            self.params.intra_node_time_per_byte
+        } else {
+            self.params.inter_node_time_per_byte
+        }
+    }
+
+    /// Time between a message leaving the sender and arriving at the receiver.
+    pub fn get_transfer_time(&self, sender: usize, receiver: usize, num_bytes: f64) -> f64 {
+        self.get_latency(sender, receiver) + num_bytes * self.get_time_per_byte(sender, receiver)
+    }
+}
+
+impl Network {
+    /// Checks that the sizes of the network allow routing between any two
+    /// nodes.
+    pub fn validate(&self) -> Result<()> {
+        match self {
+            Network::Torus { dimensions } => {
+                if dimensions.contains(&0) {
+                    bail!(
+                        "All torus dimensions need to be at least 1, got {:?}",
+                        dimensions
+                    );
+                }
+            }
+            Network::FatTree { arity } => {
+                if *arity < 2 {
+                    bail!(
+                        "The arity of a fat tree needs to be at least 2, got {}",
+                        arity
+                    );
+                }
+            }
+            Network::Dragonfly {
+                nodes_per_router,
+                routers_per_group,
+            } => {
+                if *nodes_per_router == 0 || *routers_per_group == 0 {
+                    bail!(
+                        "nodes_per_router and routers_per_group of a dragonfly need to be positive"
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The maximum number of nodes, if the network is limited.
+    pub fn num_nodes(&self) -> Option<usize> {
+        match self {
+            Network::Torus { dimensions } => Some(dimensions.iter().product()),
+            Network::FatTree { .. } | Network::Dragonfly { .. } => None,
+        }
+    }
+
+    pub fn get_num_hops(&self, node_0: usize, node_1: usize) -> usize {
+        self.get_route(node_0, node_1).len()
+    }
+
+    /// The directed links along the path of a message, using dimension order
+    /// routing on the torus and minimal routing otherwise.
+    pub fn get_route(&self, node_0: usize, node_1: usize) -> Vec<Link> {
+        if node_0 == node_1 {
+            return vec![];
+        }
+        match self {
+            Network::Torus { dimensions } => {
+                let mut route = vec![];
+                let mut current = node_0;
+                let mut stride = 1;
+                for size in dimensions.iter() {
+                    let coord_0 = (node_0 / stride) % size;
+                    let coord_1 = (node_1 / stride) % size;
+                    let forward = (coord_1 + size - coord_0) % size;
+                    let (num_steps, step) = if forward <= size - forward {
+                        (forward, 1)
+                    } else {
+                        (size - forward, size - 1)
+                    };
+                    let mut coord = coord_0;
+                    for _ in 0..num_steps {
+                        let next_coord = (coord + step) % size;
+                        let next = current - coord * stride + next_coord * stride;
+                        route.push((Vertex::Node(current), Vertex::Node(next)));
+                        current = next;
+                        coord = next_coord;
+                    }
+                    stride *= size;
+                }
+                route
+            }
+            Network::FatTree { arity } => {
+                let mut up = vec![Vertex::Node(node_0)];
+                let mut down = vec![Vertex::Node(node_1)];
+                let (mut switch_0, mut switch_1) = (node_0, node_1);
+                let mut level = 0;
+                while switch_0 != switch_1 {
+                    switch_0 /= arity;
+                    switch_1 /= arity;
+                    level += 1;
+                    up.push(Vertex::Switch {
+                        level,
+                        index: switch_0,
+                    });
+                    down.push(Vertex::Switch {
+                        level,
+                        index: switch_1,
+                    });
+                }
+                down.pop();
+                let path: Vec<Vertex> = up.into_iter().chain(down.into_iter().rev()).collect();
+                path.windows(2)
+                    .map(|w| (w[0].clone(), w[1].clone()))
+                    .collect()
+            }
+            Network::Dragonfly {
+                nodes_per_router,
+                routers_per_group,
+            } => {
+                let router_0 = node_0 / nodes_per_router;
+                let router_1 = node_1 / nodes_per_router;
+                let group_0 = router_0 / routers_per_group;
+                let group_1 = router_1 / routers_per_group;
+                let mut path = vec![Vertex::Node(node_0), Vertex::Router(router_0)];
+                if group_0 != group_1 {
+                    // Group g reaches group h via its router number h % routers_per_group
+                    let gateway_0 = group_0 * routers_per_group + group_1 % routers_per_group;
+                    let gateway_1 = group_1 * routers_per_group + group_0 % routers_per_group;
+                    path.push(Vertex::Router(gateway_0));
+                    path.push(Vertex::Router(gateway_1));
+                }
+                path.push(Vertex::Router(router_1));
+                path.push(Vertex::Node(node_1));
+                path.dedup();
+                path.windows(2)
+                    .map(|w| (w[0].clone(), w[1].clone()))
+                    .collect()
+            }
+        }
+    }
+}
+
+fn read_placement_file(path: &Path) -> Result<Vec<usize>> {
+    let contents = fs::read_to_string(path)?;
+    contents
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            line.parse::<usize>()
+                .context(format!("Invalid node index: {}", line))
+        })
+        .collect()
+}
+
+pub fn write_placement_file(nodes: &[usize], path: &Path) -> Result<()> {
+    let contents: String = nodes.iter().map(|node| format!("{}\n", node)).collect();
+    fs::write(path, contents).context(format!("While writing placement file {:?}", path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_params(network: Network, placement: Placement) -> TopologyParams {
+        TopologyParams {
+            ranks_per_node: 2,
+            network,
+            placement,
+            intra_node_latency: 1.0,
+            intra_node_time_per_byte: 0.0,
+            inter_node_latency: 10.0,
+            latency_per_hop: 1.0,
+            inter_node_time_per_byte: 0.0,
+            contention: None,
+        }
+    }
+
+    #[test]
+    fn hop_counts() {
+        let torus = Network::Torus {
+            dimensions: [4, 4, 4],
+        };
+        assert_eq!(torus.get_num_hops(0, 3), 1);
+        assert_eq!(torus.get_num_hops(0, 2 + 4 * 2 + 16 * 3), 5);
+        let fat_tree = Network::FatTree { arity: 4 };
+        assert_eq!(fat_tree.get_num_hops(0, 3), 2);
+        assert_eq!(fat_tree.get_num_hops(0, 4), 4);
+        assert_eq!(fat_tree.get_num_hops(0, 16), 6);
+        let dragonfly = Network::Dragonfly {
+            nodes_per_router: 2,
+            routers_per_group: 4,
+        };
+        assert_eq!(dragonfly.get_num_hops(0, 1), 2);
+        assert_eq!(dragonfly.get_num_hops(0, 7), 3);
+        assert_eq!(dragonfly.get_num_hops(0, 8), 4);
+        assert_eq!(dragonfly.get_num_hops(0, 19), 5);
+        let route = torus.get_route(1, 2 + 4 * 3 + 16 * 2);
+        assert_eq!(route.len(), 4);
+        assert_eq!(route.last().unwrap().1, Vertex::Node(2 + 4 * 3 + 16 * 2));
+    }
+
+    #[test]
+    fn placement() {
+        let network = Network::Torus {
+            dimensions: [2, 1, 1],
+        };
+        let block = Topology::new(&get_params(network.clone(), Placement::Block), 4).unwrap();
+        assert_eq!(block.get_transfer_time(0, 1, 100.0), 1.0);
+        assert_eq!(block.get_transfer_time(1, 2, 100.0), 11.0);
+        let round_robin =
+            Topology::new(&get_params(network.clone(), Placement::RoundRobin), 4).unwrap();
+        assert_eq!(round_robin.get_node(2), 0);
+        assert_eq!(round_robin.get_transfer_time(0, 1, 100.0), 11.0);
+        assert!(Topology::new(&get_params(network, Placement::Block), 5).is_err());
+        for network in [
+            Network::Torus {
+                dimensions: [2, 0, 1],
+            },
+            Network::FatTree { arity: 1 },
+            Network::Dragonfly {
+                nodes_per_router: 0,
+                routers_per_group: 4,
+            },
+        ] {
+            assert!(Topology::new(&get_params(network, Placement::Block), 2).is_err());
+        }
+    }
+}
@@ -0,0 +1,62 @@
+/// What a processor did during a sweep, in the order the simulation
+/// processed it.
+#[derive(Debug, Clone, Default)]
+pub struct Trace {
+    pub events: Vec<Event>,
+    /// Arrivals of tasks at the processors that own them
+    pub arrivals: Vec<Arrival>,
+}
+
+/// An activity of a core of a processor from `start` to `end`.
+#[derive(Debug, Clone)]
+pub struct Event {
+    pub processor: usize,
+    pub core: usize,
+    pub start: f64,
+    pub end: f64,
+    pub kind: EventKind,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum EventKind {
+    /// Solving the task of a cell, given by its global index, in a direction
+    /// and group
+    Solve {
+        direction: usize,
+        cell: usize,
+        group: usize,
+    },
+    /// Receiving and sending messages at the end of a batch, including the
+    /// time spent waiting for messages in between
+    Communicate,
+}
+
+/// A message containing the task of a cell arrived at the receiver.
+#[derive(Debug, Clone)]
+pub struct Arrival {
+    pub direction: usize,
+    pub cell: usize,
+    pub group: usize,
+    pub sender: usize,
+    pub receiver: usize,
+    pub time: f64,
+}
+
+impl Trace {
+    pub(crate) fn record(
+        &mut self,
+        processor: usize,
+        core: usize,
+        start: f64,
+        end: f64,
+        kind: EventKind,
+    ) {
+        self.events.push(Event {
+            processor,
+            core,
+            start,
+            end,
+            kind,
+        });
+    }
+}
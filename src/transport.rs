@@ -0,0 +1,369 @@
+use std::collections::HashSet;
+use std::hint::black_box;
+use std::time::Instant;
+
+use crate::direction::get_directions;
+use crate::direction::Direction;
+use crate::grid::Grid;
+
+/// Weight column of the grid file holding the absorption opacity of a cell.
+pub const OPACITY_COLUMN: &str = "opacity";
+/// Weight column of the grid file holding the isotropic source of a cell.
+pub const SOURCE_COLUMN: &str = "source";
+const DEFAULT_OPACITY: f64 = 1.0;
+const DEFAULT_SOURCE: f64 = 1.0;
+
+/// Batch sizes over which the per-batch overhead is fitted.
+const BATCH_SIZES: &[usize] = &[1, 2, 4, 8, 16, 64, 256, usize::MAX];
+
+/// The upwind faces of every cell of a grid for one direction and an order
+/// in which all upwind cells come first.
+pub(crate) struct UpwindStencil {
+    /// Global indices of the cells in sweep order. Cells on dependency
+    /// cycles come last in arbitrary order.
+    order: Vec<usize>,
+    /// Upwind cells along with the projected area of the shared face,
+    /// excluding lagged dependencies
+    upwind: Vec<Vec<(usize, f64)>>,
+    /// Total projected area of the downwind faces
+    outflow: Vec<f64>,
+}
+
+impl UpwindStencil {
+    /// Upwind cells of the cell along with the projected area of the faces.
+    pub(crate) fn get_upwind(&self, cell: usize) -> &[(usize, f64)] {
+        &self.upwind[cell]
+    }
+
+    pub(crate) fn new(grid: &Grid, direction: &Direction, face_tolerance: f64) -> Self {
+        let lagged = grid.get_lagged_dependencies(direction, face_tolerance);
+        UpwindStencil::with_lagged(grid, direction, face_tolerance, &lagged)
+    }
+
+    /// Builds the stencil from the already known lagged dependencies of the
+    /// direction.
+    pub(crate) fn with_lagged(
+        grid: &Grid,
+        direction: &Direction,
+        face_tolerance: f64,
+        lagged: &HashSet<(usize, usize)>,
+    ) -> Self {
+        let num_cells = grid.iter().count();
+        let mut upwind = vec![vec![]; num_cells];
+        let mut downwind = vec![vec![]; num_cells];
+        let mut outflow = vec![0.0; num_cells];
+        for (upwind_cell, downwind_cell, face) in grid.iter_neighbours() {
+            if face.is_downwind(&direction.vector, face_tolerance) {
+                let projected_area = face.get_projected_area(&direction.vector);
+                // Radiation leaves through lagged faces, but enters the
+                // downwind cell only in the next iteration
+                outflow[upwind_cell.global_index] += projected_area.abs();
+                if lagged.contains(&(upwind_cell.global_index, downwind_cell.global_index)) {
+                    continue;
+                }
+                upwind[downwind_cell.global_index]
+                    .push((upwind_cell.global_index, projected_area.abs()));
+                downwind[upwind_cell.global_index].push(downwind_cell.global_index);
+            }
+        }
+        let mut num_upwind: Vec<usize> = upwind.iter().map(|cells| cells.len()).collect();
+        let mut order: Vec<usize> = (0..num_cells).filter(|i| num_upwind[*i] == 0).collect();
+        let mut position = 0;
+        while position < order.len() {
+            for cell in downwind[order[position]].iter() {
+                num_upwind[*cell] -= 1;
+                if num_upwind[*cell] == 0 {
+                    order.push(*cell);
+                }
+            }
+            position += 1;
+        }
+        order.extend((0..num_cells).filter(|i| num_upwind[*i] > 0));
+        UpwindStencil {
+            order,
+            upwind,
+            outflow,
+        }
+    }
+}
+
+/// A discrete-ordinates transport problem with absorption and an isotropic
+/// source. All cells have unit volume.
+pub(crate) struct TransportProblem {
+    opacity: Vec<f64>,
+    source: Vec<f64>,
+}
+
+impl TransportProblem {
+    /// Reads the opacities and sources from the weight columns of the grid.
+    /// Cells without them get unit opacity and source.
+    pub(crate) fn new(grid: &Grid) -> Self {
+        let get_column = |column: &str, default: f64| {
+            grid.iter()
+                .map(|cell| cell.weights.get(column).map(|w| **w).unwrap_or(default))
+                .collect()
+        };
+        TransportProblem {
+            opacity: get_column(OPACITY_COLUMN, DEFAULT_OPACITY),
+            source: get_column(SOURCE_COLUMN, DEFAULT_SOURCE),
+        }
+    }
+
+    /// The upwind intensity of a cell given the intensities of its upwind
+    /// neighbours: the balance of inflow plus source against absorption plus
+    /// outflow.
+    pub(crate) fn solve_cell(
+        &self,
+        stencil: &UpwindStencil,
+        cell: usize,
+        intensity: &[f64],
+    ) -> f64 {
+        let inflow: f64 = stencil.upwind[cell]
+            .iter()
+            .map(|(upwind_cell, area)| area * intensity[*upwind_cell])
+            .sum();
+        let loss = (self.opacity[cell] + stencil.outflow[cell]).max(f64::MIN_POSITIVE);
+        (self.source[cell] + inflow) / loss
+    }
+
+    /// Solves the cells of a batch in order and returns their intensities,
+    /// as they would be packed into the messages at the end of the batch.
+    fn solve_batch(
+        &self,
+        stencil: &UpwindStencil,
+        cells: &[usize],
+        intensity: &mut [f64],
+    ) -> Vec<f64> {
+        for cell in cells.iter() {
+            intensity[*cell] = self.solve_cell(stencil, *cell, intensity);
+        }
+        cells.iter().map(|cell| intensity[*cell]).collect()
+    }
+
+    /// Sweeps the whole grid in one direction.
+    pub(crate) fn sweep(&self, stencil: &UpwindStencil, intensity: &mut [f64]) {
+        for cell in stencil.order.iter() {
+            intensity[*cell] = self.solve_cell(stencil, *cell, intensity);
+        }
+    }
+}
+
+/// The intensities computed task by task during a simulated sweep.
+pub(crate) struct TransportSolution {
+    problem: TransportProblem,
+    stencils: Vec<UpwindStencil>,
+    /// Intensity of each cell for each direction
+    intensity: Vec<Vec<f64>>,
+    solved: Vec<Vec<bool>>,
+    num_dependency_violations: usize,
+}
+
+/// How the intensities of a simulated sweep compare to a serial sweep.
+#[derive(Debug, Clone)]
+pub struct TransportVerification {
+    pub max_relative_error: f64,
+    /// Number of tasks solved before one of their upwind tasks
+    pub num_dependency_violations: usize,
+}
+
+impl TransportSolution {
+    /// Takes the lagged dependencies of each direction.
+    pub(crate) fn new(
+        grid: &Grid,
+        directions: &[Direction],
+        face_tolerance: f64,
+        lagged: &[HashSet<(usize, usize)>],
+    ) -> Self {
+        let num_cells = grid.iter().count();
+        TransportSolution {
+            problem: TransportProblem::new(grid),
+            stencils: directions
+                .iter()
+                .zip(lagged.iter())
+                .map(|(direction, lagged)| {
+                    UpwindStencil::with_lagged(grid, direction, face_tolerance, lagged)
+                })
+                .collect(),
+            intensity: vec![vec![0.0; num_cells]; directions.len()],
+            solved: vec![vec![false; num_cells]; directions.len()],
+            num_dependency_violations: 0,
+        }
+    }
+
+    /// Computes the intensity of a cell from the current values of its
+    /// upwind neighbours.
+    pub(crate) fn solve_task(&mut self, direction_index: usize, cell: usize) {
+        let stencil = &self.stencils[direction_index];
+        let solved = &mut self.solved[direction_index];
+        if stencil.upwind[cell]
+            .iter()
+            .any(|(upwind_cell, _)| !solved[*upwind_cell])
+        {
+            self.num_dependency_violations += 1;
+        }
+        solved[cell] = true;
+        let intensity = &mut self.intensity[direction_index];
+        intensity[cell] = self.problem.solve_cell(stencil, cell, intensity);
+    }
+
+    /// Compares the intensities against a serial sweep in each direction.
+    pub(crate) fn verify(&self) -> TransportVerification {
+        let mut max_relative_error: f64 = 0.0;
+        for (stencil, intensity) in self.stencils.iter().zip(self.intensity.iter()) {
+            let mut reference = vec![0.0; intensity.len()];
+            self.problem.sweep(stencil, &mut reference);
+            for (value, reference) in intensity.iter().zip(reference.iter()) {
+                let error = (value - reference).abs() / reference.abs().max(f64::MIN_POSITIVE);
+                max_relative_error = max_relative_error.max(error);
+            }
+        }
+        TransportVerification {
+            max_relative_error,
+            num_dependency_violations: self.num_dependency_violations,
+        }
+    }
+}
+
+pub struct SolveTimeMeasurement {
+    pub solve_time_offset: f64,
+    pub solve_time_per_task: f64,
+    pub num_tasks: usize,
+}
+
+/// Times the upwind kernel on the grid for all directions, single-threaded,
+/// with the tasks split into batches of several sizes. A least squares fit of
+/// the total time against the number of batches gives the time per batch
+/// and per task. Each batch size is timed `repetitions` times and the
+/// fastest run counts.
+pub fn measure_solve_time(
+    grid: &Grid,
+    num_directions: usize,
+    face_tolerance: f64,
+    repetitions: usize,
+) -> SolveTimeMeasurement {
+    let problem = TransportProblem::new(grid);
+    let stencils: Vec<UpwindStencil> = get_directions(num_directions)
+        .iter()
+        .map(|direction| UpwindStencil::new(grid, direction, face_tolerance))
+        .collect();
+    let num_cells = grid.iter().count();
+    let num_tasks = num_cells * stencils.len();
+    let mut intensity = vec![0.0; num_cells];
+    // Warm up the caches
+    for stencil in stencils.iter() {
+        problem.sweep(stencil, &mut intensity);
+    }
+    let samples: Vec<(f64, f64)> = BATCH_SIZES
+        .iter()
+        .map(|batch_size| {
+            let batch_size = (*batch_size).clamp(1, num_cells.max(1));
+            let time = (0..repetitions.max(1))
+                .map(|_| {
+                    let start = Instant::now();
+                    for stencil in stencils.iter() {
+                        for batch in stencil.order.chunks(batch_size) {
+                            black_box(problem.solve_batch(stencil, batch, &mut intensity));
+                        }
+                    }
+                    start.elapsed().as_secs_f64()
+                })
+                .fold(f64::INFINITY, f64::min);
+            let num_batches = stencils.len() * num_cells.div_ceil(batch_size);
+            (num_batches as f64, time)
+        })
+        .collect();
+    let (time_without_batches, time_per_batch) = fit_line(&samples);
+    SolveTimeMeasurement {
+        solve_time_offset: time_per_batch.max(0.0),
+        solve_time_per_task: (time_without_batches / num_tasks.max(1) as f64).max(0.0),
+        num_tasks,
+    }
+}
+
+/// Least squares fit of y = a + b x. Returns (a, b).
+fn fit_line(samples: &[(f64, f64)]) -> (f64, f64) {
+    let n = samples.len() as f64;
+    let mean_x = samples.iter().map(|(x, _)| x).sum::<f64>() / n;
+    let mean_y = samples.iter().map(|(_, y)| y).sum::<f64>() / n;
+    let covariance: f64 = samples
+        .iter()
+        .map(|(x, y)| (x - mean_x) * (y - mean_y))
+        .sum();
+    let variance: f64 = samples.iter().map(|(x, _)| (x - mean_x).powi(2)).sum();
+    let slope = match variance > 0.0 {
+        true => covariance / variance,
+        false => 0.0,
+    };
+    (mean_y - slope * mean_x, slope)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generate::generate_grid;
+    use crate::generate::GridSpec;
+    use crate::vector_3d::Vector3D;
+
+    /// With a constant source S and opacity k, a column of unit cells has
+    /// I_i = (S + a I_(i-1)) / (k + a) for the projected face area a, so
+    /// I_i = S / k (1 - (a / (k + a))^(i + 1)). The last cell has no
+    /// downwind face and keeps the inflow: I = (S + a I_(n-2)) / k.
+    #[test]
+    fn column_matches_the_analytic_solution() {
+        let num_cells = 8;
+        let grid = generate_grid(&GridSpec {
+            size: [num_cells, 1, 1],
+            ..GridSpec::default()
+        });
+        let directions = vec![
+            Vector3D::new(1.0, 0.0, 0.0),
+            Vector3D::new(-0.6, 0.8, 0.0),
+            Vector3D::new(0.28, 0.0, -0.96),
+        ]
+        .into_iter()
+        .enumerate()
+        .map(|(index, vector)| Direction { index, vector })
+        .collect::<Vec<_>>();
+        let (source, opacity) = (3.0, 2.0);
+        let lagged = vec![HashSet::new(); directions.len()];
+        let mut solution = TransportSolution::new(&grid, &directions, 0.0, &lagged);
+        solution.problem = TransportProblem {
+            opacity: vec![opacity; num_cells],
+            source: vec![source; num_cells],
+        };
+        for direction in directions.iter() {
+            let order = solution.stencils[direction.index].order.clone();
+            for cell in order {
+                solution.solve_task(direction.index, cell);
+            }
+        }
+        let verification = solution.verify();
+        assert_eq!(verification.num_dependency_violations, 0);
+        assert_eq!(verification.max_relative_error, 0.0);
+        let analytic = |area: f64, i: usize| {
+            let equilibrium = source / opacity;
+            let ratio = area / (opacity + area);
+            match i == num_cells - 1 {
+                true => (source + area * equilibrium * (1.0 - ratio.powi(i as i32))) / opacity,
+                false => equilibrium * (1.0 - ratio.powi(i as i32 + 1)),
+            }
+        };
+        for direction in directions.iter() {
+            let x = *direction.vector.x;
+            let mut cells: Vec<(f64, usize)> = grid
+                .iter()
+                .map(|cell| (*cell.center.x * x.signum(), cell.global_index))
+                .collect();
+            cells.sort_by(|a, b| a.0.total_cmp(&b.0));
+            for (i, (_, cell)) in cells.into_iter().enumerate() {
+                let intensity = solution.intensity[direction.index][cell];
+                assert!((intensity - analytic(x.abs(), i)).abs() < 1e-12);
+            }
+        }
+    }
+
+    #[test]
+    fn fit_line_recovers_a_line() {
+        assert_eq!(fit_line(&[(1.0, 3.0), (2.0, 5.0), (4.0, 9.0)]), (1.0, 2.0));
+    }
+}
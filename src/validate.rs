@@ -0,0 +1,322 @@
+use std::collections::HashMap;
+use std::collections::HashSet;
+
+use anyhow::bail;
+use anyhow::Result;
+
+use crate::boundary::get_reflective_dependencies;
+use crate::direction::get_directions;
+use crate::grid::Grid;
+use crate::param_file::ParamFile;
+use crate::trace::EventKind;
+use crate::trace::Trace;
+use crate::transport::UpwindStencil;
+
+/// Absolute tolerance for comparing times
+const TIME_TOLERANCE: f64 = 1.0e-12;
+
+/// Checks the trace of a sweep over the grid against the dependencies
+/// derived from the grid itself:
+/// - every task of every group is solved exactly once, on the processor
+///   owning its cell,
+/// - no task starts before all of its upwind tasks finished, including the
+///   tasks of the mirrored directions at reflective boundaries,
+/// - a task that was sent to its processor only starts after its message
+///   arrived, which in turn happens after all upwind tasks of the sender
+///   finished; tasks whose upwind tasks are all remote have to be sent,
+/// - the events of a core do not overlap and their times never decrease.
+pub fn validate(grid: &Grid, param_file: &ParamFile, trace: &Trace) -> Result<()> {
+    let owners: Vec<usize> = grid.iter().map(|cell| cell.processor_num).collect();
+    let directions = get_directions(param_file.num_directions);
+    let mut solves: HashMap<(usize, usize, usize), (usize, f64, f64)> = HashMap::new();
+    let mut last_events: HashMap<(usize, usize), (f64, f64)> = HashMap::new();
+    for event in trace.events.iter() {
+        if event.end < event.start - TIME_TOLERANCE {
+            bail!("Event ends before it starts: {:?}", event);
+        }
+        if let Some((start, end)) = last_events.get(&(event.processor, event.core)) {
+            if event.start < *start - TIME_TOLERANCE {
+                bail!(
+                    "Time decreases on core {} of processor {} from {:e} to {:e}: {:?}",
+                    event.core,
+                    event.processor,
+                    start,
+                    event.start,
+                    event
+                );
+            }
+            if event.start < *end - TIME_TOLERANCE {
+                bail!(
+                    "Event overlaps the previous event on its core, which ends at {:e}: {:?}",
+                    end,
+                    event
+                );
+            }
+        }
+        last_events.insert((event.processor, event.core), (event.start, event.end));
+        if let EventKind::Solve {
+            direction,
+            cell,
+            group,
+        } = event.kind
+        {
+            if owners[cell] != event.processor {
+                bail!(
+                    "Task (direction {}, cell {}, group {}) of processor {} solved on processor {}",
+                    direction,
+                    cell,
+                    group,
+                    owners[cell],
+                    event.processor
+                );
+            }
+            if solves
+                .insert(
+                    (group, direction, cell),
+                    (event.processor, event.start, event.end),
+                )
+                .is_some()
+            {
+                bail!(
+                    "Task (direction {}, cell {}, group {}) solved twice",
+                    direction,
+                    cell,
+                    group
+                );
+            }
+        }
+    }
+    let arrivals: HashMap<(usize, usize, usize), (usize, f64)> = trace
+        .arrivals
+        .iter()
+        .map(|arrival| {
+            (
+                (arrival.group, arrival.direction, arrival.cell),
+                (arrival.sender, arrival.time),
+            )
+        })
+        .collect();
+    let reflective_dependencies =
+        get_reflective_dependencies(grid, &directions, &param_file.reflective_boundaries);
+    // Tasks which depend on a task of their own cell need not be sent
+    let reflected: HashSet<(usize, usize)> = reflective_dependencies
+        .iter()
+        .map(|(_, downwind)| *downwind)
+        .collect();
+    let stencils: Vec<UpwindStencil> = directions
+        .iter()
+        .map(|direction| UpwindStencil::new(grid, direction, param_file.face_tolerance))
+        .collect();
+    for group in 0..param_file.get_num_task_groups() {
+        for (direction, stencil) in directions.iter().zip(stencils.iter()) {
+            for cell in 0..owners.len() {
+                let (_, start, _) = match solves.get(&(group, direction.index, cell)) {
+                    Some(solve) => *solve,
+                    None => bail!(
+                        "Task (direction {}, cell {}, group {}) never solved",
+                        direction.index,
+                        cell,
+                        group
+                    ),
+                };
+                let upwind = stencil.get_upwind(cell);
+                for (upwind_cell, _) in upwind {
+                    let (_, _, end) = solves[&(group, direction.index, *upwind_cell)];
+                    if start < end - TIME_TOLERANCE {
+                        bail!(
+                            "Task (direction {}, cell {}, group {}) starts at {:e} before its upwind task (cell {}) finishes at {:e}",
+                            direction.index,
+                            cell,
+                            group,
+                            start,
+                            upwind_cell,
+                            end
+                        );
+                    }
+                }
+                let (sender, arrival) = match arrivals.get(&(group, direction.index, cell)) {
+                    Some(arrival) => *arrival,
+                    None if !upwind.is_empty()
+                        && !reflected.contains(&(direction.index, cell))
+                        && upwind
+                            .iter()
+                            .all(|(upwind_cell, _)| owners[*upwind_cell] != owners[cell]) =>
+                    {
+                        bail!(
+                            "Task (direction {}, cell {}, group {}) solved without receiving it, although all of its upwind tasks are remote",
+                            direction.index,
+                            cell,
+                            group
+                        )
+                    }
+                    None => continue,
+                };
+                let sender_ends: Vec<f64> = upwind
+                    .iter()
+                    .map(|(upwind_cell, _)| solves[&(group, direction.index, *upwind_cell)])
+                    .filter(|(processor, _, _)| *processor == sender)
+                    .map(|(_, _, end)| end)
+                    .collect();
+                if sender == owners[cell] || sender_ends.is_empty() {
+                    bail!(
+                        "Task (direction {}, cell {}, group {}) received from processor {}, which owns none of its upwind tasks",
+                        direction.index,
+                        cell,
+                        group,
+                        sender
+                    );
+                }
+                let end = sender_ends.into_iter().fold(f64::MIN, f64::max);
+                if arrival < end - TIME_TOLERANCE {
+                    bail!(
+                        "Message with task (direction {}, cell {}, group {}) from processor {} arrives at {:e} before its upwind task finishes at {:e}",
+                        direction.index, cell, group, sender, arrival, end
+                    );
+                }
+                if start < arrival - TIME_TOLERANCE {
+                    bail!(
+                        "Task (direction {}, cell {}, group {}) starts at {:e} before its message arrives at {:e}",
+                        direction.index, cell, group, start, arrival
+                    );
+                }
+            }
+        }
+        for ((upwind_direction, cell), (direction, _)) in reflective_dependencies.iter() {
+            let (_, start, _) = solves[&(group, *direction, *cell)];
+            let (_, _, end) = solves[&(group, *upwind_direction, *cell)];
+            if start < end - TIME_TOLERANCE {
+                bail!(
+                    "Task (direction {}, cell {}, group {}) starts at {:e} before the reflected task (direction {}) finishes at {:e}",
+                    direction,
+                    cell,
+                    group,
+                    start,
+                    upwind_direction,
+                    end
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+    use crate::generate::generate_grid;
+    use crate::generate::GridKind;
+    use crate::generate::GridSpec;
+    use crate::param_file::get_test_param_file;
+    use crate::run::simulate_grid_with_trace;
+
+    #[test]
+    fn sweeps_respect_dependencies() {
+        let grid = generate_grid(&GridSpec {
+            kind: GridKind::JitteredCartesian,
+            size: [6, 6, 6],
+            num_processors: 8,
+            ..GridSpec::default()
+        });
+        for extra in [
+            "",
+            "batch_size: 3",
+            "cores_per_processor: 3",
+            "cores_per_processor: 3\ncommunication_thread: dedicated",
+            "communication_mode: asynchronous\npost_time_offset: 1.0e-7",
+            "protocol:\n  eager_threshold: 500.0\n  max_outstanding_bytes: 2000.0",
+            "noise:\n  jitter: 0.2\n  seed: 3",
+            "topology:
+  ranks_per_node: 2
+  network:
+    kind: torus
+    dimensions: [2, 2, 1]
+  intra_node_latency: 1.0e-7
+  intra_node_time_per_byte: 1.0e-10
+  inter_node_latency: 1.0e-6
+  inter_node_time_per_byte: 1.0e-9
+  contention:
+    link_time_per_byte: 1.0e-8
+    injection_time_per_byte: 1.0e-8",
+        ] {
+            let mut param_file = get_test_param_file(extra);
+            param_file.send_time_per_byte = 1.0e-9;
+            param_file.recv_time_per_byte = 1.0e-9;
+            let (_, trace) = simulate_grid_with_trace(&param_file, &grid).unwrap();
+            if let Err(error) = validate(&grid, &param_file, &trace) {
+                panic!("{:?}: {}", extra, error);
+            }
+        }
+    }
+
+    #[test]
+    fn solving_a_task_before_its_upwind_task_is_caught() {
+        let grid = generate_grid(&GridSpec {
+            kind: GridKind::JitteredCartesian,
+            size: [6, 6, 6],
+            num_processors: 8,
+            ..GridSpec::default()
+        });
+        let param_file = get_test_param_file("");
+        let (_, mut trace) = simulate_grid_with_trace(&param_file, &grid).unwrap();
+        let owners: Vec<usize> = grid.iter().map(|cell| cell.processor_num).collect();
+        let stencils: Vec<UpwindStencil> = get_directions(param_file.num_directions)
+            .iter()
+            .map(|direction| UpwindStencil::new(&grid, direction, param_file.face_tolerance))
+            .collect();
+        let is_upwind = |direction: usize, upwind_cell: usize, cell: usize| {
+            stencils[direction]
+                .get_upwind(cell)
+                .iter()
+                .any(|(upwind, _)| *upwind == upwind_cell)
+        };
+        // Swapping two consecutive solves of a core keeps the times of the
+        // core in order. Tasks downwind of the first one on other
+        // processors would see their message arrive too early, so only
+        // tasks without remote downwind tasks qualify.
+        let mut solves_by_core: BTreeMap<(usize, usize), Vec<usize>> = BTreeMap::new();
+        for (index, event) in trace.events.iter().enumerate() {
+            if matches!(event.kind, EventKind::Solve { .. }) {
+                solves_by_core
+                    .entry((event.processor, event.core))
+                    .or_default()
+                    .push(index);
+            }
+        }
+        let (first, second, upwind_cell, cell) = solves_by_core
+            .values()
+            .flat_map(|indices| indices.windows(2))
+            .find_map(
+                |pair| match (&trace.events[pair[0]].kind, &trace.events[pair[1]].kind) {
+                    (
+                        EventKind::Solve {
+                            direction: upwind_direction,
+                            cell: upwind_cell,
+                            ..
+                        },
+                        EventKind::Solve {
+                            direction, cell, ..
+                        },
+                    ) if upwind_direction == direction
+                        && is_upwind(*direction, *upwind_cell, *cell)
+                        && (0..owners.len())
+                            .filter(|downwind| is_upwind(*direction, *upwind_cell, *downwind))
+                            .all(|downwind| owners[downwind] == owners[*upwind_cell]) =>
+                    {
+                        Some((pair[0], pair[1], *upwind_cell, *cell))
+                    }
+                    _ => None,
+                },
+            )
+            .unwrap();
+        let kind = trace.events[first].kind.clone();
+        trace.events[first].kind = trace.events[second].kind.clone();
+        trace.events[second].kind = kind;
+        let error = validate(&grid, &param_file, &trace)
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains(&format!("cell {}, group 0) starts at", cell)));
+        assert!(error.contains(&format!("before its upwind task (cell {})", upwind_cell)));
+    }
+}
@@ -35,9 +35,41 @@ impl Vector3D {
         }
     }
 
+    pub fn add(&self, other: &Vector3D) -> Vector3D {
+        Vector3D {
+            x: self.x + other.x,
+            y: self.y + other.y,
+            z: self.z + other.z,
+        }
+    }
+
+    pub fn scale(&self, factor: f64) -> Vector3D {
+        Vector3D::new(*self.x * factor, *self.y * factor, *self.z * factor)
+    }
+
     pub fn dot(&self, other: &Vector3D) -> OrderedFloat<f64> {
         self.x * other.x + self.y * other.y + self.z * other.z
     }
+
+    pub fn cross(&self, other: &Vector3D) -> Vector3D {
+        Vector3D {
+            x: self.y * other.z - self.z * other.y,
+            y: self.z * other.x - self.x * other.z,
+            z: self.x * other.y - self.y * other.x,
+        }
+    }
+
+    pub fn norm(&self) -> f64 {
+        self.dot(self).sqrt()
+    }
+}
+
+impl DivAssign<f64> for Vector3D {
+    fn div_assign(&mut self, rhs: f64) {
+        self.x /= rhs;
+        self.y /= rhs;
+        self.z /= rhs;
+    }
 }
 
 impl DivAssign<f64> for &mut Vector3D {
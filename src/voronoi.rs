@@ -0,0 +1,548 @@
+use std::collections::HashMap;
+use std::f64::consts::PI;
+
+use clap::ArgEnum;
+use rand::rngs::StdRng;
+use rand::Rng;
+use rand::SeedableRng;
+
+use crate::delaunay::Triangulation;
+use crate::face::Face;
+use crate::vector_3d::Vector3D;
+
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq)]
+pub enum PointDistribution {
+    Random,
+    PoissonDisk,
+    Clustered,
+}
+
+#[derive(Clone, Debug)]
+pub struct VoronoiSpec {
+    pub distribution: PointDistribution,
+    pub lloyd_iterations: usize,
+    pub periodic: bool,
+    pub num_clusters: usize,
+    /// Standard deviation of the points around their cluster center, in units of the mean point spacing.
+    pub cluster_width: f64,
+}
+
+impl Default for VoronoiSpec {
+    fn default() -> Self {
+        VoronoiSpec {
+            distribution: PointDistribution::Random,
+            lloyd_iterations: 0,
+            periodic: false,
+            num_clusters: 8,
+            cluster_width: 2.0,
+        }
+    }
+}
+
+/// The Voronoi tessellation of a set of points in a box, clipped at the
+/// box boundary or wrapped around it for periodic boxes.
+pub struct Tessellation {
+    pub points: Vec<Vector3D>,
+    /// Faces between neighbouring cells. Every neighbouring pair appears in both orders.
+    pub faces: Vec<(usize, usize, Face)>,
+    pub volumes: Vec<f64>,
+    pub centroids: Vec<Vector3D>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum GhostKind {
+    Mirror,
+    PeriodicImage,
+}
+
+struct Ghost {
+    position: Vector3D,
+    origin: usize,
+    kind: GhostKind,
+}
+
+/// Generates the points (with unit mean spacing) in a box with the given
+/// side lengths and returns their Voronoi tessellation.
+pub fn generate_tessellation(spec: &VoronoiSpec, box_size: &Vector3D, seed: u64) -> Tessellation {
+    let volume = *box_size.x * *box_size.y * *box_size.z;
+    let num_points = volume.round() as usize;
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut points = match spec.distribution {
+        PointDistribution::Random => get_random_points(&mut rng, num_points, box_size),
+        PointDistribution::PoissonDisk => {
+            get_poisson_disk_points(&mut rng, num_points, box_size, spec.periodic)
+        }
+        PointDistribution::Clustered => get_clustered_points(
+            &mut rng,
+            num_points,
+            box_size,
+            spec.num_clusters,
+            spec.cluster_width,
+            spec.periodic,
+        ),
+    };
+    for _ in 0..spec.lloyd_iterations {
+        let tessellation = tessellate(points, box_size, spec.periodic);
+        points = tessellation
+            .centroids
+            .into_iter()
+            .map(|centroid| {
+                if spec.periodic {
+                    wrap(&centroid, box_size)
+                } else {
+                    centroid
+                }
+            })
+            .collect();
+    }
+    tessellate(points, box_size, spec.periodic)
+}
+
+pub fn tessellate(points: Vec<Vector3D>, box_size: &Vector3D, periodic: bool) -> Tessellation {
+    let largest_side = *box_size.x.max(box_size.y.max(box_size.z));
+    let spacing = (*box_size.x * *box_size.y * *box_size.z / points.len() as f64).cbrt();
+    let mut margin = (2.0 * spacing).min(largest_side);
+    loop {
+        let ghosts = get_ghosts(&points, box_size, periodic, margin);
+        let mut all_points = points.clone();
+        all_points.extend(ghosts.iter().map(|ghost| ghost.position.clone()));
+        let min = Vector3D::new(-margin, -margin, -margin);
+        let max = box_size.add(&Vector3D::new(margin, margin, margin));
+        let triangulation = Triangulation::new(&all_points, &min, &max);
+        let builder = TessellationBuilder {
+            triangulation: &triangulation,
+            num_points: points.len(),
+            ghosts: &ghosts,
+        };
+        if margin >= largest_side || builder.ghosts_sufficient(&min, &max) {
+            return builder.build(spacing);
+        }
+        margin = (2.0 * margin).min(largest_side);
+    }
+}
+
+/// Mirror images of the points across the box faces make the Voronoi cells of
+/// the original points end exactly at the box boundary. For periodic boxes,
+/// periodic images are used instead. Only points within the margin of the
+/// box are included.
+fn get_ghosts(points: &[Vector3D], box_size: &Vector3D, periodic: bool, margin: f64) -> Vec<Ghost> {
+    let size = [*box_size.x, *box_size.y, *box_size.z];
+    let in_extended_box = |position: &[f64; 3]| {
+        (0..3).all(|axis| position[axis] >= -margin && position[axis] <= size[axis] + margin)
+    };
+    let mut ghosts = vec![];
+    for (origin, point) in points.iter().enumerate() {
+        let point = [*point.x, *point.y, *point.z];
+        if periodic {
+            for offset in 0..27 {
+                let shift = [offset % 3, offset / 3 % 3, offset / 9];
+                if shift == [1, 1, 1] {
+                    continue;
+                }
+                let mut position = point;
+                for axis in 0..3 {
+                    position[axis] += (shift[axis] as f64 - 1.0) * size[axis];
+                }
+                if in_extended_box(&position) {
+                    ghosts.push(Ghost {
+                        position: Vector3D::new(position[0], position[1], position[2]),
+                        origin,
+                        kind: GhostKind::PeriodicImage,
+                    });
+                }
+            }
+        } else {
+            for axis in 0..3 {
+                for wall in [0.0, size[axis]] {
+                    let mut position = point;
+                    position[axis] = 2.0 * wall - point[axis];
+                    if position[axis] != point[axis] && in_extended_box(&position) {
+                        ghosts.push(Ghost {
+                            position: Vector3D::new(position[0], position[1], position[2]),
+                            origin,
+                            kind: GhostKind::Mirror,
+                        });
+                    }
+                }
+            }
+        }
+    }
+    ghosts
+}
+
+struct TessellationBuilder<'a> {
+    triangulation: &'a Triangulation,
+    num_points: usize,
+    ghosts: &'a [Ghost],
+}
+
+impl<'a> TessellationBuilder<'a> {
+    fn is_original(&self, vertex: usize) -> bool {
+        vertex < self.num_points && !self.triangulation.duplicates.contains_key(&vertex)
+    }
+
+    fn get_ghost(&self, vertex: usize) -> Option<&Ghost> {
+        if vertex < self.num_points {
+            None
+        } else {
+            self.ghosts.get(vertex - self.num_points)
+        }
+    }
+
+    fn get_relevant_tetras(&self) -> impl Iterator<Item = [usize; 4]> + '_ {
+        self.triangulation
+            .iter_tetras()
+            .filter(move |tetra| tetra.iter().any(|vertex| self.is_original(*vertex)))
+    }
+
+    /// The tessellation is exact if the circumsphere of every tetrahedron
+    /// touching an original point lies within the region in which all
+    /// ghosts are present.
+    fn ghosts_sufficient(&self, min: &Vector3D, max: &Vector3D) -> bool {
+        let tolerance = 1e-9 * max.sub(min).norm();
+        self.get_relevant_tetras().all(|tetra| {
+            if tetra
+                .iter()
+                .any(|vertex| self.triangulation.is_super_vertex(*vertex))
+            {
+                return false;
+            }
+            let center = self.triangulation.get_circumcenter(tetra);
+            let radius = center
+                .sub(&self.triangulation.get_position(tetra[0]))
+                .norm();
+            let low = center.sub(min);
+            let high = max.sub(&center);
+            [low.x, low.y, low.z, high.x, high.y, high.z]
+                .iter()
+                .all(|distance| **distance + tolerance >= radius)
+        })
+    }
+
+    fn build(&self, spacing: f64) -> Tessellation {
+        let mut circumcenters = vec![];
+        let mut tetras_by_edge: HashMap<(usize, usize), Vec<usize>> = HashMap::new();
+        for tetra in self.get_relevant_tetras() {
+            circumcenters.push(self.triangulation.get_circumcenter(tetra));
+            for i in 0..4 {
+                for j in i + 1..4 {
+                    let (a, b) = (tetra[i].min(tetra[j]), tetra[i].max(tetra[j]));
+                    if self.is_original(a) || self.is_original(b) {
+                        tetras_by_edge
+                            .entry((a, b))
+                            .or_default()
+                            .push(circumcenters.len() - 1);
+                    }
+                }
+            }
+        }
+        let points: Vec<Vector3D> = (0..self.num_points)
+            .map(|vertex| self.triangulation.get_position(vertex))
+            .collect();
+        let mut volumes = vec![0.0; self.num_points];
+        let mut weighted_centroids = vec![Vector3D::new(0.0, 0.0, 0.0); self.num_points];
+        let mut faces: HashMap<(usize, usize), Face> = HashMap::new();
+        let min_area = 1e-10 * spacing * spacing;
+        for ((a, b), tetras) in tetras_by_edge.into_iter() {
+            let position_a = self.triangulation.get_position(a);
+            let position_b = self.triangulation.get_position(b);
+            let polygon = get_ordered_polygon(
+                tetras.iter().map(|tetra| &circumcenters[*tetra]),
+                &position_b.sub(&position_a),
+            );
+            let area = get_polygon_area(&polygon);
+            for (vertex, position, other, other_position) in [
+                (a, &position_a, b, &position_b),
+                (b, &position_b, a, &position_a),
+            ] {
+                if !self.is_original(vertex) {
+                    continue;
+                }
+                let (volume, centroid) = get_pyramid_volume_and_centroid(position, &polygon);
+                volumes[vertex] += volume;
+                weighted_centroids[vertex] += &centroid.scale(volume);
+                let neighbour = match self.get_ghost(other) {
+                    Some(ghost) if ghost.kind == GhostKind::Mirror => continue,
+                    Some(ghost) => ghost.origin,
+                    None => *self.triangulation.duplicates.get(&other).unwrap_or(&other),
+                };
+                if neighbour != vertex && area > min_area {
+                    let outward_normal = other_position.sub(position);
+                    faces
+                        .entry((vertex, neighbour))
+                        .or_insert_with(|| Face::from_outward_normal(&outward_normal, area));
+                }
+            }
+        }
+        let centroids = weighted_centroids
+            .iter()
+            .zip(volumes.iter())
+            .zip(points.iter())
+            .map(|((weighted_centroid, volume), point)| {
+                if *volume > 0.0 {
+                    weighted_centroid.scale(1.0 / volume)
+                } else {
+                    point.clone()
+                }
+            })
+            .collect();
+        let mut faces: Vec<(usize, usize, Face)> = faces
+            .into_iter()
+            .map(|((i0, i1), face)| (i0, i1, face))
+            .collect();
+        faces.sort_by_key(|(i0, i1, _)| (*i0, *i1));
+        Tessellation {
+            points,
+            faces,
+            volumes,
+            centroids,
+        }
+    }
+}
+
+/// Orders the corners of a convex polygon in the plane perpendicular to the axis.
+fn get_ordered_polygon<'a>(
+    corners: impl Iterator<Item = &'a Vector3D>,
+    axis: &Vector3D,
+) -> Vec<Vector3D> {
+    let corners: Vec<Vector3D> = corners.cloned().collect();
+    let axis = axis.scale(1.0 / axis.norm());
+    let helper = if axis.x.abs() < 0.9 {
+        Vector3D::new(1.0, 0.0, 0.0)
+    } else {
+        Vector3D::new(0.0, 1.0, 0.0)
+    };
+    let u = axis.cross(&helper);
+    let u = u.scale(1.0 / u.norm());
+    let v = axis.cross(&u);
+    let center = get_mean(&corners);
+    let mut with_angle: Vec<(f64, Vector3D)> = corners
+        .into_iter()
+        .map(|corner| {
+            let relative = corner.sub(&center);
+            (relative.dot(&v).atan2(*relative.dot(&u)), corner)
+        })
+        .collect();
+    with_angle.sort_by(|(angle_0, _), (angle_1, _)| angle_0.partial_cmp(angle_1).unwrap());
+    with_angle.into_iter().map(|(_, corner)| corner).collect()
+}
+
+fn get_mean(points: &[Vector3D]) -> Vector3D {
+    let mut mean = Vector3D::new(0.0, 0.0, 0.0);
+    for point in points.iter() {
+        mean += point;
+    }
+    mean.scale(1.0 / points.len() as f64)
+}
+
+fn get_polygon_area(polygon: &[Vector3D]) -> f64 {
+    let center = get_mean(polygon);
+    let mut area_vector = Vector3D::new(0.0, 0.0, 0.0);
+    for (i, corner) in polygon.iter().enumerate() {
+        let next = &polygon[(i + 1) % polygon.len()];
+        area_vector += &corner.sub(&center).cross(&next.sub(&center));
+    }
+    area_vector.norm() / 2.0
+}
+
+fn get_pyramid_volume_and_centroid(apex: &Vector3D, polygon: &[Vector3D]) -> (f64, Vector3D) {
+    let center = get_mean(polygon);
+    let mut volume = 0.0;
+    let mut weighted_centroid = Vector3D::new(0.0, 0.0, 0.0);
+    for (i, corner) in polygon.iter().enumerate() {
+        let next = &polygon[(i + 1) % polygon.len()];
+        let tetra_volume = center
+            .sub(apex)
+            .dot(&corner.sub(apex).cross(&next.sub(apex)))
+            .abs()
+            / 6.0;
+        let tetra_centroid = apex.add(&center).add(corner).add(next).scale(0.25);
+        volume += tetra_volume;
+        weighted_centroid += &tetra_centroid.scale(tetra_volume);
+    }
+    if volume > 0.0 {
+        (volume, weighted_centroid.scale(1.0 / volume))
+    } else {
+        (0.0, center)
+    }
+}
+
+fn wrap(point: &Vector3D, box_size: &Vector3D) -> Vector3D {
+    Vector3D::new(
+        point.x.rem_euclid(*box_size.x),
+        point.y.rem_euclid(*box_size.y),
+        point.z.rem_euclid(*box_size.z),
+    )
+}
+
+fn get_random_point(rng: &mut StdRng, box_size: &Vector3D) -> Vector3D {
+    Vector3D::new(
+        rng.gen::<f64>() * *box_size.x,
+        rng.gen::<f64>() * *box_size.y,
+        rng.gen::<f64>() * *box_size.z,
+    )
+}
+
+fn get_random_points(rng: &mut StdRng, num_points: usize, box_size: &Vector3D) -> Vec<Vector3D> {
+    (0..num_points)
+        .map(|_| get_random_point(rng, box_size))
+        .collect()
+}
+
+/// Random sequential addition: points are rejected if they are closer than
+/// a minimum distance to an existing point. The distance starts at 0.7 times
+/// the mean spacing and is reduced whenever too many attempts fail in a row.
+fn get_poisson_disk_points(
+    rng: &mut StdRng,
+    num_points: usize,
+    box_size: &Vector3D,
+    periodic: bool,
+) -> Vec<Vector3D> {
+    let spacing = (*box_size.x * *box_size.y * *box_size.z / num_points as f64).cbrt();
+    let bin_size = 0.7 * spacing;
+    let mut radius = bin_size;
+    let get_bin = |point: &Vector3D| {
+        (
+            (*point.x / bin_size) as i64,
+            (*point.y / bin_size) as i64,
+            (*point.z / bin_size) as i64,
+        )
+    };
+    let num_bins = [
+        (*box_size.x / bin_size) as i64 + 1,
+        (*box_size.y / bin_size) as i64 + 1,
+        (*box_size.z / bin_size) as i64 + 1,
+    ];
+    let mut bins: HashMap<(i64, i64, i64), Vec<usize>> = HashMap::new();
+    let mut points: Vec<Vector3D> = vec![];
+    let mut num_failures = 0;
+    while points.len() < num_points {
+        let candidate = get_random_point(rng, box_size);
+        let (bx, by, bz) = get_bin(&candidate);
+        let mut accepted = true;
+        'search: for dx in -1..=1 {
+            for dy in -1..=1 {
+                for dz in -1..=1 {
+                    let mut bin = (bx + dx, by + dy, bz + dz);
+                    if periodic {
+                        bin = (
+                            bin.0.rem_euclid(num_bins[0]),
+                            bin.1.rem_euclid(num_bins[1]),
+                            bin.2.rem_euclid(num_bins[2]),
+                        );
+                    }
+                    for other in bins.get(&bin).into_iter().flatten() {
+                        let mut difference = candidate.sub(&points[*other]);
+                        if periodic {
+                            difference = get_minimum_image(&difference, box_size);
+                        }
+                        if difference.norm() < radius {
+                            accepted = false;
+                            break 'search;
+                        }
+                    }
+                }
+            }
+        }
+        if accepted {
+            bins.entry((bx, by, bz)).or_default().push(points.len());
+            points.push(candidate);
+            num_failures = 0;
+        } else {
+            num_failures += 1;
+            if num_failures > 100 {
+                radius *= 0.95;
+                num_failures = 0;
+            }
+        }
+    }
+    points
+}
+
+fn get_minimum_image(difference: &Vector3D, box_size: &Vector3D) -> Vector3D {
+    let wrap_component = |d: f64, size: f64| d - size * (d / size).round();
+    Vector3D::new(
+        wrap_component(*difference.x, *box_size.x),
+        wrap_component(*difference.y, *box_size.y),
+        wrap_component(*difference.z, *box_size.z),
+    )
+}
+
+/// Points normally distributed around uniformly placed cluster centers.
+fn get_clustered_points(
+    rng: &mut StdRng,
+    num_points: usize,
+    box_size: &Vector3D,
+    num_clusters: usize,
+    cluster_width: f64,
+    periodic: bool,
+) -> Vec<Vector3D> {
+    let spacing = (*box_size.x * *box_size.y * *box_size.z / num_points as f64).cbrt();
+    let sigma = cluster_width * spacing;
+    let centers = get_random_points(rng, num_clusters.max(1), box_size);
+    let mut points = vec![];
+    while points.len() < num_points {
+        let center = &centers[rng.gen_range(0..centers.len())];
+        let mut gaussian = || {
+            // Box-Muller transform
+            let u: f64 = 1.0 - rng.gen::<f64>();
+            let v: f64 = rng.gen();
+            sigma * (-2.0 * u.ln()).sqrt() * (2.0 * PI * v).cos()
+        };
+        let point = center.add(&Vector3D::new(gaussian(), gaussian(), gaussian()));
+        if periodic {
+            points.push(wrap(&point, box_size));
+        } else if point.x >= 0.0.into()
+            && point.y >= 0.0.into()
+            && point.z >= 0.0.into()
+            && point.x < box_size.x
+            && point.y < box_size.y
+            && point.z < box_size.z
+        {
+            points.push(point);
+        }
+    }
+    points
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check_volumes(periodic: bool, distribution: PointDistribution) {
+        let box_size = Vector3D::new(6.0, 5.0, 4.0);
+        let spec = VoronoiSpec {
+            distribution,
+            periodic,
+            lloyd_iterations: 1,
+            ..VoronoiSpec::default()
+        };
+        let tessellation = generate_tessellation(&spec, &box_size, 0);
+        let total_volume: f64 = tessellation.volumes.iter().sum();
+        // Exact up to the snapping of the points in the triangulation
+        assert!((total_volume - 120.0).abs() < 1e-3);
+        // Faces are symmetric: the face between two cells is seen from both sides.
+        let faces: HashMap<(usize, usize), &Face> = tessellation
+            .faces
+            .iter()
+            .map(|(i0, i1, face)| ((*i0, *i1), face))
+            .collect();
+        for ((i0, i1), face) in faces.iter() {
+            let reverse = faces[&(*i1, *i0)];
+            assert!((face.area.unwrap() - reverse.area.unwrap()).abs() < 1e-3);
+            assert!(face.normal.add(&reverse.normal).norm() < 1e-3);
+        }
+    }
+
+    #[test]
+    fn volumes_add_up_to_box() {
+        check_volumes(false, PointDistribution::Random);
+        check_volumes(false, PointDistribution::PoissonDisk);
+    }
+
+    #[test]
+    fn volumes_add_up_to_periodic_box() {
+        check_volumes(true, PointDistribution::Random);
+        check_volumes(true, PointDistribution::Clustered);
+    }
+}
@@ -0,0 +1,615 @@
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::fmt::Write as FmtWrite;
+use std::fs;
+use std::path::Path;
+
+use anyhow::anyhow;
+use anyhow::bail;
+use anyhow::Context;
+use anyhow::Result;
+use regex::Regex;
+
+use crate::cell::Cell;
+use crate::grid::Grid;
+use crate::grid_geometry::get_face_between;
+use crate::grid_geometry::CellShape;
+use crate::grid_geometry::GridGeometry;
+use crate::grid_geometry::VTK_POLYHEDRON;
+use crate::grid_geometry::VTK_VERTEX;
+use crate::vector_3d::Vector3D;
+
+const APPENDED_DATA_TAG: &[u8] = b"<AppendedData";
+
+struct DataArray {
+    attributes: HashMap<String, String>,
+    content: String,
+}
+
+struct VtuFile<'a> {
+    header: String,
+    appended_data: Option<&'a [u8]>,
+    header_type_size: usize,
+    little_endian: bool,
+}
+
+pub fn read_vtu_file(file: &Path) -> Result<Grid> {
+    let contents = fs::read(file).context(format!("While reading vtu file at {:?}", file))?;
+    let vtu = VtuFile::new(&contents)?;
+    let (points, shapes) = vtu.read_piece()?;
+    let num_cells = shapes.len();
+    let processor_nums = vtu
+        .read_cell_data("processor_num", num_cells)?
+        .unwrap_or_else(|| vec![0; num_cells]);
+    let local_indices = match vtu.read_cell_data("local_index", num_cells)? {
+        Some(local_indices) => local_indices,
+        None => enumerate_per_processor(&processor_nums),
+    };
+    let mut cells: Vec<(Cell, CellShape)> = shapes
+        .into_iter()
+        .zip(processor_nums.into_iter().zip(local_indices))
+        .map(|(shape, (processor_num, local_index))| {
+            let cell = Cell {
+                center: shape.get_center(&points),
+                global_index: 0,
+                local_index,
+                processor_num,
+                weights: BTreeMap::new(),
+            };
+            (cell, shape)
+        })
+        .collect();
+    cells.sort_by_key(|(cell, _)| (cell.processor_num, cell.local_index));
+    let (mut cells, shapes): (Vec<Cell>, Vec<CellShape>) = cells.into_iter().unzip();
+    for (index, cell) in cells.iter_mut().enumerate() {
+        cell.global_index = index;
+    }
+    let edges = get_neighbour_pairs(&shapes)?
+        .into_iter()
+        .map(|(i0, i1, face)| {
+            let face = get_face_between(&points, &face, &cells[i0].center, &cells[i1].center);
+            (i0, i1, Some(face))
+        })
+        .collect();
+    let geometry = GridGeometry { points, shapes };
+    Ok(Grid::from_cells_and_faces(cells, edges).with_geometry(geometry))
+}
+
+fn enumerate_per_processor(processor_nums: &[usize]) -> Vec<usize> {
+    let mut counts: HashMap<usize, usize> = HashMap::new();
+    processor_nums
+        .iter()
+        .map(|processor_num| {
+            let count = counts.entry(*processor_num).or_insert(0);
+            *count += 1;
+            *count - 1
+        })
+        .collect()
+}
+
+/// Two cells are neighbours if they share a face. Returns each pair in both
+/// orders, together with the points of the shared face.
+fn get_neighbour_pairs(shapes: &[CellShape]) -> Result<Vec<(usize, usize, Vec<usize>)>> {
+    let mut cells_by_face: HashMap<Vec<usize>, (Vec<usize>, Vec<usize>)> = HashMap::new();
+    for (index, shape) in shapes.iter().enumerate() {
+        for face in shape.get_faces()? {
+            let mut key = face.clone();
+            key.sort_unstable();
+            cells_by_face
+                .entry(key)
+                .or_insert_with(|| (face, vec![]))
+                .1
+                .push(index);
+        }
+    }
+    let mut pairs = vec![];
+    for (face, cells) in cells_by_face.into_values() {
+        if let [cell_0, cell_1] = cells[..] {
+            pairs.push((cell_0, cell_1, face.clone()));
+            pairs.push((cell_1, cell_0, face));
+        }
+    }
+    pairs.sort_unstable();
+    pairs.dedup_by_key(|(cell_0, cell_1, _)| (*cell_0, *cell_1));
+    Ok(pairs)
+}
+
+impl<'a> VtuFile<'a> {
+    fn new(contents: &'a [u8]) -> Result<Self> {
+        let appended_start = contents
+            .windows(APPENDED_DATA_TAG.len())
+            .position(|window| window == APPENDED_DATA_TAG);
+        let (header, appended_data) = match appended_start {
+            Some(start) => (
+                &contents[..start],
+                Some(get_appended_data(&contents[start..])?),
+            ),
+            None => (contents, None),
+        };
+        let header = String::from_utf8_lossy(header).into_owned();
+        let file_attributes = get_attributes(
+            &find_element(&header, "VTKFile")?
+                .ok_or_else(|| anyhow!("No VTKFile element found"))?,
+        );
+        if file_attributes.get("type").map(|s| s.as_str()) != Some("UnstructuredGrid") {
+            bail!("Only VTK files of type UnstructuredGrid are supported");
+        }
+        if file_attributes.contains_key("compressor") {
+            bail!("Compressed vtu files are not supported");
+        }
+        let header_type_size = match file_attributes.get("header_type").map(|s| s.as_str()) {
+            None | Some("UInt32") => 4,
+            Some("UInt64") => 8,
+            Some(other) => bail!("Unsupported header type: {}", other),
+        };
+        let little_endian =
+            file_attributes.get("byte_order").map(|s| s.as_str()) != Some("BigEndian");
+        Ok(VtuFile {
+            header,
+            appended_data,
+            header_type_size,
+            little_endian,
+        })
+    }
+
+    fn read_piece(&self) -> Result<(Vec<Vector3D>, Vec<CellShape>)> {
+        if self.header.matches("<Piece").count() != 1 {
+            bail!("Only vtu files with exactly one piece are supported");
+        }
+        let points_section = get_section(&self.header, "Points")?;
+        let points_array = get_data_arrays(&points_section)
+            .into_iter()
+            .next()
+            .ok_or_else(|| anyhow!("No point coordinates found"))?;
+        let coordinates = self.read_values(&points_array)?;
+        let points = coordinates
+            .chunks_exact(3)
+            .map(|xyz| Vector3D::new(xyz[0], xyz[1], xyz[2]))
+            .collect();
+        let cells_section = get_section(&self.header, "Cells")?;
+        let arrays = get_data_arrays(&cells_section);
+        let connectivity = self.read_indices(get_array_by_name(&arrays, "connectivity")?)?;
+        let offsets = self.read_indices(get_array_by_name(&arrays, "offsets")?)?;
+        let types = self.read_indices(get_array_by_name(&arrays, "types")?)?;
+        let faces = match get_array_by_name(&arrays, "faces") {
+            Ok(faces) => Some(self.read_values(faces)?),
+            Err(_) => None,
+        };
+        let face_offsets = match get_array_by_name(&arrays, "faceoffsets") {
+            Ok(face_offsets) => Some(self.read_values(face_offsets)?),
+            Err(_) => None,
+        };
+        let mut shapes = vec![];
+        let mut start = 0;
+        for (index, (end, cell_type)) in offsets.iter().zip(types.iter()).enumerate() {
+            let cell_type = *cell_type as u8;
+            let cell_faces = if cell_type == VTK_POLYHEDRON {
+                Some(get_polyhedron_faces(
+                    faces.as_deref(),
+                    face_offsets.as_deref(),
+                    index,
+                )?)
+            } else {
+                None
+            };
+            shapes.push(CellShape {
+                cell_type,
+                connectivity: connectivity
+                    .get(start..*end)
+                    .ok_or_else(|| anyhow!("Invalid cell offsets"))?
+                    .to_vec(),
+                faces: cell_faces,
+            });
+            start = *end;
+        }
+        Ok((points, shapes))
+    }
+
+    fn read_cell_data(&self, name: &str, num_cells: usize) -> Result<Option<Vec<usize>>> {
+        let cell_data_section = match get_section(&self.header, "CellData") {
+            Ok(section) => section,
+            Err(_) => return Ok(None),
+        };
+        let arrays = get_data_arrays(&cell_data_section);
+        match get_array_by_name(&arrays, name) {
+            Ok(array) => {
+                let values = self.read_indices(array)?;
+                if values.len() != num_cells {
+                    bail!("Wrong number of values in cell data array {}", name);
+                }
+                Ok(Some(values))
+            }
+            Err(_) => Ok(None),
+        }
+    }
+
+    fn read_indices(&self, array: &DataArray) -> Result<Vec<usize>> {
+        self.read_values(array)?.into_iter().map(to_index).collect()
+    }
+
+    fn read_values(&self, array: &DataArray) -> Result<Vec<f64>> {
+        let data_type = array
+            .attributes
+            .get("type")
+            .ok_or_else(|| anyhow!("DataArray without type"))?;
+        match array.attributes.get("format").map(|s| s.as_str()) {
+            Some("ascii") => array
+                .content
+                .split_ascii_whitespace()
+                .map(|value| {
+                    value
+                        .parse::<f64>()
+                        .context(format!("While parsing value {}", value))
+                })
+                .collect(),
+            Some("appended") => {
+                let offset: usize = array
+                    .attributes
+                    .get("offset")
+                    .ok_or_else(|| anyhow!("Appended DataArray without offset"))?
+                    .parse()?;
+                self.read_appended_values(data_type, offset)
+            }
+            Some(format) => bail!("Unsupported DataArray format: {}", format),
+            None => bail!("DataArray without format"),
+        }
+    }
+
+    fn read_appended_values(&self, data_type: &str, offset: usize) -> Result<Vec<f64>> {
+        let data = self
+            .appended_data
+            .ok_or_else(|| anyhow!("No appended data section found"))?;
+        let header_end = offset + self.header_type_size;
+        let num_bytes = self.read_unsigned(
+            data.get(offset..header_end)
+                .ok_or_else(|| anyhow!("Appended data offset out of range"))?,
+        ) as usize;
+        let bytes = data
+            .get(header_end..header_end + num_bytes)
+            .ok_or_else(|| anyhow!("Appended data array out of range"))?;
+        let size = get_type_size(data_type)?;
+        Ok(bytes
+            .chunks_exact(size)
+            .map(|chunk| self.convert_bytes(data_type, chunk))
+            .collect())
+    }
+
+    fn read_unsigned(&self, bytes: &[u8]) -> u64 {
+        let mut buffer = [0u8; 8];
+        if self.little_endian {
+            buffer[..bytes.len()].copy_from_slice(bytes);
+            u64::from_le_bytes(buffer)
+        } else {
+            buffer[8 - bytes.len()..].copy_from_slice(bytes);
+            u64::from_be_bytes(buffer)
+        }
+    }
+
+    fn convert_bytes(&self, data_type: &str, bytes: &[u8]) -> f64 {
+        let mut buffer = [0u8; 8];
+        let size = bytes.len();
+        // Normalize to little endian so that only one conversion per type is needed.
+        if self.little_endian {
+            buffer[..size].copy_from_slice(bytes);
+        } else {
+            for (i, byte) in bytes.iter().rev().enumerate() {
+                buffer[i] = *byte;
+            }
+        }
+        let sign_extend = |value: u64, bits: u32| -> i64 {
+            let shift = 64 - bits;
+            ((value << shift) as i64) >> shift
+        };
+        let unsigned = u64::from_le_bytes(buffer);
+        match data_type {
+            "Float32" => f32::from_le_bytes([buffer[0], buffer[1], buffer[2], buffer[3]]) as f64,
+            "Float64" => f64::from_le_bytes(buffer),
+            "Int8" | "Int16" | "Int32" | "Int64" => sign_extend(unsigned, 8 * size as u32) as f64,
+            _ => unsigned as f64,
+        }
+    }
+}
+
+fn get_type_size(data_type: &str) -> Result<usize> {
+    Ok(match data_type {
+        "Int8" | "UInt8" => 1,
+        "Int16" | "UInt16" => 2,
+        "Int32" | "UInt32" | "Float32" => 4,
+        "Int64" | "UInt64" | "Float64" => 8,
+        _ => bail!("Unsupported data type: {}", data_type),
+    })
+}
+
+fn to_index(value: f64) -> Result<usize> {
+    if value < 0.0 || value.fract() != 0.0 {
+        bail!("Expected non-negative integer, found {}", value);
+    }
+    Ok(value as usize)
+}
+
+fn get_polyhedron_faces(
+    faces: Option<&[f64]>,
+    face_offsets: Option<&[f64]>,
+    cell_index: usize,
+) -> Result<Vec<Vec<usize>>> {
+    let error = || anyhow!("Invalid face data for polyhedron cell {}", cell_index);
+    let faces = faces.ok_or_else(error)?;
+    let face_offsets = face_offsets.ok_or_else(error)?;
+    // faceoffsets point to the end of the face stream of each cell
+    let end = to_index(*face_offsets.get(cell_index).ok_or_else(error)?)?;
+    let mut position = face_offsets[..cell_index]
+        .iter()
+        .rev()
+        .find(|offset| **offset >= 0.0)
+        .map(|offset| *offset as usize)
+        .unwrap_or(0);
+    let mut next = || -> Result<usize> {
+        let value = faces.get(position).ok_or_else(error)?;
+        position += 1;
+        to_index(*value)
+    };
+    let num_faces = next()?;
+    let mut cell_faces = vec![];
+    for _ in 0..num_faces {
+        let num_points = next()?;
+        cell_faces.push((0..num_points).map(|_| next()).collect::<Result<_>>()?);
+    }
+    if position != end {
+        return Err(error());
+    }
+    Ok(cell_faces)
+}
+
+fn get_appended_data(section: &[u8]) -> Result<&[u8]> {
+    let start = section
+        .iter()
+        .position(|byte| *byte == b'_')
+        .ok_or_else(|| anyhow!("Appended data section without start marker"))?;
+    Ok(&section[start + 1..])
+}
+
+fn find_element(text: &str, name: &str) -> Result<Option<String>> {
+    let re = Regex::new(&format!("<{}(\\s[^>]*)?>", name))?;
+    Ok(re.find(text).map(|m| m.as_str().to_owned()))
+}
+
+fn get_section(text: &str, name: &str) -> Result<String> {
+    let re = Regex::new(&format!("(?s)<{}(\\s[^>]*)?>(.*?)</{}>", name, name))?;
+    let captures = re
+        .captures(text)
+        .ok_or_else(|| anyhow!("No {} section found", name))?;
+    Ok(captures[2].to_owned())
+}
+
+fn get_attributes(element: &str) -> HashMap<String, String> {
+    let re = Regex::new("([A-Za-z_]+)=\"([^\"]*)\"").unwrap();
+    re.captures_iter(element)
+        .map(|cap| (cap[1].to_owned(), cap[2].to_owned()))
+        .collect()
+}
+
+fn get_data_arrays(section: &str) -> Vec<DataArray> {
+    let re = Regex::new("(?s)<DataArray([^>]*?)(/>|>(.*?)</DataArray>)").unwrap();
+    re.captures_iter(section)
+        .map(|cap| DataArray {
+            attributes: get_attributes(&cap[1]),
+            content: cap
+                .get(3)
+                .map(|m| m.as_str().to_owned())
+                .unwrap_or_default(),
+        })
+        .collect()
+}
+
+fn get_array_by_name<'a>(arrays: &'a [DataArray], name: &str) -> Result<&'a DataArray> {
+    arrays
+        .iter()
+        .find(|array| array.attributes.get("Name").map(|s| s.as_str()) == Some(name))
+        .ok_or_else(|| anyhow!("No DataArray named {} found", name))
+}
+
+/// Writes the grid as an ASCII vtu file. If the grid was read from a file
+/// that contained cell geometry, the original cells are written, otherwise
+/// each cell is represented by a vertex at its center.
+pub fn write_vtu_file(grid: &Grid, file: &Path) -> Result<()> {
+    let cells: Vec<&Cell> = grid.iter().collect();
+    let (points, shapes) = match grid.geometry() {
+        Some(geometry) => (geometry.points.clone(), geometry.shapes.clone()),
+        None => (
+            cells.iter().map(|cell| cell.center.clone()).collect(),
+            (0..cells.len())
+                .map(|index| CellShape {
+                    cell_type: VTK_VERTEX,
+                    connectivity: vec![index],
+                    faces: None,
+                })
+                .collect(),
+        ),
+    };
+    let mut out = String::new();
+    writeln!(out, "<?xml version=\"1.0\"?>")?;
+    writeln!(
+        out,
+        "<VTKFile type=\"UnstructuredGrid\" version=\"1.0\" byte_order=\"LittleEndian\">"
+    )?;
+    writeln!(out, "<UnstructuredGrid>")?;
+    writeln!(
+        out,
+        "<Piece NumberOfPoints=\"{}\" NumberOfCells=\"{}\">",
+        points.len(),
+        shapes.len()
+    )?;
+    writeln!(out, "<Points>")?;
+    write_data_array(
+        &mut out,
+        "Points",
+        "Float64",
+        3,
+        points.iter().map(|p| format!("{} {} {}", p.x, p.y, p.z)),
+    )?;
+    writeln!(out, "</Points>")?;
+    writeln!(out, "<Cells>")?;
+    write_data_array(
+        &mut out,
+        "connectivity",
+        "Int64",
+        1,
+        shapes.iter().flat_map(|shape| shape.connectivity.iter()),
+    )?;
+    let mut offset = 0;
+    write_data_array(
+        &mut out,
+        "offsets",
+        "Int64",
+        1,
+        shapes.iter().map(|shape| {
+            offset += shape.connectivity.len();
+            offset
+        }),
+    )?;
+    write_data_array(
+        &mut out,
+        "types",
+        "UInt8",
+        1,
+        shapes.iter().map(|shape| shape.cell_type),
+    )?;
+    if shapes.iter().any(|shape| shape.faces.is_some()) {
+        write_polyhedron_faces(&mut out, &shapes)?;
+    }
+    writeln!(out, "</Cells>")?;
+    writeln!(out, "<CellData Scalars=\"processor_num\">")?;
+    write_data_array(
+        &mut out,
+        "processor_num",
+        "Int64",
+        1,
+        cells.iter().map(|cell| cell.processor_num),
+    )?;
+    write_data_array(
+        &mut out,
+        "local_index",
+        "Int64",
+        1,
+        cells.iter().map(|cell| cell.local_index),
+    )?;
+    writeln!(out, "</CellData>")?;
+    writeln!(out, "</Piece>")?;
+    writeln!(out, "</UnstructuredGrid>")?;
+    writeln!(out, "</VTKFile>")?;
+    fs::write(file, out).context(format!("While writing vtu file at {:?}", file))
+}
+
+fn write_polyhedron_faces(out: &mut String, shapes: &[CellShape]) -> Result<()> {
+    let mut faces = vec![];
+    let mut face_offsets = vec![];
+    for shape in shapes.iter() {
+        match &shape.faces {
+            Some(cell_faces) => {
+                faces.push(cell_faces.len() as i64);
+                for face in cell_faces.iter() {
+                    faces.push(face.len() as i64);
+                    faces.extend(face.iter().map(|point| *point as i64));
+                }
+                face_offsets.push(faces.len() as i64);
+            }
+            None => face_offsets.push(-1),
+        }
+    }
+    write_data_array(out, "faces", "Int64", 1, faces.iter())?;
+    write_data_array(out, "faceoffsets", "Int64", 1, face_offsets.iter())
+}
+
+fn write_data_array<T: std::fmt::Display>(
+    out: &mut String,
+    name: &str,
+    data_type: &str,
+    num_components: usize,
+    values: impl Iterator<Item = T>,
+) -> Result<()> {
+    writeln!(
+        out,
+        "<DataArray type=\"{}\" Name=\"{}\" NumberOfComponents=\"{}\" format=\"ascii\">",
+        data_type, name, num_components
+    )?;
+    for value in values {
+        writeln!(out, "{}", value)?;
+    }
+    writeln!(out, "</DataArray>")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TWO_HEXAHEDRA: &str = r#"<?xml version="1.0"?>
+<VTKFile type="UnstructuredGrid" version="1.0" byte_order="LittleEndian">
+<UnstructuredGrid>
+<Piece NumberOfPoints="12" NumberOfCells="2">
+<Points>
+<DataArray type="Float64" NumberOfComponents="3" format="ascii">
+0 0 0  1 0 0  2 0 0  0 1 0  1 1 0  2 1 0
+0 0 1  1 0 1  2 0 1  0 1 1  1 1 1  2 1 1
+</DataArray>
+</Points>
+<Cells>
+<DataArray type="Int64" Name="connectivity" format="ascii">
+0 1 4 3 6 7 10 9  1 2 5 4 7 8 11 10
+</DataArray>
+<DataArray type="Int64" Name="offsets" format="ascii">8 16</DataArray>
+<DataArray type="UInt8" Name="types" format="ascii">12 12</DataArray>
+</Cells>
+<CellData>
+<DataArray type="Int32" Name="processor_num" format="ascii">1 0</DataArray>
+</CellData>
+</Piece>
+</UnstructuredGrid>
+</VTKFile>
+"#;
+
+    #[test]
+    fn read_ascii_hexahedra() {
+        let vtu = VtuFile::new(TWO_HEXAHEDRA.as_bytes()).unwrap();
+        let (points, shapes) = vtu.read_piece().unwrap();
+        assert_eq!(points.len(), 12);
+        assert_eq!(shapes.len(), 2);
+        let pairs: Vec<(usize, usize)> = get_neighbour_pairs(&shapes)
+            .unwrap()
+            .into_iter()
+            .map(|(cell_0, cell_1, _)| (cell_0, cell_1))
+            .collect();
+        assert_eq!(pairs, vec![(0, 1), (1, 0)]);
+        assert_eq!(
+            vtu.read_cell_data("processor_num", 2).unwrap(),
+            Some(vec![1, 0])
+        );
+        assert_eq!(shapes[1].get_center(&points), Vector3D::new(1.5, 0.5, 0.5));
+    }
+
+    #[test]
+    fn read_appended_raw() {
+        let mut contents =
+            br#"<VTKFile type="UnstructuredGrid" byte_order="LittleEndian" header_type="UInt32">
+<UnstructuredGrid><Piece NumberOfPoints="1" NumberOfCells="1">
+<Points><DataArray type="Float32" NumberOfComponents="3" format="appended" offset="0"/></Points>
+<Cells>
+<DataArray type="Int64" Name="connectivity" format="appended" offset="16"/>
+</Cells>
+</Piece></UnstructuredGrid>
+<AppendedData encoding="raw">
+_"#
+            .to_vec();
+        contents.extend(12u32.to_le_bytes());
+        for value in [1.0f32, -2.0, 0.5] {
+            contents.extend(value.to_le_bytes());
+        }
+        contents.extend(8u32.to_le_bytes());
+        contents.extend((-3i64).to_le_bytes());
+        let vtu = VtuFile::new(&contents).unwrap();
+        let points = vtu.read_appended_values("Float32", 0).unwrap();
+        assert_eq!(points, vec![1.0, -2.0, 0.5]);
+        let connectivity = vtu.read_appended_values("Int64", 16).unwrap();
+        assert_eq!(connectivity, vec![-3.0]);
+    }
+}